@@ -0,0 +1,9 @@
+alter table pauth.user_config drop constraint user_config_user_id_fkey;
+alter table pauth.user_config add constraint user_config_user_id_fkey
+    foreign key (user_id) references pauth.users(id);
+
+delete from pauth.default_config where config_id in
+    (select id from pauth.config where config_key like 'notify on %');
+delete from pauth.user_config where config_id in
+    (select id from pauth.config where config_key like 'notify on %');
+delete from pauth.config where config_key like 'notify on %';
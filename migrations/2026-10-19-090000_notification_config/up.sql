@@ -0,0 +1,35 @@
+-- Per user notification preferences are stored as user_config overrides of these defaults
+with cfg as (insert into pauth.config(config_key, config_value)
+    values ('notify on new source login', 'true')
+    returning id as cfg_id)
+insert into pauth.default_config(config_id) select cfg_id from cfg;
+
+with cfg as (insert into pauth.config(config_key, config_value)
+    values ('notify on repeated login failures', 'true')
+    returning id as cfg_id)
+insert into pauth.default_config(config_id) select cfg_id from cfg;
+
+with cfg as (insert into pauth.config(config_key, config_value)
+    values ('notify on password change', 'true')
+    returning id as cfg_id)
+insert into pauth.default_config(config_id) select cfg_id from cfg;
+
+with cfg as (insert into pauth.config(config_key, config_value)
+    values ('notify on email change', 'true')
+    returning id as cfg_id)
+insert into pauth.default_config(config_id) select cfg_id from cfg;
+
+with cfg as (insert into pauth.config(config_key, config_value)
+    values ('notify on password reset request', 'true')
+    returning id as cfg_id)
+insert into pauth.default_config(config_id) select cfg_id from cfg;
+
+with cfg as (insert into pauth.config(config_key, config_value)
+    values ('notify on account disabled', 'true')
+    returning id as cfg_id)
+insert into pauth.default_config(config_id) select cfg_id from cfg;
+
+-- user overrides should go when the user does
+alter table pauth.user_config drop constraint user_config_user_id_fkey;
+alter table pauth.user_config add constraint user_config_user_id_fkey
+    foreign key (user_id) references pauth.users(id) on delete cascade;
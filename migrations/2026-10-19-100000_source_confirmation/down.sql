@@ -0,0 +1,15 @@
+delete from pauth.default_config where config_id in (select id from pauth.config where config_key in
+    ('unfamiliar source action', 'source confirmation validity minutes',
+     'familiar source ipv4 prefix', 'familiar source ipv6 prefix'));
+delete from pauth.user_config where config_id in (select id from pauth.config where config_key in
+    ('unfamiliar source action', 'source confirmation validity minutes',
+     'familiar source ipv4 prefix', 'familiar source ipv6 prefix'));
+delete from pauth.config where config_key in
+    ('unfamiliar source action', 'source confirmation validity minutes',
+     'familiar source ipv4 prefix', 'familiar source ipv6 prefix');
+
+alter table pauth.login_history drop constraint login_history_user_id_fkey;
+alter table pauth.login_history add constraint login_history_user_id_fkey
+    foreign key (user_id) references pauth.users(id);
+drop index if exists pauth.login_history_user_id_idx;
+drop table if exists pauth.source_confirmation;
@@ -0,0 +1,39 @@
+-- tokens to confirm a login from an unfamiliar source (e.g. sent by email)
+create table pauth.source_confirmation(
+    id serial primary key not null,
+    user_id integer not null references pauth.users(id) on delete cascade,
+    source integer references pauth.source(id),
+    token_hash text not null,
+    expires timestamp without time zone not null
+);
+create index source_confirmation_user_id_idx on pauth.source_confirmation(user_id);
+
+create index login_history_user_id_idx on pauth.login_history(user_id);
+
+-- history should go with the user. Set to null rather than delete, as some systems need
+-- a record of logins even when the user has gone.
+alter table pauth.login_history drop constraint login_history_user_id_fkey;
+alter table pauth.login_history add constraint login_history_user_id_fkey
+    foreign key (user_id) references pauth.users(id) on delete set null;
+
+-- what to do when a user logs in from an unfamiliar source: 'allow' or 'confirm'
+with cfg as (insert into pauth.config(config_key, config_value)
+    values ('unfamiliar source action', 'allow')
+    returning id as cfg_id)
+insert into pauth.default_config(config_id) select cfg_id from cfg;
+
+with cfg as (insert into pauth.config(config_key, config_value)
+    values ('source confirmation validity minutes', '30')
+    returning id as cfg_id)
+insert into pauth.default_config(config_id) select cfg_id from cfg;
+
+-- logins from ip addresses in the same subnet as a previous login are considered familiar
+with cfg as (insert into pauth.config(config_key, config_value)
+    values ('familiar source ipv4 prefix', '24')
+    returning id as cfg_id)
+insert into pauth.default_config(config_id) select cfg_id from cfg;
+
+with cfg as (insert into pauth.config(config_key, config_value)
+    values ('familiar source ipv6 prefix', '64')
+    returning id as cfg_id)
+insert into pauth.default_config(config_id) select cfg_id from cfg;
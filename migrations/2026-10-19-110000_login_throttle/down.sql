@@ -0,0 +1,11 @@
+delete from pauth.default_config where config_id in (select id from pauth.config where config_key in
+    ('failed logins before delay', 'login delay seconds', 'max login delay seconds',
+     'failed login count reset minutes'));
+delete from pauth.user_config where config_id in (select id from pauth.config where config_key in
+    ('failed logins before delay', 'login delay seconds', 'max login delay seconds',
+     'failed login count reset minutes'));
+delete from pauth.config where config_key in
+    ('failed logins before delay', 'login delay seconds', 'max login delay seconds',
+     'failed login count reset minutes');
+
+drop table if exists pauth.login_throttle;
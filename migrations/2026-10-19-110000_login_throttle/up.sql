@@ -0,0 +1,29 @@
+-- consecutive failed logins per identifier (username or email, lower case)
+-- the identifier need not belong to a user, so that unknown identifiers are throttled too
+create table pauth.login_throttle(
+    identifier varchar primary key not null,
+    failures integer not null default 0,
+    last_failure timestamp without time zone not null default now(),
+    blocked_until timestamp without time zone
+);
+
+with cfg as (insert into pauth.config(config_key, config_value)
+    values ('failed logins before delay', '3')
+    returning id as cfg_id)
+insert into pauth.default_config(config_id) select cfg_id from cfg;
+
+-- the delay after the first throttled failure. It doubles with each further failure.
+with cfg as (insert into pauth.config(config_key, config_value)
+    values ('login delay seconds', '1')
+    returning id as cfg_id)
+insert into pauth.default_config(config_id) select cfg_id from cfg;
+
+with cfg as (insert into pauth.config(config_key, config_value)
+    values ('max login delay seconds', '900') -- 15 minutes
+    returning id as cfg_id)
+insert into pauth.default_config(config_id) select cfg_id from cfg;
+
+with cfg as (insert into pauth.config(config_key, config_value)
+    values ('failed login count reset minutes', '1440') -- 1 day
+    returning id as cfg_id)
+insert into pauth.default_config(config_id) select cfg_id from cfg;
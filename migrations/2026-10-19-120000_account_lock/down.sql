@@ -0,0 +1,9 @@
+delete from pauth.default_config where config_id in (select id from pauth.config where config_key in
+    ('max failed logins before lock', 'notify on account locked'));
+delete from pauth.user_config where config_id in (select id from pauth.config where config_key in
+    ('max failed logins before lock', 'notify on account locked'));
+delete from pauth.config where config_key in
+    ('max failed logins before lock', 'notify on account locked');
+
+alter table pauth.users drop column locked_at;
+alter table pauth.users drop column failed_logins;
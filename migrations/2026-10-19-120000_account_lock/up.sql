@@ -0,0 +1,13 @@
+-- consecutive failed logins for the user, and when the account was locked because of them
+alter table pauth.users add column failed_logins integer not null default 0;
+alter table pauth.users add column locked_at timestamp without time zone;
+
+with cfg as (insert into pauth.config(config_key, config_value)
+    values ('max failed logins before lock', '10')
+    returning id as cfg_id)
+insert into pauth.default_config(config_id) select cfg_id from cfg;
+
+with cfg as (insert into pauth.config(config_key, config_value)
+    values ('notify on account locked', 'true')
+    returning id as cfg_id)
+insert into pauth.default_config(config_id) select cfg_id from cfg;
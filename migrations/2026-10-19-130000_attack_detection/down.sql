@@ -0,0 +1,14 @@
+delete from pauth.default_config where config_id in (select id from pauth.config where config_key in
+    ('password fingerprint key', 'attack detection window minutes',
+     'stuffing distinct identifiers per source', 'spraying distinct identifiers per password',
+     'attack response', 'attack delay seconds', 'auth failure retention days'));
+delete from pauth.user_config where config_id in (select id from pauth.config where config_key in
+    ('password fingerprint key', 'attack detection window minutes',
+     'stuffing distinct identifiers per source', 'spraying distinct identifiers per password',
+     'attack response', 'attack delay seconds', 'auth failure retention days'));
+delete from pauth.config where config_key in
+    ('password fingerprint key', 'attack detection window minutes',
+     'stuffing distinct identifiers per source', 'spraying distinct identifiers per password',
+     'attack response', 'attack delay seconds', 'auth failure retention days');
+
+drop table if exists pauth.auth_failure;
@@ -0,0 +1,49 @@
+-- failed logins, for spotting attacks across users. The password is never stored, only an hmac
+-- of it (keyed with 'password fingerprint key') so that repeated use of a password can be seen.
+create table pauth.auth_failure(
+    id serial primary key not null,
+    attempted_identifier varchar not null,
+    user_id integer references pauth.users(id) on delete set null,
+    source integer references pauth.source(id),
+    failure_time timestamp without time zone not null default now(),
+    password_fingerprint text not null
+);
+create index auth_failure_time_idx on pauth.auth_failure(failure_time);
+create index auth_failure_source_idx on pauth.auth_failure(source, failure_time);
+create index auth_failure_fingerprint_idx on pauth.auth_failure(password_fingerprint, failure_time);
+
+with cfg as (insert into pauth.config(config_key, config_value)
+    values ('password fingerprint key', encode(pauth.gen_random_bytes(32), 'hex'))
+    returning id as cfg_id)
+insert into pauth.default_config(config_id) select cfg_id from cfg;
+
+with cfg as (insert into pauth.config(config_key, config_value)
+    values ('attack detection window minutes', '60')
+    returning id as cfg_id)
+insert into pauth.default_config(config_id) select cfg_id from cfg;
+
+with cfg as (insert into pauth.config(config_key, config_value)
+    values ('stuffing distinct identifiers per source', '10')
+    returning id as cfg_id)
+insert into pauth.default_config(config_id) select cfg_id from cfg;
+
+with cfg as (insert into pauth.config(config_key, config_value)
+    values ('spraying distinct identifiers per password', '10')
+    returning id as cfg_id)
+insert into pauth.default_config(config_id) select cfg_id from cfg;
+
+-- what login does when an attack is suspected: 'none', 'delay', 'block' or 'step-up'
+with cfg as (insert into pauth.config(config_key, config_value)
+    values ('attack response', 'none')
+    returning id as cfg_id)
+insert into pauth.default_config(config_id) select cfg_id from cfg;
+
+with cfg as (insert into pauth.config(config_key, config_value)
+    values ('attack delay seconds', '30')
+    returning id as cfg_id)
+insert into pauth.default_config(config_id) select cfg_id from cfg;
+
+with cfg as (insert into pauth.config(config_key, config_value)
+    values ('auth failure retention days', '30')
+    returning id as cfg_id)
+insert into pauth.default_config(config_id) select cfg_id from cfg;
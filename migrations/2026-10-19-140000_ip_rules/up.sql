@@ -0,0 +1,13 @@
+-- ip address allow/deny rules. Global if neither user_id nor email_domain is set.
+create table pauth.ip_rule(
+    id serial primary key not null,
+    user_id integer references pauth.users(id) on delete cascade,
+    email_domain varchar,
+    network cidr not null,
+    allow boolean not null,
+    note text,
+    created timestamp without time zone not null default now(),
+    constraint ip_rule_one_scope check (user_id is null or email_domain is null)
+);
+create index ip_rule_user_id_idx on pauth.ip_rule(user_id);
+create index ip_rule_email_domain_idx on pauth.ip_rule(email_domain);
@@ -0,0 +1,2 @@
+drop index if exists pauth.users_email_lower_key;
+drop index if exists pauth.users_chosen_name_lower_key;
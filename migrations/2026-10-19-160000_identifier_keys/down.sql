@@ -0,0 +1,9 @@
+delete from pauth.default_config where config_id in (select id from pauth.config where config_key in
+    ('identifier normalisation', 'identifier case folding', 'check confusable usernames'));
+delete from pauth.user_config where config_id in (select id from pauth.config where config_key in
+    ('identifier normalisation', 'identifier case folding', 'check confusable usernames'));
+delete from pauth.config where config_key in
+    ('identifier normalisation', 'identifier case folding', 'check confusable usernames');
+
+drop view if exists pauth.identifier_collision;
+drop table if exists pauth.user_key;
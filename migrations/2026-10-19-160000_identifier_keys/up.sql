@@ -0,0 +1,51 @@
+-- canonical forms of each user's name and email, which are what lookups and duplicate checks
+-- compare. name_skeleton is filled in by pauth on startup, as Postgres can't compute it.
+create table pauth.user_key(
+    user_id integer primary key not null references pauth.users(id) on delete cascade,
+    name_key varchar not null,
+    email_key varchar not null,
+    name_skeleton varchar
+);
+create index user_key_name_key_idx on pauth.user_key(name_key);
+create index user_key_email_key_idx on pauth.user_key(email_key);
+create index user_key_name_skeleton_idx on pauth.user_key(name_skeleton);
+
+insert into pauth.user_key(user_id, name_key, email_key)
+    select id, lower(normalize(chosen_name, NFKC)), lower(normalize(email, NFKC)) from pauth.users;
+
+-- users whose identifiers are the same once canonicalised. These can't be told apart on login,
+-- so should be renamed.
+create view pauth.identifier_collision as
+    select a.user_id, b.user_id as other_user_id, 'username' as kind
+        from pauth.user_key a join pauth.user_key b
+        on a.name_key = b.name_key and a.user_id < b.user_id
+    union all
+    select a.user_id, b.user_id, 'email'
+        from pauth.user_key a join pauth.user_key b
+        on a.email_key = b.email_key and a.user_id < b.user_id;
+
+do $$
+declare
+    collisions bigint;
+begin
+    select count(*) into collisions from pauth.identifier_collision;
+    if collisions > 0 then
+        raise warning '% pairs of pauth users have identifiers which only differ in case or unicode form, see pauth.identifier_collision', collisions;
+    end if;
+end
+$$;
+
+with cfg as (insert into pauth.config(config_key, config_value)
+    values ('identifier normalisation', 'nfkc')
+    returning id as cfg_id)
+insert into pauth.default_config(config_id) select cfg_id from cfg;
+
+with cfg as (insert into pauth.config(config_key, config_value)
+    values ('identifier case folding', 'true')
+    returning id as cfg_id)
+insert into pauth.default_config(config_id) select cfg_id from cfg;
+
+with cfg as (insert into pauth.config(config_key, config_value)
+    values ('check confusable usernames', 'true')
+    returning id as cfg_id)
+insert into pauth.default_config(config_id) select cfg_id from cfg;
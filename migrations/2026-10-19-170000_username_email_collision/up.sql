@@ -0,0 +1,13 @@
+-- a username which is another user's email is ambiguous when logging in or resetting a password
+create or replace view pauth.identifier_collision as
+    select a.user_id, b.user_id as other_user_id, 'username' as kind
+        from pauth.user_key a join pauth.user_key b
+        on a.name_key = b.name_key and a.user_id < b.user_id
+    union all
+    select a.user_id, b.user_id, 'email'
+        from pauth.user_key a join pauth.user_key b
+        on a.email_key = b.email_key and a.user_id < b.user_id
+    union all
+    select a.user_id, b.user_id, 'username is email'
+        from pauth.user_key a join pauth.user_key b
+        on a.name_key = b.email_key and a.user_id <> b.user_id;
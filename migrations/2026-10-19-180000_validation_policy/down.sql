@@ -0,0 +1,12 @@
+delete from pauth.default_config where config_id in (select id from pauth.config where config_key in
+    ('username min length', 'username max length', 'username allowed characters',
+     'reserved usernames', 'username blocklist file', 'email max length',
+     'disposable email domains file'));
+delete from pauth.user_config where config_id in (select id from pauth.config where config_key in
+    ('username min length', 'username max length', 'username allowed characters',
+     'reserved usernames', 'username blocklist file', 'email max length',
+     'disposable email domains file'));
+delete from pauth.config where config_key in
+    ('username min length', 'username max length', 'username allowed characters',
+     'reserved usernames', 'username blocklist file', 'email max length',
+     'disposable email domains file');
@@ -0,0 +1,9 @@
+delete from pauth.default_config where config_id in (select id from pauth.config where config_key in
+    ('password min length', 'password max length', 'password min strength',
+     'password forbid identifiers', 'breached passwords file'));
+delete from pauth.user_config where config_id in (select id from pauth.config where config_key in
+    ('password min length', 'password max length', 'password min strength',
+     'password forbid identifiers', 'breached passwords file'));
+delete from pauth.config where config_key in
+    ('password min length', 'password max length', 'password min strength',
+     'password forbid identifiers', 'breached passwords file');
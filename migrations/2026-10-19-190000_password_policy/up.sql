@@ -0,0 +1,25 @@
+-- the PasswordPolicy, see the password module. By default any password is accepted.
+with cfg as (insert into pauth.config(config_key, config_value)
+    values ('password min length', '1')
+    returning id as cfg_id)
+insert into pauth.default_config(config_id) select cfg_id from cfg;
+
+with cfg as (insert into pauth.config(config_key, config_value)
+    values ('password max length', '1024')
+    returning id as cfg_id)
+insert into pauth.default_config(config_id) select cfg_id from cfg;
+
+with cfg as (insert into pauth.config(config_key, config_value)
+    values ('password min strength', '0')
+    returning id as cfg_id)
+insert into pauth.default_config(config_id) select cfg_id from cfg;
+
+with cfg as (insert into pauth.config(config_key, config_value)
+    values ('password forbid identifiers', 'false')
+    returning id as cfg_id)
+insert into pauth.default_config(config_id) select cfg_id from cfg;
+
+with cfg as (insert into pauth.config(config_key, config_value)
+    values ('breached passwords file', '')
+    returning id as cfg_id)
+insert into pauth.default_config(config_id) select cfg_id from cfg;
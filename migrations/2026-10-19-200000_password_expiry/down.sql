@@ -0,0 +1,10 @@
+delete from pauth.default_config where config_id in (select id from pauth.config where config_key in
+    ('max password age days', 'password change token validity minutes'));
+delete from pauth.user_config where config_id in (select id from pauth.config where config_key in
+    ('max password age days', 'password change token validity minutes'));
+delete from pauth.config where config_key in
+    ('max password age days', 'password change token validity minutes');
+
+drop table pauth.password_change_token;
+alter table pauth.users drop column must_change_password;
+alter table pauth.users drop column password_changed_at;
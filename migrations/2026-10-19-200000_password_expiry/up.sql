@@ -0,0 +1,24 @@
+-- when the password was last changed, for 'max password age days', and whether it must be
+-- changed at the next login (such as after an admin sets a temporary password)
+alter table pauth.users add column password_changed_at timestamp without time zone not null default now();
+alter table pauth.users add column must_change_password boolean not null default false;
+
+-- tokens from LoginResult::PasswordChangeRequired, which can only be used to change the password
+create table pauth.password_change_token(
+    id serial primary key not null,
+    user_id integer not null references pauth.users(id) on delete cascade,
+    token_hash text not null,
+    expires timestamp without time zone not null
+);
+create index password_change_token_user_id_idx on pauth.password_change_token(user_id);
+
+-- passwords older than this must be changed at the next login. 0 for no limit.
+with cfg as (insert into pauth.config(config_key, config_value)
+    values ('max password age days', '0')
+    returning id as cfg_id)
+insert into pauth.default_config(config_id) select cfg_id from cfg;
+
+with cfg as (insert into pauth.config(config_key, config_value)
+    values ('password change token validity minutes', '30')
+    returning id as cfg_id)
+insert into pauth.default_config(config_id) select cfg_id from cfg;
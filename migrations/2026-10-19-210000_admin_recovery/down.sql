@@ -0,0 +1,9 @@
+delete from pauth.default_config where config_id in (select id from pauth.config where config_key in
+    ('temporary password validity minutes'));
+delete from pauth.user_config where config_id in (select id from pauth.config where config_key in
+    ('temporary password validity minutes'));
+delete from pauth.config where config_key in
+    ('temporary password validity minutes');
+
+drop table pauth.admin_audit;
+alter table pauth.users drop column temporary_password_expires;
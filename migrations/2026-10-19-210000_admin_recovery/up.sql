@@ -0,0 +1,18 @@
+-- when a temporary password set by an admin stops working, if it hasn't been changed by then
+alter table pauth.users add column temporary_password_expires timestamp without time zone;
+
+-- actions taken by admins on users. No foreign keys, so that the record outlives the users.
+create table pauth.admin_audit(
+    id serial primary key not null,
+    admin_id integer not null,
+    user_id integer,
+    action text not null,
+    action_time timestamp without time zone not null default now()
+);
+create index admin_audit_user_id_idx on pauth.admin_audit(user_id);
+create index admin_audit_admin_id_idx on pauth.admin_audit(admin_id);
+
+with cfg as (insert into pauth.config(config_key, config_value)
+    values ('temporary password validity minutes', '1440')
+    returning id as cfg_id)
+insert into pauth.default_config(config_id) select cfg_id from cfg;
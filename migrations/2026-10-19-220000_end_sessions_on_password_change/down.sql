@@ -0,0 +1,6 @@
+delete from pauth.default_config where config_id in (select id from pauth.config where config_key in
+    ('end other sessions on password change'));
+delete from pauth.user_config where config_id in (select id from pauth.config where config_key in
+    ('end other sessions on password change'));
+delete from pauth.config where config_key in
+    ('end other sessions on password change');
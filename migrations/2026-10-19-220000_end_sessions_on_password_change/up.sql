@@ -0,0 +1,5 @@
+-- whether changing the password logs the user out everywhere else
+with cfg as (insert into pauth.config(config_key, config_value)
+    values ('end other sessions on password change', 'true')
+    returning id as cfg_id)
+insert into pauth.default_config(config_id) select cfg_id from cfg;
@@ -0,0 +1,8 @@
+delete from pauth.default_config where config_id in (select id from pauth.config where config_key in
+    ('reauthentication max age minutes'));
+delete from pauth.user_config where config_id in (select id from pauth.config where config_key in
+    ('reauthentication max age minutes'));
+delete from pauth.config where config_key in
+    ('reauthentication max age minutes');
+
+alter table pauth.user_login_tokens drop column last_strong_auth;
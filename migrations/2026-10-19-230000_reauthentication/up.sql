@@ -0,0 +1,9 @@
+-- when the user last gave their password for the token. Existing tokens have to reauthenticate
+-- before sensitive changes.
+alter table pauth.user_login_tokens add column last_strong_auth timestamp;
+
+-- how long after giving their password a user can make sensitive changes without giving it again
+with cfg as (insert into pauth.config(config_key, config_value)
+    values ('reauthentication max age minutes', '15')
+    returning id as cfg_id)
+insert into pauth.default_config(config_id) select cfg_id from cfg;
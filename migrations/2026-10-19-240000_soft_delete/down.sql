@@ -0,0 +1,16 @@
+delete from pauth.default_config where config_id in (select id from pauth.config where config_key in
+    ('deletion grace period days', 'notify on account deletion'));
+delete from pauth.user_config where config_id in (select id from pauth.config where config_key in
+    ('deletion grace period days', 'notify on account deletion'));
+delete from pauth.config where config_key in
+    ('deletion grace period days', 'notify on account deletion');
+
+alter table pauth.auth_history drop constraint auth_history_user_id_fkey;
+alter table pauth.auth_history add constraint auth_history_user_id_fkey
+    foreign key (user_id) references pauth.users(id);
+alter table pauth.user_history drop constraint user_history_user_id_fkey;
+alter table pauth.user_history add constraint user_history_user_id_fkey
+    foreign key (user_id) references pauth.users(id);
+
+drop index pauth.users_deleted_at_idx;
+alter table pauth.users drop column deleted_at;
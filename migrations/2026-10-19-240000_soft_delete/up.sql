@@ -0,0 +1,22 @@
+-- deleting a user marks them as pending deletion, until purge_deleted_users deletes them for good
+alter table pauth.users add column deleted_at timestamp;
+create index users_deleted_at_idx on pauth.users(deleted_at) where deleted_at is not null;
+
+-- the history is kept without the user once they are purged, as login_history already is
+alter table pauth.user_history drop constraint user_history_user_id_fkey;
+alter table pauth.user_history add constraint user_history_user_id_fkey
+    foreign key (user_id) references pauth.users(id) on delete set null;
+alter table pauth.auth_history drop constraint auth_history_user_id_fkey;
+alter table pauth.auth_history add constraint auth_history_user_id_fkey
+    foreign key (user_id) references pauth.users(id) on delete set null;
+
+-- how long a deleted account can be restored for. 0 deletes accounts straight away.
+with cfg as (insert into pauth.config(config_key, config_value)
+    values ('deletion grace period days', '30')
+    returning id as cfg_id)
+insert into pauth.default_config(config_id) select cfg_id from cfg;
+
+with cfg as (insert into pauth.config(config_key, config_value)
+    values ('notify on account deletion', 'true')
+    returning id as cfg_id)
+insert into pauth.default_config(config_id) select cfg_id from cfg;
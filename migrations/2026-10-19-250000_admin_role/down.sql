@@ -0,0 +1,2 @@
+alter table pauth.users drop column disabled_at;
+alter table pauth.users drop column is_admin;
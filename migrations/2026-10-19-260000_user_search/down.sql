@@ -0,0 +1,7 @@
+drop index pauth.users_locked_at_idx;
+drop index pauth.users_disabled_at_idx;
+drop index pauth.users_last_login_idx;
+drop index pauth.users_created_idx;
+drop index pauth.user_key_email_key_prefix_idx;
+drop index pauth.user_key_name_key_prefix_idx;
+alter table pauth.users drop column created;
@@ -0,0 +1,11 @@
+-- when the user was added. Users from before this was recorded get the time of the migration.
+alter table pauth.users add column created timestamp not null default now();
+
+-- for search_users: prefix searches on the canonical keys, the ranges it sorts by, and the
+-- (rare) disabled and locked users
+create index user_key_name_key_prefix_idx on pauth.user_key(name_key varchar_pattern_ops);
+create index user_key_email_key_prefix_idx on pauth.user_key(email_key varchar_pattern_ops);
+create index users_created_idx on pauth.users(created, id);
+create index users_last_login_idx on pauth.users(last_login, id);
+create index users_disabled_at_idx on pauth.users(disabled_at) where disabled_at is not null;
+create index users_locked_at_idx on pauth.users(locked_at) where locked_at is not null;
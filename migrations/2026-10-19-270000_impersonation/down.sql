@@ -0,0 +1,8 @@
+delete from pauth.default_config where config_id in (select id from pauth.config where config_key in
+    ('impersonation validity minutes'));
+delete from pauth.user_config where config_id in (select id from pauth.config where config_key in
+    ('impersonation validity minutes'));
+delete from pauth.config where config_key in
+    ('impersonation validity minutes');
+
+drop table pauth.impersonation;
@@ -0,0 +1,20 @@
+-- admins seeing pauth as a user sees it (see pauth::admin::impersonate). The token works for the
+-- user until it expires or the admin ends the impersonation. No foreign keys, so that the record
+-- outlives the users, as the admin audit does.
+create table pauth.impersonation(
+    id serial primary key not null,
+    admin_id integer not null,
+    user_id integer not null,
+    token_hash text not null,
+    reason text not null,
+    started timestamp without time zone not null default now(),
+    expires timestamp without time zone not null,
+    ended timestamp without time zone
+);
+create index impersonation_user_id_idx on pauth.impersonation(user_id);
+create index impersonation_open_idx on pauth.impersonation(expires) where ended is null;
+
+with cfg as (insert into pauth.config(config_key, config_value)
+    values ('impersonation validity minutes', '15')
+    returning id as cfg_id)
+insert into pauth.default_config(config_id) select cfg_id from cfg;
@@ -0,0 +1,11 @@
+delete from pauth.default_config where config_id in (select id from pauth.config where config_key in
+    ('permission cache seconds'));
+delete from pauth.user_config where config_id in (select id from pauth.config where config_key in
+    ('permission cache seconds'));
+delete from pauth.config where config_key in
+    ('permission cache seconds');
+
+drop table pauth.user_role;
+drop table pauth.role_permission;
+drop table pauth.permission;
+drop table pauth.role;
@@ -0,0 +1,29 @@
+-- roles and permissions for applications built on pauth (see pauth::roles). A user has the
+-- permissions of all of their roles.
+create table pauth.role(
+    id serial primary key not null,
+    name varchar not null unique
+);
+
+create table pauth.permission(
+    id serial primary key not null,
+    name varchar not null unique
+);
+
+create table pauth.role_permission(
+    role_id integer not null references pauth.role(id) on delete cascade,
+    permission_id integer not null references pauth.permission(id) on delete cascade,
+    primary key (role_id, permission_id)
+);
+
+create table pauth.user_role(
+    user_id integer not null references pauth.users(id) on delete cascade,
+    role_id integer not null references pauth.role(id) on delete cascade,
+    primary key (user_id, role_id)
+);
+create index user_role_role_id_idx on pauth.user_role(role_id);
+
+with cfg as (insert into pauth.config(config_key, config_value)
+    values ('permission cache seconds', '60')
+    returning id as cfg_id)
+insert into pauth.default_config(config_id) select cfg_id from cfg;
@@ -0,0 +1,4 @@
+drop index if exists pauth.user_key_email_key_unique;
+drop index if exists pauth.user_key_name_key_unique;
+alter table pauth.user_key drop column if exists email_collision, drop column if exists name_collision;
+drop table if exists pauth.user_key_form;
@@ -0,0 +1,19 @@
+-- the form of the identifier keys, as named by pauth. pauth makes every user's keys again when
+-- this isn't the configured form, so it starts empty to replace the keys computed by SQL.
+create table pauth.user_key_form(
+    form varchar primary key not null
+);
+
+-- keys which another user already had when keys became unique, or when they were made again in a
+-- new form. These users can't be told apart on login (see pauth.identifier_collision), and keep
+-- the collision until they are renamed.
+alter table pauth.user_key
+    add column name_collision boolean not null default false,
+    add column email_collision boolean not null default false;
+update pauth.user_key k set name_collision = true where exists
+    (select 1 from pauth.user_key o where o.name_key = k.name_key and o.user_id < k.user_id);
+update pauth.user_key k set email_collision = true where exists
+    (select 1 from pauth.user_key o where o.email_key = k.email_key and o.user_id < k.user_id);
+
+create unique index user_key_name_key_unique on pauth.user_key(name_key) where not name_collision;
+create unique index user_key_email_key_unique on pauth.user_key(email_key) where not email_collision;
@@ -0,0 +1,4 @@
+with cfg as (insert into pauth.config(config_key, config_value)
+    values ('password fingerprint key', encode(pauth.gen_random_bytes(32), 'hex'))
+    returning id as cfg_id)
+insert into pauth.default_config(config_id) select cfg_id from cfg;
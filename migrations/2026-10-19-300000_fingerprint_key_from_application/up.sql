@@ -0,0 +1,8 @@
+-- the password fingerprint key is a secret, so is no longer kept in the config, where anything
+-- able to read config could see it. The application supplies it instead (see the risk module).
+-- Fingerprints already recorded with the old key won't match new ones.
+delete from pauth.default_config where config_id in
+    (select id from pauth.config where config_key = 'password fingerprint key');
+delete from pauth.user_config where config_id in
+    (select id from pauth.config where config_key = 'password fingerprint key');
+delete from pauth.config where config_key = 'password fingerprint key';
@@ -0,0 +1,6 @@
+drop table user_config;
+drop table default_config;
+drop table login_history;
+drop table pw_reset;
+drop table user_login_tokens;
+drop table users;
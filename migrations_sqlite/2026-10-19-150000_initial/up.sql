@@ -0,0 +1,79 @@
+-- The core pauth tables for SQLite. Postgres only features (sources, throttling, attack
+-- detection and ip address rules) have no tables here.
+create table users (
+    id integer primary key autoincrement not null,
+    chosen_name text not null,
+    email text not null,
+    pass_hash text not null,
+    last_login timestamp not null default current_timestamp,
+    failed_logins integer not null default 0,
+    locked_at timestamp
+);
+create index users_chosen_name_idx on users(chosen_name);
+create index users_email_idx on users(email);
+
+create table user_login_tokens (
+    id integer primary key autoincrement not null,
+    user_id integer not null references users(id) on delete cascade,
+    token text not null,
+    created timestamp not null default current_timestamp,
+    last_used timestamp not null default current_timestamp
+);
+create index user_login_tokens_user_id_idx on user_login_tokens(user_id);
+
+create table pw_reset (
+    id integer primary key autoincrement not null,
+    user_id integer not null references users(id) on delete cascade,
+    user_token_hash text not null,
+    expires timestamp
+);
+create index pw_reset_user_id_idx on pw_reset(user_id);
+
+create table login_history (
+    id integer primary key autoincrement not null,
+    user_id integer references users(id) on delete set null,
+    login_time timestamp not null default current_timestamp,
+    route text
+);
+create index login_history_user_id_idx on login_history(user_id);
+
+create table default_config (
+    config_key text primary key not null,
+    config_value text
+);
+
+create table user_config (
+    user_id integer not null references users(id) on delete cascade,
+    config_key text not null,
+    config_value text,
+    primary key (user_id, config_key)
+);
+
+insert into default_config(config_key, config_value) values
+    ('max failed logins per source', '5'),
+    ('max failed logins per source reset time minutes', '1440'),
+    ('token validity minutes', '1440000'),
+    ('expire token if not used for minutes', '144000'),
+    ('password reset validity minutes', '720'),
+    ('keep login history', 'true'),
+    ('keep reset history', 'true'),
+    ('keep user change history', 'true'),
+    ('login history retention days', '365'),
+    ('reset history retention days', '365'),
+    ('user change history retention', '365'),
+    ('notify on new source login', 'true'),
+    ('notify on repeated login failures', 'true'),
+    ('notify on password change', 'true'),
+    ('notify on email change', 'true'),
+    ('notify on password reset request', 'true'),
+    ('notify on account disabled', 'true'),
+    ('notify on account locked', 'true'),
+    ('unfamiliar source action', 'allow'),
+    ('source confirmation validity minutes', '30'),
+    ('familiar source ipv4 prefix', '24'),
+    ('familiar source ipv6 prefix', '64'),
+    ('failed logins before delay', '3'),
+    ('login delay seconds', '1'),
+    ('max login delay seconds', '900'),
+    ('failed login count reset minutes', '1440'),
+    ('max failed logins before lock', '10');
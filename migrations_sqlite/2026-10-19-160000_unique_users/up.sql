@@ -0,0 +1,3 @@
+-- usernames and emails are unique regardless of case, so concurrent signups can't both succeed
+create unique index users_chosen_name_lower_key on users(lower(chosen_name));
+create unique index users_email_lower_key on users(lower(email));
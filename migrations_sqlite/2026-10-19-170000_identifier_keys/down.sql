@@ -0,0 +1,3 @@
+delete from default_config where config_key in
+    ('identifier normalisation', 'identifier case folding', 'check confusable usernames');
+drop table if exists user_key;
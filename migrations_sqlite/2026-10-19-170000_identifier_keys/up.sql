@@ -0,0 +1,16 @@
+-- canonical forms of each user's name and email, see the Postgres migration. SQLite can't
+-- compute them, so pauth fills them in when the database is opened.
+create table user_key (
+    user_id integer primary key not null references users(id) on delete cascade,
+    name_key text not null,
+    email_key text not null,
+    name_skeleton text
+);
+create index user_key_name_key_idx on user_key(name_key);
+create index user_key_email_key_idx on user_key(email_key);
+create index user_key_name_skeleton_idx on user_key(name_skeleton);
+
+insert into default_config(config_key, config_value) values
+    ('identifier normalisation', 'nfkc'),
+    ('identifier case folding', 'true'),
+    ('check confusable usernames', 'true');
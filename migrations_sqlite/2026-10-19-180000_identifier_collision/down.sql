@@ -0,0 +1 @@
+drop view if exists identifier_collision;
@@ -0,0 +1,13 @@
+-- users who can't be told apart by an identifier, see the Postgres migrations
+create view identifier_collision as
+    select a.user_id, b.user_id as other_user_id, 'username' as kind
+        from user_key a join user_key b
+        on a.name_key = b.name_key and a.user_id < b.user_id
+    union all
+    select a.user_id, b.user_id, 'email'
+        from user_key a join user_key b
+        on a.email_key = b.email_key and a.user_id < b.user_id
+    union all
+    select a.user_id, b.user_id, 'username is email'
+        from user_key a join user_key b
+        on a.name_key = b.email_key and a.user_id <> b.user_id;
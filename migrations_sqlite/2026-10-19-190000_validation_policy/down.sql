@@ -0,0 +1,4 @@
+delete from default_config where config_key in
+    ('username min length', 'username max length', 'username allowed characters',
+     'reserved usernames', 'username blocklist file', 'email max length',
+     'disposable email domains file');
@@ -0,0 +1,9 @@
+-- the ValidationPolicy for usernames and emails, see the validation module
+insert into default_config(config_key, config_value) values
+    ('username min length', '1'),
+    ('username max length', '64'),
+    ('username allowed characters', 'any'),
+    ('reserved usernames', 'admin,administrator,root,support,postmaster,webmaster,security'),
+    ('username blocklist file', ''),
+    ('email max length', '254'),
+    ('disposable email domains file', '');
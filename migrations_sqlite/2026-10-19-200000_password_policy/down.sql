@@ -0,0 +1,3 @@
+delete from default_config where config_key in
+    ('password min length', 'password max length', 'password min strength',
+     'password forbid identifiers', 'breached passwords file');
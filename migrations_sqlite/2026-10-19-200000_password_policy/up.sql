@@ -0,0 +1,7 @@
+-- the PasswordPolicy, see the password module. By default any password is accepted.
+insert into default_config(config_key, config_value) values
+    ('password min length', '1'),
+    ('password max length', '1024'),
+    ('password min strength', '0'),
+    ('password forbid identifiers', 'false'),
+    ('breached passwords file', '');
@@ -0,0 +1,6 @@
+delete from default_config where config_key in
+    ('max password age days', 'password change token validity minutes');
+
+drop table password_change_token;
+alter table users drop column must_change_password;
+alter table users drop column password_changed_at;
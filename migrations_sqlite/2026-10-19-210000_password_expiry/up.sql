@@ -0,0 +1,18 @@
+-- when the password was last changed, for 'max password age days', and whether it must be
+-- changed at the next login. SQLite can't add a column defaulting to the current time, so
+-- SqliteStore sets password_changed_at when adding users.
+alter table users add column password_changed_at timestamp not null default '1970-01-01 00:00:00';
+update users set password_changed_at = current_timestamp;
+alter table users add column must_change_password boolean not null default false;
+
+create table password_change_token (
+    id integer primary key autoincrement not null,
+    user_id integer not null references users(id) on delete cascade,
+    token_hash text not null,
+    expires timestamp not null
+);
+create index password_change_token_user_id_idx on password_change_token(user_id);
+
+insert into default_config(config_key, config_value) values
+    ('max password age days', '0'),
+    ('password change token validity minutes', '30');
@@ -0,0 +1,5 @@
+delete from default_config where config_key in
+    ('temporary password validity minutes');
+
+drop table admin_audit;
+alter table users drop column temporary_password_expires;
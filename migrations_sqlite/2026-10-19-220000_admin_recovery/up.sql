@@ -0,0 +1,16 @@
+-- when a temporary password set by an admin stops working, if it hasn't been changed by then
+alter table users add column temporary_password_expires timestamp;
+
+-- actions taken by admins on users. No foreign keys, so that the record outlives the users.
+create table admin_audit (
+    id integer primary key autoincrement not null,
+    admin_id integer not null,
+    user_id integer,
+    action text not null,
+    action_time timestamp not null default current_timestamp
+);
+create index admin_audit_user_id_idx on admin_audit(user_id);
+create index admin_audit_admin_id_idx on admin_audit(admin_id);
+
+insert into default_config(config_key, config_value) values
+    ('temporary password validity minutes', '1440');
@@ -0,0 +1,2 @@
+delete from default_config where config_key in
+    ('end other sessions on password change');
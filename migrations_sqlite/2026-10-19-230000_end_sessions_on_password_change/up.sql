@@ -0,0 +1,3 @@
+-- whether changing the password logs the user out everywhere else
+insert into default_config(config_key, config_value) values
+    ('end other sessions on password change', 'true');
@@ -0,0 +1,4 @@
+delete from default_config where config_key in
+    ('reauthentication max age minutes');
+
+alter table user_login_tokens drop column last_strong_auth;
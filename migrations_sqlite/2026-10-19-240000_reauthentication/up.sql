@@ -0,0 +1,7 @@
+-- when the user last gave their password for the token. Existing tokens have to reauthenticate
+-- before sensitive changes.
+alter table user_login_tokens add column last_strong_auth timestamp;
+
+-- how long after giving their password a user can make sensitive changes without giving it again
+insert into default_config(config_key, config_value) values
+    ('reauthentication max age minutes', '15');
@@ -0,0 +1,5 @@
+delete from default_config where config_key in
+    ('deletion grace period days', 'notify on account deletion');
+
+drop index users_deleted_at_idx;
+alter table users drop column deleted_at;
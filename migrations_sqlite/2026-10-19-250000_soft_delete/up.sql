@@ -0,0 +1,8 @@
+-- deleting a user marks them as pending deletion, until purge_deleted_users deletes them for good
+alter table users add column deleted_at timestamp;
+create index users_deleted_at_idx on users(deleted_at) where deleted_at is not null;
+
+-- how long a deleted account can be restored for. 0 deletes accounts straight away.
+insert into default_config(config_key, config_value) values
+    ('deletion grace period days', '30'),
+    ('notify on account deletion', 'true');
@@ -0,0 +1,2 @@
+alter table users drop column disabled_at;
+alter table users drop column is_admin;
@@ -0,0 +1,5 @@
+-- admins can use the functions in pauth::admin. Only an admin (or set_admin, on setup) can
+-- make a user an admin.
+alter table users add column is_admin boolean not null default false;
+-- a disabled user can't log in until an admin enables them again
+alter table users add column disabled_at timestamp;
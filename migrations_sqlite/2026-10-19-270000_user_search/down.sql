@@ -0,0 +1,3 @@
+drop index users_last_login_idx;
+drop index users_created_idx;
+alter table users drop column created;
@@ -0,0 +1,8 @@
+-- when the user was added. Users from before this was recorded get the time of the migration.
+-- SQLite can't add a column defaulting to the current time, so the store sets it.
+alter table users add column created timestamp not null default '1970-01-01 00:00:00';
+update users set created = current_timestamp;
+
+-- for search_users: the ranges it sorts by
+create index users_created_idx on users(created, id);
+create index users_last_login_idx on users(last_login, id);
@@ -0,0 +1,4 @@
+delete from default_config where config_key in
+    ('impersonation validity minutes');
+
+drop table impersonation;
@@ -0,0 +1,18 @@
+-- admins seeing pauth as a user sees it (see pauth::admin::impersonate). The token works for the
+-- user until it expires or the admin ends the impersonation. No foreign keys, so that the record
+-- outlives the users, as the admin audit does.
+create table impersonation (
+    id integer primary key autoincrement not null,
+    admin_id integer not null,
+    user_id integer not null,
+    token_hash text not null,
+    reason text not null,
+    started timestamp not null default current_timestamp,
+    expires timestamp not null,
+    ended timestamp
+);
+create index impersonation_user_id_idx on impersonation(user_id);
+create index impersonation_open_idx on impersonation(expires) where ended is null;
+
+insert into default_config(config_key, config_value) values
+    ('impersonation validity minutes', '15');
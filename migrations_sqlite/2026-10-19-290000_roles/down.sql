@@ -0,0 +1,7 @@
+delete from default_config where config_key in
+    ('permission cache seconds');
+
+drop table user_role;
+drop table role_permission;
+drop table permission;
+drop table role;
@@ -0,0 +1,27 @@
+-- roles and permissions for applications built on pauth (see pauth::roles). A user has the
+-- permissions of all of their roles.
+create table role (
+    id integer primary key autoincrement not null,
+    name text not null unique
+);
+
+create table permission (
+    id integer primary key autoincrement not null,
+    name text not null unique
+);
+
+create table role_permission (
+    role_id integer not null references role(id) on delete cascade,
+    permission_id integer not null references permission(id) on delete cascade,
+    primary key (role_id, permission_id)
+);
+
+create table user_role (
+    user_id integer not null references users(id) on delete cascade,
+    role_id integer not null references role(id) on delete cascade,
+    primary key (user_id, role_id)
+);
+create index user_role_role_id_idx on user_role(role_id);
+
+insert into default_config(config_key, config_value) values
+    ('permission cache seconds', '60');
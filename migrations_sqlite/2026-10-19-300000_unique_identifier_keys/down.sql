@@ -0,0 +1,5 @@
+drop index if exists user_key_email_key_unique;
+drop index if exists user_key_name_key_unique;
+alter table user_key drop column email_collision;
+alter table user_key drop column name_collision;
+drop table if exists user_key_form;
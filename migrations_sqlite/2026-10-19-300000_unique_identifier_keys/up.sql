@@ -0,0 +1,14 @@
+-- the form of the identifier keys and the collisions they had, see the Postgres migrations
+create table user_key_form (
+    form text primary key not null
+);
+
+alter table user_key add column name_collision boolean not null default 0;
+alter table user_key add column email_collision boolean not null default 0;
+update user_key set name_collision = 1 where exists
+    (select 1 from user_key o where o.name_key = user_key.name_key and o.user_id < user_key.user_id);
+update user_key set email_collision = 1 where exists
+    (select 1 from user_key o where o.email_key = user_key.email_key and o.user_id < user_key.user_id);
+
+create unique index user_key_name_key_unique on user_key(name_key) where not name_collision;
+create unique index user_key_email_key_unique on user_key(email_key) where not email_collision;
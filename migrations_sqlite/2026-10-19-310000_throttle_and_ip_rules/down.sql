@@ -0,0 +1,2 @@
+drop table if exists ip_rule;
+drop table if exists login_throttle;
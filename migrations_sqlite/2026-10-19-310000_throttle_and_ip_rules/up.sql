@@ -0,0 +1,21 @@
+-- login throttling and ip address rules, see the Postgres migrations. Networks are kept as text
+-- such as 10.0.0.0/8.
+create table login_throttle (
+    identifier text primary key not null,
+    failures integer not null default 0,
+    last_failure timestamp not null default current_timestamp,
+    blocked_until timestamp
+);
+
+create table ip_rule (
+    id integer primary key autoincrement not null,
+    user_id integer references users(id) on delete cascade,
+    email_domain text,
+    network text not null,
+    allow boolean not null,
+    note text,
+    created timestamp not null default current_timestamp,
+    check (user_id is null or email_domain is null)
+);
+create index ip_rule_user_id_idx on ip_rule(user_id);
+create index ip_rule_email_domain_idx on ip_rule(email_domain);
@@ -0,0 +1,1072 @@
+//! Managing users on their behalf, for helpdesks and admins. The functions here take the
+//! AuthenticatedID of the admin, who must have logged in, and whose account must be marked as an
+//! admin's in pauth - so an admin can't be impersonated by passing their id. Make the first admin
+//! with set_admin, and further admins with grant_admin. Every action is recorded in the admin
+//! audit with the id of the admin who took it (see admin_actions).
+//!
+//! An admin can set a temporary password, which is returned to pass on to the user. It must be
+//! changed at the first login (which returns LoginResult::PasswordChangeRequired), and stops
+//! working after the config 'temporary password validity minutes' if it hasn't been. Or an admin
+//! can issue a password reset token, as generate_pw_reset would, to send to the user.
+//!
+//! Admins can view, grant and revoke admin for, and enable other admins, but not act on them
+//! otherwise - disabling, logging out, resetting, unlocking or erasing another admin, or
+//! overriding their config, is NotAuthorised. Revoke their admin first, which is recorded in
+//! the audit like everything else.
+//!
+//! A disabled user can't log in (LoginResult::AccountDisabled) or reset their password, and
+//! their sessions are ended, until an admin enables them again.
+//!
+//! search_users lists the users matching a UserFilter a page at a time. Each page gives a cursor
+//! for the next, which can be passed around as a string (such as in a link). user_counts gives
+//! the numbers of users in each state, for dashboards.
+//!
+//! To see what a user sees, an admin can impersonate them, giving a reason. The AuthenticatedID
+//! from impersonate works as the user's own would (check_id accepts it), but is read only:
+//! change_details and reauthenticate refuse it, as do actions needing a recent authentication
+//! and the admin functions here. impersonation tells it apart from the user's own, and gives the
+//! real admin's id. It stops working when the config 'impersonation validity minutes' pass, or
+//! end_impersonation is called. Admins can't be impersonated. The start and end, and any change
+//! refused, are recorded in the admin audit.
+use crate::config;
+use crate::identifier::Canonicaliser;
+use crate::models::{self, AuthenticatedID, User, UserUpdate};
+use crate::notify::{self, SecurityEvent};
+use crate::pauth_error::ApplicationError;
+use crate::roles;
+use crate::store::{self, Store};
+use chrono::{Duration, NaiveDateTime, Utc};
+use std::fmt;
+use std::str::FromStr;
+
+/// Generated temporary passwords are this long, from letters and digits.
+const TEMPORARY_PASSWORD_LENGTH: usize = 16;
+
+/// AuthenticatedIDs from impersonate have tokens this long, from letters and digits.
+const IMPERSONATION_TOKEN_LENGTH: usize = 32;
+
+/// Pages of search_users are at most this long.
+const MAX_PAGE_SIZE: i64 = 1000;
+
+/// How times are written in cursors.
+const CURSOR_TIME_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%.f";
+
+/// An action an admin took on a user.
+#[derive(Queryable, Clone, Debug, PartialEq)]
+pub struct AdminAction {
+    pub admin_id: i32,
+    pub user_id: Option<i32>,
+    pub action: String,
+    pub time: NaiveDateTime,
+}
+
+/// An admin acting as a user (see impersonate).
+#[derive(Queryable, Clone, Debug, PartialEq)]
+pub struct Impersonation {
+    pub id: i32,
+    /// The admin who is impersonating the user.
+    pub admin_id: i32,
+    pub user_id: i32,
+    pub reason: String,
+    pub started: NaiveDateTime,
+    pub expires: NaiveDateTime,
+    pub ended: Option<NaiveDateTime>,
+}
+
+/// The result of an admin function. NotAuthorised if the AuthenticatedID is not valid, or is not
+/// an admin's (or the admin is disabled or pending deletion), and NotFound if there is no such
+/// user.
+#[derive(Debug, PartialEq)]
+pub enum AdminResult<T> {
+    Done(T),
+    NotAuthorised,
+    NotFound,
+}
+
+/// Which users search_users finds. Every filter given must match. Prefixes are compared as
+/// usernames and emails are (see the identifier module), so ignore case by default. Ranges
+/// include their since time, and exclude their before time.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct UserFilter {
+    pub name_prefix: Option<String>,
+    pub email_prefix: Option<String>,
+    pub created_since: Option<NaiveDateTime>,
+    pub created_before: Option<NaiveDateTime>,
+    pub last_login_since: Option<NaiveDateTime>,
+    pub last_login_before: Option<NaiveDateTime>,
+    pub disabled: Option<bool>,
+    pub locked: Option<bool>,
+    pub pending_deletion: Option<bool>,
+    pub admin: Option<bool>,
+}
+
+impl UserFilter {
+    /// Whether the user matches, for the stores which don't filter in SQL.
+    pub(crate) fn matches(&self, user: &User, canonicaliser: &Canonicaliser) -> bool {
+        let in_range =
+            |time: NaiveDateTime, since: Option<NaiveDateTime>, before: Option<NaiveDateTime>| {
+                since.is_none_or(|since| time >= since) && before.is_none_or(|before| time < before)
+            };
+        let has = |wanted: Option<bool>, actual: bool| wanted.is_none_or(|w| w == actual);
+        self.name_prefix.as_ref().is_none_or(|prefix| {
+            canonicaliser
+                .key(&user.chosen_name)
+                .starts_with(&canonicaliser.key(prefix))
+        }) && self.email_prefix.as_ref().is_none_or(|prefix| {
+            canonicaliser
+                .key(&user.email)
+                .starts_with(&canonicaliser.key(prefix))
+        }) && in_range(user.created, self.created_since, self.created_before)
+            && in_range(
+                user.last_login,
+                self.last_login_since,
+                self.last_login_before,
+            )
+            && has(self.disabled, user.disabled_at.is_some())
+            && has(self.locked, user.locked_at.is_some())
+            && has(self.pending_deletion, user.deleted_at.is_some())
+            && has(self.admin, user.is_admin)
+    }
+}
+
+/// What search_users orders users by. Names and emails are ordered by their canonical form, and
+/// users with the same value by id.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum UserSort {
+    #[default]
+    Id,
+    Name,
+    Email,
+    Created,
+    LastLogin,
+}
+
+impl UserSort {
+    fn code(self) -> &'static str {
+        match self {
+            UserSort::Id => "i",
+            UserSort::Name => "n",
+            UserSort::Email => "e",
+            UserSort::Created => "c",
+            UserSort::LastLogin => "l",
+        }
+    }
+}
+
+/// The value a user is sorted by.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum SortKey {
+    Id,
+    Text(String),
+    Time(NaiveDateTime),
+}
+
+impl SortKey {
+    pub(crate) fn of(sort: UserSort, user: &User, canonicaliser: &Canonicaliser) -> SortKey {
+        match sort {
+            UserSort::Id => SortKey::Id,
+            UserSort::Name => SortKey::Text(canonicaliser.key(&user.chosen_name)),
+            UserSort::Email => SortKey::Text(canonicaliser.key(&user.email)),
+            UserSort::Created => SortKey::Time(user.created),
+            UserSort::LastLogin => SortKey::Time(user.last_login),
+        }
+    }
+
+    pub(crate) fn text(&self) -> String {
+        match self {
+            SortKey::Text(text) => text.clone(),
+            _ => String::new(),
+        }
+    }
+
+    pub(crate) fn time(&self) -> NaiveDateTime {
+        match self {
+            SortKey::Time(time) => *time,
+            _ => NaiveDateTime::default(),
+        }
+    }
+}
+
+/// Where a page of search_users starts: after the last user of the page before. It can be
+/// written as a string with to_string, and read back with parse.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UserCursor {
+    pub(crate) sort: UserSort,
+    pub(crate) id: i32,
+    pub(crate) key: SortKey,
+}
+
+impl fmt::Display for UserCursor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}", self.sort.code(), self.id)?;
+        match &self.key {
+            SortKey::Id => Ok(()),
+            SortKey::Text(text) => write!(f, ".{}", text),
+            SortKey::Time(time) => write!(f, ".{}", time.format(CURSOR_TIME_FORMAT)),
+        }
+    }
+}
+
+impl FromStr for UserCursor {
+    type Err = ApplicationError;
+
+    fn from_str(cursor: &str) -> Result<UserCursor, ApplicationError> {
+        let invalid =
+            || ApplicationError::ApplicationDataLogic(format!("invalid cursor {}", cursor));
+        let mut parts = cursor.splitn(3, '.');
+        let sort = match parts.next() {
+            Some("i") => UserSort::Id,
+            Some("n") => UserSort::Name,
+            Some("e") => UserSort::Email,
+            Some("c") => UserSort::Created,
+            Some("l") => UserSort::LastLogin,
+            _ => return Err(invalid()),
+        };
+        let id = parts
+            .next()
+            .and_then(|id| id.parse().ok())
+            .ok_or_else(invalid)?;
+        let key = match (sort, parts.next()) {
+            (UserSort::Id, None) => SortKey::Id,
+            (UserSort::Name, Some(text)) | (UserSort::Email, Some(text)) => {
+                SortKey::Text(text.to_owned())
+            }
+            (UserSort::Created, Some(time)) | (UserSort::LastLogin, Some(time)) => SortKey::Time(
+                NaiveDateTime::parse_from_str(time, CURSOR_TIME_FORMAT).map_err(|_| invalid())?,
+            ),
+            _ => return Err(invalid()),
+        };
+        Ok(UserCursor { sort, id, key })
+    }
+}
+
+/// A page of search_users to get: the first, or the one after a cursor.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PageRequest {
+    pub sort: UserSort,
+    pub descending: bool,
+    pub after: Option<UserCursor>,
+    /// At most MAX_PAGE_SIZE (1000).
+    pub size: i64,
+}
+
+impl Default for PageRequest {
+    fn default() -> Self {
+        PageRequest {
+            sort: UserSort::Id,
+            descending: false,
+            after: None,
+            size: 50,
+        }
+    }
+}
+
+/// A page of users from search_users. next is the cursor for the next page, if there is one,
+/// and total the number of users matching the filter across all pages.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UserPage {
+    pub users: Vec<User>,
+    pub next: Option<UserCursor>,
+    pub total: i64,
+}
+
+/// The numbers of users in each state, from user_counts.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct UserCounts {
+    pub total: i64,
+    pub admins: i64,
+    pub disabled: i64,
+    pub locked: i64,
+    pub pending_deletion: i64,
+}
+
+/// Whether the AuthenticatedID is valid and belongs to an admin who can act.
+pub(crate) fn is_admin(
+    store: &dyn Store,
+    admin: &AuthenticatedID,
+) -> Result<bool, ApplicationError> {
+    //impersonations are deliberately not accepted here, so can't be used to act as an admin
+    if !store.token_valid(admin.user_id, &admin.token)? {
+        return Ok(false);
+    }
+    Ok(store.user_by_id(admin.user_id)?.is_some_and(can_act))
+}
+
+/// Whether the user is an admin who can act.
+fn can_act(user: User) -> bool {
+    user.is_admin && user.disabled_at.is_none() && user.deleted_at.is_none()
+}
+
+/// Run f on the user if the admin is authorised, recording the action in the audit.
+pub(crate) fn as_admin<T, F>(
+    name: &str,
+    admin: &AuthenticatedID,
+    uid: i32,
+    action: &str,
+    f: F,
+) -> Result<AdminResult<T>, ApplicationError>
+where
+    F: FnOnce(&dyn Store, User) -> Result<T, ApplicationError>,
+{
+    act(name, admin, uid, action, true, f)
+}
+
+/// As as_admin, for actions which change the user or their access. NotAuthorised if the user is
+/// another admin (see the module docs).
+pub(crate) fn over_user<T, F>(
+    name: &str,
+    admin: &AuthenticatedID,
+    uid: i32,
+    action: &str,
+    f: F,
+) -> Result<AdminResult<T>, ApplicationError>
+where
+    F: FnOnce(&dyn Store, User) -> Result<T, ApplicationError>,
+{
+    act(name, admin, uid, action, false, f)
+}
+
+fn act<T, F>(
+    name: &str,
+    admin: &AuthenticatedID,
+    uid: i32,
+    action: &str,
+    other_admins: bool,
+    f: F,
+) -> Result<AdminResult<T>, ApplicationError>
+where
+    F: FnOnce(&dyn Store, User) -> Result<T, ApplicationError>,
+{
+    let store = store::current();
+    store.api_call(name, &[&admin.user_id.to_string(), &uid.to_string()])?;
+    if !is_admin(&*store, admin)? {
+        return Ok(AdminResult::NotAuthorised);
+    }
+    let user = match store.user_by_id(uid)? {
+        Some(user) => user,
+        None => return Ok(AdminResult::NotFound),
+    };
+    if !other_admins && user.is_admin && user.id != admin.user_id {
+        return Ok(AdminResult::NotAuthorised);
+    }
+    let result = f(&*store, user)?;
+    store.record_admin_action(admin.user_id, Some(uid), action)?;
+    Ok(AdminResult::Done(result))
+}
+
+/// Make the user an admin, or not, without needing an admin. For setting up the first admin from
+/// somewhere trusted, such as a command line tool - never with an id from a request. Returns
+/// false if there is no such user.
+pub fn set_admin(uid: i32, is_admin: bool) -> Result<bool, ApplicationError> {
+    let store = store::current();
+    store.api_call("set_admin", &[&uid.to_string()])?;
+    store.set_admin(uid, is_admin)
+}
+
+/// Make the user an admin.
+pub fn grant_admin(admin: &AuthenticatedID, uid: i32) -> Result<AdminResult<()>, ApplicationError> {
+    as_admin("grant_admin", admin, uid, "grant admin", |store, user| {
+        store.set_admin(user.id, true).map(|_| ())
+    })
+}
+
+/// Stop the user being an admin. Their sessions carry on, as an ordinary user's.
+pub fn revoke_admin(
+    admin: &AuthenticatedID,
+    uid: i32,
+) -> Result<AdminResult<()>, ApplicationError> {
+    as_admin("revoke_admin", admin, uid, "revoke admin", |store, user| {
+        store.set_admin(user.id, false).map(|_| ())
+    })
+}
+
+/// The user's details.
+pub fn view_user(admin: &AuthenticatedID, uid: i32) -> Result<AdminResult<User>, ApplicationError> {
+    as_admin("view_user", admin, uid, "view user", |_, user| Ok(user))
+}
+
+/// Disable the user, ending their sessions. They are told, as the config
+/// 'notify on account disabled' says.
+pub fn disable_user(
+    admin: &AuthenticatedID,
+    uid: i32,
+) -> Result<AdminResult<()>, ApplicationError> {
+    over_user("disable_user", admin, uid, "disable user", |store, user| {
+        store.set_disabled(user.id, Some(Utc::now().naive_utc()))?;
+        store.delete_tokens(user.id, None)?;
+        roles::sessions_ended(user.id);
+        store.delete_password_change_tokens(user.id)?;
+        let _ = notify::dispatch(SecurityEvent::AccountDisabled, &user, None);
+        Ok(())
+    })
+}
+
+/// Let a disabled user log in again.
+pub fn enable_user(admin: &AuthenticatedID, uid: i32) -> Result<AdminResult<()>, ApplicationError> {
+    as_admin("enable_user", admin, uid, "enable user", |store, user| {
+        store.set_disabled(user.id, None).map(|_| ())
+    })
+}
+
+/// End all of the user's sessions, returning how many there were. They can log in again.
+pub fn force_logout(
+    admin: &AuthenticatedID,
+    uid: i32,
+) -> Result<AdminResult<usize>, ApplicationError> {
+    over_user("force_logout", admin, uid, "force logout", |store, user| {
+        store.delete_password_change_tokens(user.id)?;
+        let ended = store.delete_tokens(user.id, None)?;
+        roles::sessions_ended(user.id);
+        Ok(ended)
+    })
+}
+
+/// Unlock the user's account, locked after too many failed logins.
+pub fn unlock_user(admin: &AuthenticatedID, uid: i32) -> Result<AdminResult<()>, ApplicationError> {
+    over_user(
+        "unlock_user",
+        admin,
+        uid,
+        "unlock account",
+        |store, user| store.unlock_user(user.id).map(|_| ()),
+    )
+}
+
+/// Make the user change their password the next time they log in.
+pub fn force_password_change(
+    admin: &AuthenticatedID,
+    uid: i32,
+) -> Result<AdminResult<()>, ApplicationError> {
+    over_user(
+        "force_password_change",
+        admin,
+        uid,
+        "require password change",
+        |store, user| models::mark_password_change(store, user.id).map(|_| ()),
+    )
+}
+
+/// Set a generated temporary password for the user, returning it. The account is unlocked, and
+/// the user's outstanding resets no longer work.
+pub fn set_temporary_password(
+    admin: &AuthenticatedID,
+    uid: i32,
+) -> Result<AdminResult<String>, ApplicationError> {
+    over_user(
+        "set_temporary_password",
+        admin,
+        uid,
+        "set temporary password",
+        |store, user| {
+            let password = models::generate_random_string(TEMPORARY_PASSWORD_LENGTH);
+            let validity =
+                config::get_config_i64("temporary password validity minutes", Some(uid), 1440)?;
+            let changes = UserUpdate::with_temporary_password(
+                &password,
+                Utc::now().naive_utc() + Duration::minutes(validity),
+            )?;
+            store.update_user(uid, &changes)?;
+            store.unlock_user(uid)?;
+            store.delete_password_change_tokens(uid)?;
+            let _ = notify::dispatch(SecurityEvent::PasswordChanged, &user, None);
+            Ok(password)
+        },
+    )
+}
+
+/// Issue a password reset token for the user, to send them (such as in a link). It expires
+/// after the config 'password reset validity minutes'.
+pub fn issue_pw_reset(
+    admin: &AuthenticatedID,
+    uid: i32,
+) -> Result<AdminResult<String>, ApplicationError> {
+    over_user(
+        "issue_pw_reset",
+        admin,
+        uid,
+        "issue password reset",
+        |store, user| {
+            let tok = models::insert_pw_reset(store, uid, Some(models::pw_reset_expiry(uid)?))?;
+            let _ = notify::dispatch(SecurityEvent::PasswordResetRequested, &user, None);
+            Ok(tok)
+        },
+    )
+}
+
+/// Override a config value for the user, as set_user_config does.
+pub fn set_config_override(
+    admin: &AuthenticatedID,
+    uid: i32,
+    key: &str,
+    value: &str,
+) -> Result<AdminResult<()>, ApplicationError> {
+    let action = format!("set config override: {}", key);
+    over_user("set_config_override", admin, uid, &action, |store, _| {
+        store.set_user_config(uid, key, value)
+    })
+}
+
+/// Remove the user's override for a config value, so the default applies again.
+pub fn remove_config_override(
+    admin: &AuthenticatedID,
+    uid: i32,
+    key: &str,
+) -> Result<AdminResult<()>, ApplicationError> {
+    let action = format!("remove config override: {}", key);
+    over_user("remove_config_override", admin, uid, &action, |store, _| {
+        store.remove_user_config(uid, key)
+    })
+}
+
+/// Give the admin an AuthenticatedID for the user, to see pauth as they do (see the module
+/// docs). The reason is recorded in the admin audit. NotAuthorised if the user is an admin.
+pub fn impersonate(
+    admin: &AuthenticatedID,
+    uid: i32,
+    reason: &str,
+) -> Result<AdminResult<AuthenticatedID>, ApplicationError> {
+    let store = store::current();
+    store.api_call(
+        "impersonate",
+        &[&admin.user_id.to_string(), &uid.to_string()],
+    )?;
+    if !is_admin(&*store, admin)? {
+        return Ok(AdminResult::NotAuthorised);
+    }
+    let user = match store.user_by_id(uid)? {
+        Some(user) => user,
+        None => return Ok(AdminResult::NotFound),
+    };
+    if user.is_admin {
+        return Ok(AdminResult::NotAuthorised);
+    }
+    let validity = config::get_config_i64("impersonation validity minutes", Some(uid), 15)?;
+    let token = models::generate_random_string(IMPERSONATION_TOKEN_LENGTH);
+    store.insert_impersonation(
+        admin.user_id,
+        uid,
+        &store.hash(&token)?,
+        reason,
+        Utc::now().naive_utc() + Duration::minutes(validity),
+    )?;
+    store.record_admin_action(
+        admin.user_id,
+        Some(uid),
+        &format!("start impersonation: {}", reason),
+    )?;
+    Ok(AdminResult::Done(AuthenticatedID {
+        user_id: uid,
+        token,
+    }))
+}
+
+/// The impersonation the AuthenticatedID is from, if it is from impersonate and still works.
+/// None for the user's own AuthenticatedIDs.
+pub fn impersonation(
+    auth_token: &AuthenticatedID,
+) -> Result<Option<Impersonation>, ApplicationError> {
+    let store = store::current();
+    store.api_call("impersonation", &[&auth_token.user_id.to_string()])?;
+    current_impersonation(&*store, auth_token)
+}
+
+/// Stop the AuthenticatedID from impersonate working. Returns false if it isn't one which still
+/// works.
+pub fn end_impersonation(auth_token: &AuthenticatedID) -> Result<bool, ApplicationError> {
+    let store = store::current();
+    store.api_call("end_impersonation", &[&auth_token.user_id.to_string()])?;
+    match current_impersonation(&*store, auth_token)? {
+        Some(impersonation) => record_end(
+            &*store,
+            &impersonation,
+            Utc::now().naive_utc(),
+            "end impersonation",
+        ),
+        None => Ok(false),
+    }
+}
+
+/// Record the end of impersonations which have expired, returning how many there were. Expired
+/// impersonations stop working either way, but otherwise their end is only recorded when they
+/// are next used. Call periodically, such as hourly.
+pub fn end_expired_impersonations() -> Result<usize, ApplicationError> {
+    let store = store::current();
+    store.api_call("end_expired_impersonations", &[])?;
+    let mut ended = 0;
+    for impersonation in store.expired_impersonations(Utc::now().naive_utc())? {
+        if record_end(
+            &*store,
+            &impersonation,
+            impersonation.expires,
+            "end impersonation: expired",
+        )? {
+            ended += 1;
+        }
+    }
+    Ok(ended)
+}
+
+/// The impersonation the AuthenticatedID is from, if it still works. One which has expired, whose
+/// admin can no longer act, or whose user has become an admin, is ended.
+pub(crate) fn current_impersonation(
+    store: &dyn Store,
+    auth_token: &AuthenticatedID,
+) -> Result<Option<Impersonation>, ApplicationError> {
+    let impersonation = match store.impersonation(auth_token.user_id, &auth_token.token)? {
+        Some(impersonation) => impersonation,
+        None => return Ok(None),
+    };
+    let now = Utc::now().naive_utc();
+    let ended = if impersonation.expires <= now {
+        Some((impersonation.expires, "end impersonation: expired"))
+    } else if !store
+        .user_by_id(impersonation.admin_id)?
+        .is_some_and(can_act)
+    {
+        Some((now, "end impersonation: admin can no longer act"))
+    } else if store
+        .user_by_id(impersonation.user_id)?
+        .is_none_or(|user| user.is_admin)
+    {
+        Some((now, "end impersonation: user is an admin"))
+    } else {
+        None
+    };
+    match ended {
+        Some((time, action)) => {
+            record_end(store, &impersonation, time, action)?;
+            Ok(None)
+        }
+        None => Ok(Some(impersonation)),
+    }
+}
+
+/// End the impersonation and record it in the audit, unless it has already ended. Returns
+/// whether this call ended it.
+fn record_end(
+    store: &dyn Store,
+    impersonation: &Impersonation,
+    time: NaiveDateTime,
+    action: &str,
+) -> Result<bool, ApplicationError> {
+    if !store.end_impersonation(impersonation.id, time)? {
+        return Ok(false);
+    }
+    store.record_admin_action(impersonation.admin_id, Some(impersonation.user_id), action)?;
+    Ok(true)
+}
+
+/// The actions admins have taken on the user, oldest first. These are kept after the user is
+/// deleted, so this is never NotFound.
+pub fn admin_actions(
+    admin: &AuthenticatedID,
+    uid: i32,
+) -> Result<AdminResult<Vec<AdminAction>>, ApplicationError> {
+    let store = store::current();
+    store.api_call(
+        "admin_actions",
+        &[&admin.user_id.to_string(), &uid.to_string()],
+    )?;
+    if !is_admin(&*store, admin)? {
+        return Ok(AdminResult::NotAuthorised);
+    }
+    Ok(AdminResult::Done(store.admin_actions(uid)?))
+}
+
+/// A page of the users matching the filter. Fails if the page's cursor is for another sort.
+pub fn search_users(
+    admin: &AuthenticatedID,
+    filter: &UserFilter,
+    page: &PageRequest,
+) -> Result<AdminResult<UserPage>, ApplicationError> {
+    let store = store::current();
+    store.api_call("search_users", &[&admin.user_id.to_string()])?;
+    if !is_admin(&*store, admin)? {
+        return Ok(AdminResult::NotAuthorised);
+    }
+    if page
+        .after
+        .as_ref()
+        .is_some_and(|after| after.sort != page.sort)
+    {
+        return Err(ApplicationError::ApplicationDataLogic(
+            "the cursor is for another sort".to_owned(),
+        ));
+    }
+    let size = page.size.clamp(1, MAX_PAGE_SIZE);
+    //one more than the page, to tell whether there is a next page
+    let mut users = store.search_users(filter, page, size + 1)?;
+    let next = if users.len() as i64 > size {
+        users.truncate(size as usize);
+        let canonicaliser = Canonicaliser::new(&*store)?;
+        users.last().map(|last| UserCursor {
+            sort: page.sort,
+            id: last.id,
+            key: SortKey::of(page.sort, last, &canonicaliser),
+        })
+    } else {
+        None
+    };
+    let total = store.count_users(filter)?;
+    store.record_admin_action(admin.user_id, None, "search users")?;
+    Ok(AdminResult::Done(UserPage { users, next, total }))
+}
+
+/// The numbers of users in each state.
+pub fn user_counts(admin: &AuthenticatedID) -> Result<AdminResult<UserCounts>, ApplicationError> {
+    let store = store::current();
+    store.api_call("user_counts", &[&admin.user_id.to_string()])?;
+    if !is_admin(&*store, admin)? {
+        return Ok(AdminResult::NotAuthorised);
+    }
+    let count = |filter: UserFilter| store.count_users(&filter);
+    Ok(AdminResult::Done(UserCounts {
+        total: count(UserFilter::default())?,
+        admins: count(UserFilter {
+            admin: Some(true),
+            ..UserFilter::default()
+        })?,
+        disabled: count(UserFilter {
+            disabled: Some(true),
+            ..UserFilter::default()
+        })?,
+        locked: count(UserFilter {
+            locked: Some(true),
+            ..UserFilter::default()
+        })?,
+        pending_deletion: count(UserFilter {
+            pending_deletion: Some(true),
+            ..UserFilter::default()
+        })?,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::admin::*;
+    use crate::db;
+    use crate::models::*;
+    use crate::test_db::{added, admin, TestDb, PASSWORD};
+    use diesel::prelude::*;
+
+    fn done<T: std::fmt::Debug>(result: AdminResult<T>) -> T {
+        match result {
+            AdminResult::Done(value) => value,
+            r => panic!("Test failure: admin action not done, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn helpdesk_recovery() {
+        let _db = TestDb::new();
+        let (first, second) = (admin("Admin1"), admin("Admin2"));
+        let uid = added("Helpdesk").user_id;
+        store::current()
+            .lock_user(uid, Utc::now().naive_utc())
+            .unwrap();
+        assert_eq!(
+            AdminResult::NotFound,
+            set_temporary_password(&first, -1).unwrap()
+        );
+
+        let temporary = done(set_temporary_password(&first, uid).unwrap());
+        assert_eq!(TEMPORARY_PASSWORD_LENGTH, temporary.len());
+        assert_eq!(
+            LoginResult::AuthenticationFailure,
+            login("Helpdesk", PASSWORD).unwrap()
+        );
+        let limited = match login("Helpdesk", &temporary).unwrap() {
+            LoginResult::PasswordChangeRequired(id) => id,
+            r => panic!("Test failure: password change not required, got {:?}", r),
+        };
+        assert_eq!(
+            ChangeDetailsResult::PasswordChanged { sessions_ended: 1 },
+            change_details(&limited, &UserUpdate::with_password("remembered").unwrap()).unwrap()
+        );
+        let auth_id = match login("Helpdesk", "remembered").unwrap() {
+            LoginResult::LoggedIn(id) => id,
+            r => panic!("Test failure: login failed, got {:?}", r),
+        };
+        assert_eq!(
+            None,
+            get_user(&auth_id)
+                .unwrap()
+                .unwrap()
+                .temporary_password_expires
+        );
+
+        //an unused temporary password stops working
+        let temporary = done(set_temporary_password(&second, uid).unwrap());
+        db::connection()
+            .unwrap()
+            .execute(&format!(
+                "update pauth.users set temporary_password_expires = now() - interval '1 minute'
+                 where id = {}",
+                uid
+            ))
+            .unwrap();
+        assert_eq!(
+            LoginResult::AuthenticationFailure,
+            login("Helpdesk", &temporary).unwrap()
+        );
+
+        let token = done(issue_pw_reset(&first, uid).unwrap());
+        assert!(matches!(
+            validate_pw_reset("Helpdesk", token).unwrap(),
+            LoginResult::PasswordChangeRequired(_)
+        ));
+        assert_eq!(AdminResult::NotFound, issue_pw_reset(&first, -1).unwrap());
+
+        let actions: Vec<(i32, String)> = done(admin_actions(&first, uid).unwrap())
+            .into_iter()
+            .map(|a| (a.admin_id, a.action))
+            .collect();
+        let expected = vec![
+            (first.user_id, "set temporary password".to_owned()),
+            (second.user_id, "set temporary password".to_owned()),
+            (first.user_id, "issue password reset".to_owned()),
+        ];
+        assert_eq!(expected, actions);
+        //the audit outlives the user
+        assert!(store::current().delete_user(uid).unwrap());
+        assert_eq!(3, done(admin_actions(&first, uid).unwrap()).len());
+    }
+
+    #[test]
+    fn only_admins_can_act() {
+        let _db = TestDb::new();
+        let first = admin("Admin3");
+        let user = added("Ordinary");
+        let uid = user.user_id;
+        let forged = AuthenticatedID {
+            user_id: first.user_id,
+            token: user.token.clone(),
+        };
+        assert_eq!(AdminResult::NotAuthorised, view_user(&user, uid).unwrap());
+        assert_eq!(AdminResult::NotAuthorised, view_user(&forged, uid).unwrap());
+        assert_eq!(AdminResult::NotAuthorised, grant_admin(&user, uid).unwrap());
+        assert_eq!(
+            AdminResult::NotAuthorised,
+            admin_actions(&user, uid).unwrap()
+        );
+        assert_eq!(
+            "Ordinary",
+            done(view_user(&first, uid).unwrap()).chosen_name
+        );
+        assert_eq!(AdminResult::NotFound, view_user(&first, -1).unwrap());
+
+        done(grant_admin(&first, uid).unwrap());
+        assert!(done(view_user(&user, first.user_id).unwrap()).is_admin);
+        done(revoke_admin(&first, uid).unwrap());
+        assert_eq!(AdminResult::NotAuthorised, view_user(&user, uid).unwrap());
+    }
+
+    #[test]
+    fn disable_and_log_out() {
+        let _db = TestDb::new();
+        let first = admin("Admin4");
+        let user = added("Disabled");
+        let uid = user.user_id;
+        assert!(matches!(
+            login("Disabled", PASSWORD).unwrap(),
+            LoginResult::LoggedIn(_)
+        ));
+        assert_eq!(2, done(force_logout(&first, uid).unwrap()));
+        assert!(!check_id(&user).unwrap());
+
+        let user = match login("Disabled", PASSWORD).unwrap() {
+            LoginResult::LoggedIn(id) => id,
+            r => panic!("Test failure: login failed, got {:?}", r),
+        };
+        done(disable_user(&first, uid).unwrap());
+        assert!(!check_id(&user).unwrap());
+        assert_eq!(
+            LoginResult::AccountDisabled,
+            login("Disabled", PASSWORD).unwrap()
+        );
+        let token = generate_pw_reset("Disabled", None).unwrap().unwrap();
+        assert_eq!(
+            LoginResult::AccountDisabled,
+            validate_pw_reset("Disabled", token).unwrap()
+        );
+        //a disabled admin is no longer one
+        assert!(set_admin(uid, true).unwrap());
+        assert_eq!(AdminResult::NotAuthorised, view_user(&user, uid).unwrap());
+        done(enable_user(&first, uid).unwrap());
+        assert!(matches!(
+            login("Disabled", PASSWORD).unwrap(),
+            LoginResult::LoggedIn(_)
+        ));
+        assert!(set_admin(uid, false).unwrap());
+
+        done(set_config_override(&first, uid, "password reset validity minutes", "5").unwrap());
+        assert_eq!(
+            Some("5".to_owned()),
+            crate::get_config("password reset validity minutes", Some(uid)).unwrap()
+        );
+        done(remove_config_override(&first, uid, "password reset validity minutes").unwrap());
+        assert_eq!(
+            Some("720".to_owned()),
+            crate::get_config("password reset validity minutes", Some(uid)).unwrap()
+        );
+        let actions: Vec<String> = done(admin_actions(&first, uid).unwrap())
+            .into_iter()
+            .map(|a| a.action)
+            .collect();
+        let expected = vec![
+            "force logout",
+            "disable user",
+            "enable user",
+            "set config override: password reset validity minutes",
+            "remove config override: password reset validity minutes",
+        ];
+        assert_eq!(expected, actions);
+    }
+
+    fn refused<T>(result: Result<AdminResult<T>, ApplicationError>) -> bool {
+        matches!(result.unwrap(), AdminResult::NotAuthorised)
+    }
+
+    #[test]
+    fn admins_are_left_to_admins() {
+        let _db = TestDb::new();
+        let (first, second) = (admin("Admin7"), admin("Admin8"));
+        let other = second.user_id;
+        assert!(refused(disable_user(&first, other)));
+        assert!(refused(force_logout(&first, other)));
+        assert!(refused(unlock_user(&first, other)));
+        assert!(refused(force_password_change(&first, other)));
+        assert!(refused(set_temporary_password(&first, other)));
+        assert!(refused(issue_pw_reset(&first, other)));
+        assert!(refused(set_config_override(
+            &first,
+            other,
+            "max password age days",
+            "1"
+        )));
+        assert!(refused(crate::privacy::erase_user(&first, other)));
+        assert!(check_id(&second).unwrap());
+        assert!(done(admin_actions(&first, other).unwrap()).is_empty());
+
+        //an admin can act on themselves, and on a former admin
+        assert_eq!(1, done(force_logout(&second, other).unwrap()));
+        done(revoke_admin(&first, other).unwrap());
+        store::current()
+            .lock_user(other, Utc::now().naive_utc())
+            .unwrap();
+        done(unlock_user(&first, other).unwrap());
+        done(force_password_change(&first, other).unwrap());
+        assert!(matches!(
+            login("Admin8", PASSWORD).unwrap(),
+            LoginResult::PasswordChangeRequired(_)
+        ));
+        let actions: Vec<String> = done(admin_actions(&first, other).unwrap())
+            .into_iter()
+            .map(|a| a.action)
+            .collect();
+        let expected = vec![
+            "force logout",
+            "revoke admin",
+            "unlock account",
+            "require password change",
+        ];
+        assert_eq!(expected, actions);
+    }
+
+    #[test]
+    fn impersonation_is_audited() {
+        let _db = TestDb::new();
+        let (first, second) = (admin("Admin5"), admin("Admin6"));
+        let user = added("Customer");
+        let uid = user.user_id;
+        assert_eq!(
+            AdminResult::NotAuthorised,
+            impersonate(&user, uid, "ticket 49").unwrap()
+        );
+        assert_eq!(
+            AdminResult::NotAuthorised,
+            impersonate(&first, second.user_id, "ticket 49").unwrap()
+        );
+        assert_eq!(
+            AdminResult::NotFound,
+            impersonate(&first, -1, "ticket 49").unwrap()
+        );
+
+        let as_user = done(impersonate(&first, uid, "ticket 49").unwrap());
+        assert_eq!(uid, as_user.user_id);
+        assert!(check_id(&as_user).unwrap());
+        assert_eq!("Customer", get_user(&as_user).unwrap().unwrap().chosen_name);
+        let started = impersonation(&as_user).unwrap().unwrap();
+        assert_eq!(
+            (first.user_id, "ticket 49"),
+            (started.admin_id, &*started.reason)
+        );
+        assert_eq!(None, impersonation(&user).unwrap());
+        //it can't be used for changes, sensitive actions, or as an admin
+        assert_eq!(
+            ChangeDetailsResult::AuthenticationFailure,
+            change_details(&as_user, UserUpdate::new().with_chosen_name("Renamed")).unwrap()
+        );
+        assert!(!reauthenticate(&as_user, PASSWORD).unwrap());
+        assert_eq!("Customer", get_user(&user).unwrap().unwrap().chosen_name);
+        assert_eq!(
+            DeleteUserResult::ReauthRequired,
+            delete_user(&as_user).unwrap()
+        );
+        assert_eq!(
+            DeleteUserResult::AuthFailure,
+            delete_user_with_password(&as_user, PASSWORD).unwrap()
+        );
+        assert_eq!(
+            AdminResult::NotAuthorised,
+            view_user(&as_user, uid).unwrap()
+        );
+        assert!(end_impersonation(&as_user).unwrap());
+        assert!(!end_impersonation(&as_user).unwrap());
+        assert!(!check_id(&as_user).unwrap());
+        assert!(check_id(&user).unwrap());
+
+        let expire = || {
+            db::connection()
+                .unwrap()
+                .execute(
+                    "update pauth.impersonation set expires = now() - interval '1 hour'
+                     where ended is null",
+                )
+                .unwrap()
+        };
+        //ended when next used after expiring, or by end_expired_impersonations
+        let as_user = done(impersonate(&second, uid, "ticket 50").unwrap());
+        expire();
+        assert!(!check_id(&as_user).unwrap());
+        assert_eq!(0, end_expired_impersonations().unwrap());
+        let as_user = done(impersonate(&second, uid, "ticket 51").unwrap());
+        expire();
+        assert_eq!(1, end_expired_impersonations().unwrap());
+        assert!(!check_id(&as_user).unwrap());
+
+        //or when the admin is no longer one
+        let as_user = done(impersonate(&first, uid, "ticket 52").unwrap());
+        done(revoke_admin(&second, first.user_id).unwrap());
+        assert!(!check_id(&as_user).unwrap());
+
+        let actions: Vec<String> = done(admin_actions(&second, uid).unwrap())
+            .into_iter()
+            .map(|a| a.action)
+            .collect();
+        let expected = vec![
+            "start impersonation: ticket 49",
+            "change details refused: impersonating",
+            "delete user refused: impersonating",
+            "delete user refused: impersonating",
+            "end impersonation",
+            "start impersonation: ticket 50",
+            "end impersonation: expired",
+            "start impersonation: ticket 51",
+            "end impersonation: expired",
+            "start impersonation: ticket 52",
+            "end impersonation: admin can no longer act",
+        ];
+        assert_eq!(expected, actions);
+    }
+}
@@ -0,0 +1,40 @@
+use crate::pauth_error::ApplicationError;
+use crate::store;
+
+/// Look up a config value. If the user has an override for the key, that value is returned,
+/// otherwise the default value (if any) is returned.
+pub fn get_config(key: &str, uid: Option<i32>) -> Result<Option<String>, ApplicationError> {
+    store::current().get_config(key, uid)
+}
+
+/// Look up a config value which should be 'true' or 'false'. Missing or unparseable values
+/// give the supplied default.
+pub fn get_config_bool(
+    key: &str,
+    uid: Option<i32>,
+    default: bool,
+) -> Result<bool, ApplicationError> {
+    Ok(get_config(key, uid)?
+        .and_then(|v| v.trim().parse::<bool>().ok())
+        .unwrap_or(default))
+}
+
+/// Look up a numeric config value. Missing or unparseable values give the supplied default.
+pub fn get_config_i64(key: &str, uid: Option<i32>, default: i64) -> Result<i64, ApplicationError> {
+    Ok(get_config(key, uid)?
+        .and_then(|v| v.trim().parse::<i64>().ok())
+        .unwrap_or(default))
+}
+
+/// Set a user specific override for a config value. Any existing override for the same key
+/// is replaced. This needs no admin and isn't audited, so is for trusted code such as a command
+/// line tool - never with an id from a request. Admins use admin::set_config_override.
+pub fn set_user_config(uid: i32, key: &str, value: &str) -> Result<(), ApplicationError> {
+    store::current().set_user_config(uid, key, value)
+}
+
+/// Remove a user specific override, so that the default applies again. Like set_user_config,
+/// for trusted code only; admins use admin::remove_config_override.
+pub fn remove_user_config(uid: i32, key: &str) -> Result<(), ApplicationError> {
+    store::current().remove_user_config(uid, key)
+}
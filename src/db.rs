@@ -1,11 +1,18 @@
 //based on https://github.com/olajohn-ajiboye/Rust-Rest-API
+use crate::pauth_error::ApplicationError;
+use diesel::dsl::sql;
 use diesel::pg::PgConnection;
+use diesel::prelude::*;
 use diesel::r2d2::ConnectionManager;
+use diesel::sql_types::{Bool, Text};
+use diesel_migrations::RunMigrationsError;
 use lazy_static::lazy_static;
 use r2d2;
+#[cfg(test)]
+use std::cell::RefCell;
 use std::env;
 
-type Pool = r2d2::Pool<ConnectionManager<PgConnection>>;
+pub(crate) type Pool = r2d2::Pool<ConnectionManager<PgConnection>>;
 pub type DbConnection = r2d2::PooledConnection<ConnectionManager<PgConnection>>;
 
 embed_migrations!();
@@ -14,16 +21,116 @@ lazy_static! {
     static ref POOL: Pool = {
         let db_url = env::var("DATABASE_URL").expect("Database url not set");
         let manager = ConnectionManager::<PgConnection>::new(db_url);
-        Pool::new(manager).expect("Failed to create db pool")
+        Pool::builder().build_unchecked(manager)
     };
 }
 
-pub fn init() {
-    lazy_static::initialize(&POOL);
-    let conn = connection().expect("Failed to get db connection");
-    embedded_migrations::run(&conn).unwrap();
+#[cfg(test)]
+thread_local! {
+    static THREAD_POOL: RefCell<Option<Pool>> = const { RefCell::new(None) };
+}
+
+pub fn init() -> Result<(), ApplicationError> {
+    if env::var("DATABASE_URL").is_err() {
+        return Err(ApplicationError::ApplicationDataLogic(
+            "DATABASE_URL is not set".to_owned(),
+        ));
+    }
+    let conn = connection()?;
+    check_case_duplicates(&conn)?;
+    run_migrations(&conn).map_err(|e| {
+        ApplicationError::ApplicationDataLogic(format!("Failed to run the pauth migrations: {}", e))
+    })
+}
+
+#[derive(QueryableByName)]
+struct CaseDuplicates {
+    #[sql_type = "Text"]
+    kind: String,
+    #[sql_type = "Text"]
+    identifiers: String,
+}
+
+/// The unique_users migration makes usernames and emails unique ignoring case, which fails if
+/// existing users differ only in case. Before it is run, fail naming any such users instead.
+fn check_case_duplicates(conn: &PgConnection) -> Result<(), ApplicationError> {
+    let pending = diesel::select(sql::<Bool>(
+        "to_regclass('pauth.users') is not null \
+            and to_regclass('pauth.users_chosen_name_lower_key') is null",
+    ))
+    .get_result::<bool>(conn)?;
+    if !pending {
+        return Ok(());
+    }
+    let duplicates = diesel::sql_query(
+        "select 'usernames' as kind, string_agg(chosen_name, ', ' order by id) as identifiers
+            from pauth.users group by lower(chosen_name) having count(*) > 1
+        union all
+        select 'emails', string_agg(email, ', ' order by id)
+            from pauth.users group by lower(email) having count(*) > 1",
+    )
+    .load::<CaseDuplicates>(conn)?;
+    if duplicates.is_empty() {
+        return Ok(());
+    }
+    let names: Vec<String> = duplicates
+        .iter()
+        .map(|d| format!("{} {}", d.kind, d.identifiers))
+        .collect();
+    Err(ApplicationError::ApplicationDataLogic(format!(
+        "Usernames and emails must now be unique ignoring case, but these users only differ in \
+            case: {}. Rename the users or change their emails so that they differ, then run the \
+            migrations again",
+        names.join("; ")
+    )))
+}
+
+pub(crate) fn run_migrations(conn: &PgConnection) -> Result<(), RunMigrationsError> {
+    embedded_migrations::run(conn)
 }
 
 pub fn connection() -> Result<DbConnection, r2d2::Error> {
+    #[cfg(test)]
+    {
+        if let Some(pool) = THREAD_POOL.with(|p| p.borrow().clone()) {
+            return pool.get();
+        }
+    }
     POOL.get()
 }
+
+/// Use pool instead of DATABASE_URL on the current thread, see test_db.
+#[cfg(test)]
+pub(crate) fn set_thread_pool(pool: Option<Pool>) {
+    THREAD_POOL.with(|p| *p.borrow_mut() = pool);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::*;
+    use crate::test_db::TestDb;
+    use diesel::connection::SimpleConnection;
+
+    #[test]
+    fn case_duplicates_stop_the_migrations() {
+        let _db = TestDb::new();
+        let conn = connection().unwrap();
+        check_case_duplicates(&conn).unwrap();
+        //as if from before the unique_users migration
+        conn.batch_execute(
+            "drop index pauth.users_chosen_name_lower_key; drop index pauth.users_email_lower_key;
+            insert into pauth.users(chosen_name, email, pass_hash) values
+                ('Paul', 'paul@example.com', ''),
+                ('paul', 'other@example.com', ''),
+                ('Other', 'OTHER@example.com', '')",
+        )
+        .unwrap();
+        let error = format!("{:?}", check_case_duplicates(&conn).unwrap_err());
+        assert!(error.contains("usernames Paul, paul"), "{}", error);
+        assert!(
+            error.contains("emails other@example.com, OTHER@example.com"),
+            "{}",
+            error
+        );
+    }
+}
@@ -0,0 +1,308 @@
+//! Canonical forms of usernames and emails, so that identifiers which look the same are treated
+//! as the same. Lookups (login, password resets) and duplicate checks (add_user, change_details)
+//! compare these forms rather than what the user typed, which is kept for display.
+//!
+//! The canonical form (key) is set by the config:
+//! - 'identifier normalisation': 'nfkc' to apply Unicode NFKC normalisation, so that for example
+//!   full width letters match their usual forms, or 'none'
+//! - 'identifier case folding': 'true' to ignore case, using Unicode case folding so that for
+//!   example "STRASSE" matches "straße"
+//!
+//! If 'check confusable usernames' is true, a username is also taken if another looks like it,
+//! such as "paul" with a Cyrillic "а". This uses the Unicode confusable skeleton, and only
+//! applies when adding or renaming users - login still needs the user's actual name.
+//!
+//! Keys are stored when users are added or changed, along with the form they were made in. If the
+//! config changes, the stores make every user's keys again in the new form before using them, so
+//! existing users can still log in. Stored keys are unique, except where existing users already
+//! had the same key - those users are listed by identifier_collisions, and keep the key until
+//! they are renamed. Names which only differ in case are always rejected by the database,
+//! whatever the config.
+use crate::pauth_error::ApplicationError;
+use crate::store::Store;
+use caseless::default_case_fold_str;
+use unicode_normalization::UnicodeNormalization;
+use unicode_security::skeleton;
+
+/// The canonical forms of a user's identifiers.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct UserKeys {
+    pub name_key: String,
+    pub email_key: String,
+    pub name_skeleton: String,
+}
+
+/// Canonicalises identifiers as configured in a store.
+pub(crate) struct Canonicaliser {
+    nfkc: bool,
+    fold_case: bool,
+    pub check_confusables: bool,
+}
+
+impl Canonicaliser {
+    /// The configured canonicaliser, having first made sure the store's keys are in its form.
+    pub(crate) fn new(store: &dyn Store) -> Result<Canonicaliser, ApplicationError> {
+        let canonicaliser = Canonicaliser::configured(store)?;
+        store.rekey_users(&canonicaliser.form())?;
+        Ok(canonicaliser)
+    }
+
+    /// The configured canonicaliser, for stores making keys.
+    pub(crate) fn configured(store: &dyn Store) -> Result<Canonicaliser, ApplicationError> {
+        let setting = |key: &str, default: &str| -> Result<String, ApplicationError> {
+            Ok(store
+                .get_config(key, None)?
+                .unwrap_or_else(|| default.to_owned()))
+        };
+        Ok(Canonicaliser {
+            nfkc: setting("identifier normalisation", "nfkc")? == "nfkc",
+            fold_case: setting("identifier case folding", "true")? == "true",
+            check_confusables: setting("check confusable usernames", "true")? == "true",
+        })
+    }
+
+    /// Names the form of the keys, so that stores can tell when theirs were made differently.
+    /// Must change whenever key does.
+    pub(crate) fn form(&self) -> String {
+        let normalisation = if self.nfkc { "nfkc" } else { "none" };
+        if self.fold_case {
+            format!("{} casefold", normalisation)
+        } else {
+            normalisation.to_owned()
+        }
+    }
+
+    pub(crate) fn key(&self, identifier: &str) -> String {
+        match (self.nfkc, self.fold_case) {
+            //normalised again after folding, as folding can undo it
+            (true, true) => default_case_fold_str(&identifier.nfkc().collect::<String>())
+                .nfkc()
+                .collect(),
+            (true, false) => identifier.nfkc().collect(),
+            (false, true) => default_case_fold_str(identifier),
+            (false, false) => identifier.to_owned(),
+        }
+    }
+
+    /// Names with the same skeleton look alike. Always computed, so that turning on
+    /// 'check confusable usernames' applies to existing users.
+    pub(crate) fn skeleton(&self, name: &str) -> String {
+        let normalised: String = name.nfkc().collect();
+        skeleton(&normalised).collect::<String>().to_lowercase()
+    }
+
+    pub(crate) fn user_keys(&self, name: &str, email: &str) -> UserKeys {
+        UserKeys {
+            name_key: self.key(name),
+            email_key: self.key(email),
+            name_skeleton: self.skeleton(name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db;
+    use crate::identifier::*;
+    use crate::models::*;
+    use crate::store::{PgStore, Store};
+    use crate::test_db::TestDb;
+    use diesel::prelude::*;
+    use diesel::sql_query;
+    use diesel::sql_types::{Int4, Text};
+
+    fn canonicaliser(nfkc: bool, fold_case: bool) -> Canonicaliser {
+        Canonicaliser {
+            nfkc,
+            fold_case,
+            check_confusables: true,
+        }
+    }
+
+    #[test]
+    fn keys() {
+        let c = canonicaliser(true, true);
+        assert_eq!("paul@example.com", c.key("Paul@Example.COM"));
+        //full width letters and the "fi" ligature
+        assert_eq!("paul", c.key("Ｐａｕｌ"));
+        assert_eq!("finn", c.key("ﬁnn"));
+        //composed and decomposed accents
+        assert_eq!(c.key("jos\u{e9}"), c.key("jose\u{301}"));
+        //folding, not just lower case
+        assert_eq!(c.key("STRASSE"), c.key("stra\u{df}e"));
+        assert_eq!(
+            canonicaliser(false, true).key("STRASSE"),
+            canonicaliser(false, true).key("stra\u{df}e")
+        );
+        assert_ne!(c.form(), canonicaliser(true, false).form());
+        assert_ne!(c.form(), canonicaliser(false, true).form());
+
+        assert_eq!("Ｐａｕｌ", canonicaliser(false, false).key("Ｐａｕｌ"));
+        assert_eq!("Paul", canonicaliser(true, false).key("Ｐａｕｌ"));
+    }
+
+    #[test]
+    fn confusables() {
+        let c = canonicaliser(true, true);
+        //Cyrillic a, and a capital I for an l
+        assert_eq!(c.skeleton("paul"), c.skeleton("p\u{430}ul"));
+        assert_eq!(c.skeleton("paul"), c.skeleton("pauI"));
+        assert_ne!(c.skeleton("paul"), c.skeleton("pauls"));
+        assert_ne!(c.key("paul"), c.key("p\u{430}ul"));
+    }
+
+    #[derive(QueryableByName, Debug, PartialEq)]
+    struct Collision {
+        #[sql_type = "Int4"]
+        user_id: i32,
+        #[sql_type = "Int4"]
+        other_user_id: i32,
+        #[sql_type = "Text"]
+        kind: String,
+    }
+
+    #[test]
+    fn canonical_lookups() {
+        let _db = TestDb::new();
+        let paul = match add_user("Paul", "Paul@Example.com", "test").unwrap() {
+            AddUserResult::Added(auth_id) => auth_id,
+            r => panic!("Test failure: user not added, got {:?}", r),
+        };
+        for name in &["paul", "Ｐａｕｌ", "paul@example.com", "PAUL@EXAMPLE.COM"] {
+            assert!(
+                matches!(login(name, "test").unwrap(), LoginResult::LoggedIn(_)),
+                "Test failure: could not log in as {}",
+                name
+            );
+        }
+        //looks like Paul, but isn't
+        assert_eq!(
+            LoginResult::AuthenticationFailure,
+            login("p\u{430}ul", "test").unwrap()
+        );
+        assert_eq!(
+            AddUserResult::NotAdded(vec![
+                UserActionFailure::UsernameExists,
+                UserActionFailure::EmailExists
+            ]),
+            add_user("p\u{430}ul", "ｐａｕｌ@example.com", "test").unwrap()
+        );
+        assert!(generate_pw_reset("PAUL@example.com", None)
+            .unwrap()
+            .is_some());
+
+        let other = match add_user("Other", "other@example.com", "test").unwrap() {
+            AddUserResult::Added(auth_id) => auth_id,
+            r => panic!("Test failure: user not added, got {:?}", r),
+        };
+        assert_eq!(
+            ChangeDetailsResult::NotChanged(vec![UserActionFailure::UsernameExists]),
+            change_details(&other, UserUpdate::new().with_chosen_name("pauI")).unwrap()
+        );
+        //renaming updates the keys
+        assert_eq!(
+            ChangeDetailsResult::Changed,
+            change_details(&paul, UserUpdate::new().with_chosen_name("Saul")).unwrap()
+        );
+        assert_eq!(
+            LoginResult::AuthenticationFailure,
+            login("paul", "test").unwrap()
+        );
+        assert!(matches!(
+            login("SAUL", "test").unwrap(),
+            LoginResult::LoggedIn(_)
+        ));
+        assert_eq!(
+            ChangeDetailsResult::Changed,
+            change_details(&other, UserUpdate::new().with_chosen_name("paul")).unwrap()
+        );
+    }
+
+    #[test]
+    fn existing_users_and_collisions() {
+        let _db = TestDb::new();
+        //users from before there were keys, whose names only differ in unicode form
+        let conn = db::connection().unwrap();
+        conn.execute(
+            "insert into pauth.users(chosen_name, email, pass_hash) values
+                ('Ｐａｕｌ', 'one@example.com', pauth.crypt('test', pauth.gen_salt('bf'))),
+                ('paul', 'two@example.com', pauth.crypt('test', pauth.gen_salt('bf')))",
+        )
+        .unwrap();
+        assert_eq!(2, PgStore.fill_keys().unwrap());
+        assert_eq!(0, PgStore.fill_keys().unwrap());
+        assert!(matches!(
+            login("two@example.com", "test").unwrap(),
+            LoginResult::LoggedIn(_)
+        ));
+        let collisions =
+            sql_query("select user_id, other_user_id, kind from pauth.identifier_collision")
+                .load::<Collision>(&conn)
+                .unwrap();
+        assert_eq!(1, collisions.len());
+        assert_eq!("username", collisions[0].kind);
+        assert_ne!(collisions[0].user_id, collisions[0].other_user_id);
+    }
+
+    #[test]
+    fn keys_follow_the_config() {
+        let _db = TestDb::new();
+        assert!(matches!(
+            add_user("Strasse", "strasse@example.com", "test").unwrap(),
+            AddUserResult::Added(_)
+        ));
+        assert!(matches!(
+            login("STRA\u{df}E", "test").unwrap(),
+            LoginResult::LoggedIn(_)
+        ));
+        let conn = db::connection().unwrap();
+        conn.execute(
+            "update pauth.config set config_value = 'false' where config_key = 'identifier case folding'",
+        )
+        .unwrap();
+        //the existing user is keyed again, so can still log in as they are named
+        assert!(matches!(
+            login("Strasse", "test").unwrap(),
+            LoginResult::LoggedIn(_)
+        ));
+        assert_eq!(
+            LoginResult::AuthenticationFailure,
+            login("STRASSE", "test").unwrap()
+        );
+        assert!(matches!(
+            add_user("stra\u{df}e", "other@example.com", "other").unwrap(),
+            AddUserResult::Added(_)
+        ));
+        conn.execute(
+            "update pauth.config set config_value = 'true' where config_key = 'identifier case folding'",
+        )
+        .unwrap();
+        //now they collide, and the later user keeps the collision
+        let collisions = PgStore.identifier_collisions().unwrap();
+        assert_eq!(1, collisions.len());
+        assert!(matches!(
+            login("Strasse", "test").unwrap(),
+            LoginResult::LoggedIn(_)
+        ));
+    }
+
+    #[test]
+    fn keys_are_unique() {
+        let _db = TestDb::new();
+        assert!(matches!(
+            add_user("Strasse", "strasse@example.com", "test").unwrap(),
+            AddUserResult::Added(_)
+        ));
+        //as if the duplicate checks had raced with adding the first user
+        let store = PgStore;
+        let hash = store.hash("test").unwrap();
+        assert!(store
+            .insert_user("stra\u{df}e", "new@example.com", &hash)
+            .unwrap_err()
+            .is_unique_violation());
+        assert!(store
+            .insert_user("new", "STRASSE@example.com", &hash)
+            .unwrap_err()
+            .is_unique_violation());
+    }
+}
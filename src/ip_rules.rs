@@ -0,0 +1,323 @@
+//! IP address allow and deny rules, which restrict where users can log in from.
+//!
+//! Rules apply globally, to every user with an email address in a domain, or to one user.
+//! The most specific scope with a relevant rule decides: user rules first, then email domain,
+//! then global. Within a scope, the matching rule with the longest prefix wins (and deny wins
+//! a tie), so a narrow deny can be carved out of a wide allow, or the other way round.
+//! If a scope has allow rules but none of its rules match, the address is denied, so allow rules
+//! act as an allow list. With no relevant rules, the address is allowed.
+//!
+//! When the ip address is not known, it is only allowed if none of the scopes have allow rules.
+use crate::models::User;
+use crate::pauth_error::ApplicationError;
+use crate::store::{self, Store};
+use ipnetwork::IpNetwork;
+use std::net::IpAddr;
+
+/// Who a rule applies to.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RuleScope {
+    Global,
+    EmailDomain(String),
+    User(i32),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct IpRule {
+    pub id: i32,
+    pub scope: RuleScope,
+    pub network: IpNetwork,
+    pub allow: bool,
+    pub note: Option<String>,
+}
+
+impl RuleScope {
+    /// The scope of a stored rule, from its user id and email domain.
+    pub(crate) fn from_columns(user_id: Option<i32>, email_domain: Option<String>) -> RuleScope {
+        match (user_id, email_domain) {
+            (Some(uid), _) => RuleScope::User(uid),
+            (None, Some(domain)) => RuleScope::EmailDomain(domain),
+            (None, None) => RuleScope::Global,
+        }
+    }
+
+    /// The user id and email domain columns of a stored rule.
+    pub(crate) fn columns(&self) -> (Option<i32>, Option<&str>) {
+        match self {
+            RuleScope::Global => (None, None),
+            RuleScope::EmailDomain(domain) => (None, Some(domain)),
+            RuleScope::User(uid) => (Some(*uid), None),
+        }
+    }
+}
+
+fn email_domain(email: &str) -> Option<String> {
+    email
+        .rsplit_once('@')
+        .map(|(_, domain)| domain.to_lowercase())
+}
+
+/// The decision of a single scope, if it has one.
+fn scope_decision(rules: &[&IpRule], ip: Option<IpAddr>) -> Option<bool> {
+    if rules.is_empty() {
+        return None;
+    }
+    let has_allow_rules = rules.iter().any(|r| r.allow);
+    let ip = match ip {
+        Some(ip) => ip,
+        None => return if has_allow_rules { Some(false) } else { None },
+    };
+    let best = rules
+        .iter()
+        .filter(|r| r.network.contains(ip))
+        //longest prefix, then deny before allow
+        .max_by_key(|r| (r.network.prefix(), !r.allow));
+    match best {
+        Some(rule) => Some(rule.allow),
+        None if has_allow_rules => Some(false),
+        None => None,
+    }
+}
+
+/// Whether the rules allow a user to log in from the ip address. The rules should be those
+/// which apply to the user, as loaded by rules_for_user.
+pub fn is_allowed(rules: &[IpRule], ip: Option<IpAddr>) -> bool {
+    let in_scope = |f: fn(&RuleScope) -> bool| -> Vec<&IpRule> {
+        rules.iter().filter(|r| f(&r.scope)).collect()
+    };
+    let scopes = [
+        in_scope(|s| matches!(s, RuleScope::User(_))),
+        in_scope(|s| matches!(s, RuleScope::EmailDomain(_))),
+        in_scope(|s| matches!(s, RuleScope::Global)),
+    ];
+    scopes
+        .iter()
+        .find_map(|rules| scope_decision(rules, ip))
+        .unwrap_or(true)
+}
+
+/// The rules which apply to the user - their own, their email domain's, and global rules.
+pub(crate) fn rules_for_user(
+    store: &dyn Store,
+    user: &User,
+) -> Result<Vec<IpRule>, ApplicationError> {
+    let mut rules = store.ip_rules(&RuleScope::User(user.id))?;
+    if let Some(domain) = email_domain(&user.email) {
+        rules.extend(store.ip_rules(&RuleScope::EmailDomain(domain))?);
+    }
+    rules.extend(store.ip_rules(&RuleScope::Global)?);
+    Ok(rules)
+}
+
+pub(crate) fn user_allowed(
+    store: &dyn Store,
+    user: &User,
+    ip: Option<IpAddr>,
+) -> Result<bool, ApplicationError> {
+    Ok(is_allowed(&rules_for_user(store, user)?, ip))
+}
+
+/// Email domains are compared ignoring case.
+fn stored_scope(scope: &RuleScope) -> RuleScope {
+    match scope {
+        RuleScope::EmailDomain(domain) => RuleScope::EmailDomain(domain.to_lowercase()),
+        scope => scope.clone(),
+    }
+}
+
+/// Add a rule. Any host bits in the network are ignored, so 10.1.2.3/8 is stored as 10.0.0.0/8.
+/// Returns the id of the new rule.
+pub fn add_ip_rule(
+    scope: &RuleScope,
+    network: IpNetwork,
+    allow: bool,
+    note: Option<&str>,
+) -> Result<i32, ApplicationError> {
+    let store = store::current();
+    store.api_call("add_ip_rule", &[&network.to_string()])?;
+    let network = IpNetwork::new(network.network(), network.prefix())
+        .map_err(|e| ApplicationError::ApplicationDataLogic(e.to_string()))?;
+    store.insert_ip_rule(&stored_scope(scope), network, allow, note)
+}
+
+/// Remove a rule. Returns false if there was no rule with the id.
+pub fn remove_ip_rule(rule_id: i32) -> Result<bool, ApplicationError> {
+    let store = store::current();
+    store.api_call("remove_ip_rule", &[&rule_id.to_string()])?;
+    store.delete_ip_rule(rule_id)
+}
+
+/// List the rules in a scope.
+pub fn list_ip_rules(scope: &RuleScope) -> Result<Vec<IpRule>, ApplicationError> {
+    let store = store::current();
+    store.api_call("list_ip_rules", &[])?;
+    store.ip_rules(&stored_scope(scope))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ip_rules::*;
+    use crate::models::*;
+    use crate::source::Source;
+    use crate::test_db::TestDb;
+
+    fn rule(scope: RuleScope, network: &str, allow: bool) -> IpRule {
+        IpRule {
+            id: 0,
+            scope,
+            network: network.parse().unwrap(),
+            allow,
+            note: None,
+        }
+    }
+
+    fn ip(s: &str) -> Option<IpAddr> {
+        Some(s.parse().unwrap())
+    }
+
+    #[test]
+    fn no_rules_allows() {
+        assert!(is_allowed(&[], ip("10.0.0.1")));
+        assert!(is_allowed(&[], None));
+    }
+
+    #[test]
+    fn longest_prefix_wins() {
+        let rules = vec![
+            rule(RuleScope::Global, "10.0.0.0/8", false),
+            rule(RuleScope::Global, "10.1.0.0/16", true),
+            rule(RuleScope::Global, "10.1.2.0/24", false),
+        ];
+        assert!(!is_allowed(&rules, ip("10.9.9.9")));
+        assert!(is_allowed(&rules, ip("10.1.9.9")));
+        assert!(!is_allowed(&rules, ip("10.1.2.3")));
+        //there are allow rules, so anything which doesn't match is denied
+        assert!(!is_allowed(&rules, ip("192.168.0.1")));
+        assert!(!is_allowed(&rules, None));
+
+        let v6 = vec![
+            rule(RuleScope::Global, "2001:db8::/32", true),
+            rule(RuleScope::Global, "2001:db8:bad::/48", false),
+        ];
+        assert!(is_allowed(&v6, ip("2001:db8:1::1")));
+        assert!(!is_allowed(&v6, ip("2001:db8:bad::1")));
+        assert!(!is_allowed(&v6, ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn deny_wins_a_tie() {
+        let rules = vec![
+            rule(RuleScope::Global, "192.0.2.0/24", true),
+            rule(RuleScope::Global, "192.0.2.0/24", false),
+        ];
+        assert!(!is_allowed(&rules, ip("192.0.2.1")));
+    }
+
+    #[test]
+    fn most_specific_scope_wins() {
+        let mut rules = vec![
+            rule(RuleScope::Global, "203.0.113.0/24", false),
+            rule(
+                RuleScope::EmailDomain("pr0.co.uk".to_owned()),
+                "203.0.113.0/24",
+                true,
+            ),
+        ];
+        //the domain's allow rule overrides the global deny, and is an allow list
+        assert!(is_allowed(&rules, ip("203.0.113.7")));
+        assert!(!is_allowed(&rules, ip("198.51.100.1")));
+
+        //the user's own allow list takes over from the domain's
+        rules.push(rule(RuleScope::User(1), "198.51.100.0/24", true));
+        assert!(is_allowed(&rules, ip("198.51.100.1")));
+        assert!(!is_allowed(&rules, ip("203.0.113.7")));
+
+        let deny_only = vec![
+            rule(RuleScope::Global, "0.0.0.0/0", true),
+            rule(RuleScope::User(1), "192.0.2.0/24", false),
+        ];
+        assert!(!is_allowed(&deny_only, ip("192.0.2.1")));
+        //no user rule matches and the user has no allow rules, so global decides
+        assert!(is_allowed(&deny_only, ip("198.51.100.1")));
+        assert!(!is_allowed(&deny_only, ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn login_and_check_id_use_rules() {
+        let _db = TestDb::new();
+        let auth_id = match add_user("iprules31", "iprules31@pr0.co.uk", "pass31").unwrap() {
+            AddUserResult::Added(auth_id) => auth_id,
+            AddUserResult::NotAdded(_) => panic!("Test failure: user not added"),
+        };
+        let office = Source::from_ip("192.0.2.31".parse().unwrap());
+        let cafe = Source::from_ip("198.51.100.31".parse().unwrap());
+        let rule_id = add_ip_rule(
+            &RuleScope::User(auth_id.user_id),
+            "192.0.2.0/24".parse().unwrap(),
+            true,
+            Some("office"),
+        )
+        .unwrap();
+        assert_eq!(
+            1,
+            list_ip_rules(&RuleScope::User(auth_id.user_id))
+                .unwrap()
+                .len()
+        );
+
+        assert_eq!(
+            LoginResult::SourceNotAllowed,
+            login_from("iprules31", "pass31", &cafe, None)
+                .unwrap()
+                .result
+        );
+        //a wrong password does not reveal the rules
+        assert_eq!(
+            LoginResult::AuthenticationFailure,
+            login_from("iprules31", "wrong", &cafe, None)
+                .unwrap()
+                .result
+        );
+        let office_id = match login_from("iprules31", "pass31", &office, None)
+            .unwrap()
+            .result
+        {
+            LoginResult::LoggedIn(id) => id,
+            r => panic!("Test failure: expected login from the office, got {:?}", r),
+        };
+        assert_eq!(
+            IdCheckResult::Valid,
+            check_id_from(&office_id, &office).unwrap()
+        );
+        assert_eq!(
+            IdCheckResult::SourceNotAllowed,
+            check_id_from(&office_id, &cafe).unwrap()
+        );
+        //an unknown address isn't on the allow list either
+        assert!(!check_id(&office_id).unwrap());
+
+        let token = generate_pw_reset("iprules31", None).unwrap().unwrap();
+        assert_eq!(
+            LoginResult::SourceNotAllowed,
+            validate_pw_reset_from("iprules31", token, &cafe).unwrap()
+        );
+        let token = generate_pw_reset("iprules31", None).unwrap().unwrap();
+        assert_eq!(
+            LoginResult::SourceNotAllowed,
+            validate_pw_reset("iprules31", token).unwrap()
+        );
+        let token = generate_pw_reset("iprules31", None).unwrap().unwrap();
+        assert!(matches!(
+            validate_pw_reset_from("iprules31", token, &office).unwrap(),
+            LoginResult::LoggedIn(_)
+        ));
+
+        assert!(remove_ip_rule(rule_id).unwrap());
+        assert_eq!(
+            IdCheckResult::Valid,
+            check_id_from(&office_id, &cafe).unwrap()
+        );
+        assert!(check_id(&office_id).unwrap());
+        delete_user(&auth_id).unwrap();
+    }
+}
@@ -3,13 +3,17 @@
 //! do password resets.
 //!
 //! Users must have at least either an unique username or an unique email address to identify them.
-//! It is fine to have both, and to use either as the means of identifying the user. Users must additionally
-//! have a password. No stipulation of password strength is made by pauth (if you want to ensure
-//! passwords meet a particular standard, you should verify this before allowing them to be set in pauth)
+//! It is fine to have both, and to use either as the means of identifying the user. Usernames and
+//! emails are compared ignoring case and Unicode form, and a username which looks like another
+//! (such as one using a Cyrillic "а") is taken. Users must additionally
+//! have a password. By default any password is accepted, but a PasswordPolicy (minimum strength,
+//! no breached passwords, and so on) can be set in the config - see the password module.
 //!
 //! Authenticate users with pauth using either their username or email, and their password. When
 //! a user is successfully authenticated, an AuthenticatedID is returned. The AuthenticatedID
-//! can be used to authenticate the user (like a cookie) without having to re-provide credentials
+//! can be used to authenticate the user (like a cookie) without having to re-provide credentials,
+//! except for sensitive actions (deleting the user, or changing their email or password), which
+//! need the password to have been given recently - see require_recent_auth and reauthenticate.
 //!
 //! Password resets can be requested by supplying either a username or email as an identifier.
 //! If the identifier exists, a token is generated which can then be used to reset the password.
@@ -24,14 +28,18 @@
 //! When you first call run_db_migrations(), a schema "pauth" will be created which contains
 //! all of the tables needed for pauth.
 //!
-//! All passwords and tokens are stored in encrypted form using [pgcrypto](https://www.postgresql.org/docs/current/pgcrypto.html).
+//! Passwords and tokens are never stored, only salted bcrypt hashes of them - computed with
+//! [pgcrypto](https://www.postgresql.org/docs/current/pgcrypto.html) in Postgres, or in pauth
+//! with the other stores.
+//!
+//! Postgres is not the only option - see the store module for SQLite and in memory storage, and
+//! the features which need Postgres.
 //!
 //! ### Still to do
 //! pauth is still at an early stage but is under [active development](https://github.com/paulpr0/pauth)
 //! + expiry for cookies. We store created date and last used date.
 //! + Archiving and deleting. Some systems need a record of who logged in and when. We also need to archive
 //! or delete old authentication ids.
-//! + count and act on multiple authentication failures.
 //! + All config stored as a table in pauth. This should be per user, possibly email domain,
 //! and global - whichever is more specific applies. This allows for different settings perhaps for
 //! VIP users or sensitive accounts.
@@ -41,29 +49,41 @@ extern crate diesel;
 #[macro_use]
 extern crate diesel_migrations;
 
+pub mod admin;
+mod config;
 mod db;
+mod identifier;
+pub mod ip_rules;
 mod models;
+pub mod notify;
+pub mod password;
 mod pauth_error;
+pub mod privacy;
+pub mod risk;
+pub mod roles;
 mod schema;
+mod source;
+pub mod store;
+#[cfg(test)]
+mod test_db;
+#[cfg(feature = "testing")]
+pub mod testing;
+mod throttle;
+pub mod validation;
 
+pub use config::{get_config, remove_user_config, set_user_config};
 pub use models::{
-    login,
-    check_id,
-    check_id_and_password,
-    add_user,
-    get_user,
-    change_details,
-    delete_user,
-    validate_pw_reset,
-    AuthenticatedID,
-    LoginResult,
-    UserActionFailure,
-    AddUserResult,
-    DeleteUserResult,
-    ChangeDetailsResult,
-    UserActionFailureReason,
-    UserUpdate
+    add_user, change_details, check_id, check_id_and_password, check_id_from, check_identifiers,
+    confirm_source, delete_user, delete_user_with_password, generate_pw_reset, get_user, login,
+    login_by_email, login_by_username, login_from, purge_deleted_users, reauthenticate,
+    require_password_change, require_recent_auth, restore_account, restore_account_from,
+    unlock_account, validate_pw_reset, validate_pw_reset_from, AddUserResult, AuthenticatedID,
+    ChangeDetailsResult, CollisionKind, DeleteUserResult, IdCheckResult, IdentifierCollision,
+    LoginOutcome, LoginResult, RecentAuthResult, User, UserActionFailure, UserActionFailureReason,
+    UserUpdate,
 };
+pub use pauth_error::ApplicationError;
+pub use source::Source;
 
 /// Run any database migrations (schema updates). From 1.0 onwards, any schema changes
 /// will constitute at least a minor version upgrade, and any breaking changes trigger
@@ -77,6 +97,16 @@ pub use models::{
 /// If running on a production database, I suggest you check the schema updates before running
 /// as whilst they should always be reasonable, I don't know your setup and our definitions
 /// of 'reasonable' could differ.
-pub fn run_db_migrations() {
-    db::init()
+///
+/// Usernames and emails are unique ignoring case from the 2026-10-19-150000_unique_users
+/// migration. If existing users' names or emails only differ in case, the migrations stop before
+/// it with an error listing them, and nothing more is changed. Rename those users or change their
+/// emails (in pauth.users) so that they differ, and run the migrations again.
+///
+/// Returns the users who still can't be told apart by an identifier once the migrations have
+/// run, as check_identifiers does. Rename those users.
+pub fn run_db_migrations() -> Result<Vec<IdentifierCollision>, ApplicationError> {
+    db::init()?;
+    store::PgStore.fill_keys()?;
+    store::Store::identifier_collisions(&store::PgStore)
 }
@@ -0,0 +1,719 @@
+//! Notifications for security related events, such as a password being changed.
+//!
+//! Pauth does not send anything unless a [Notifier] has been registered with [set_notifier].
+//! Users can opt out of individual events using the config keys returned by
+//! [SecurityEvent::config_key], which default to 'true'.
+use crate::config;
+use crate::models::User;
+use crate::pauth_error::ApplicationError;
+use crate::source::Source;
+use chrono::{NaiveDateTime, Utc};
+use lazy_static::lazy_static;
+#[cfg(test)]
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+/// The security events which pauth can notify a user about.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SecurityEvent {
+    NewSourceLogin,
+    RepeatedLoginFailures,
+    PasswordChanged,
+    EmailChanged,
+    PasswordResetRequested,
+    AccountDisabled,
+    AccountLocked,
+    AccountDeleted,
+}
+
+impl SecurityEvent {
+    /// The config key which controls whether a user is notified of this event.
+    pub fn config_key(&self) -> &'static str {
+        match self {
+            SecurityEvent::NewSourceLogin => "notify on new source login",
+            SecurityEvent::RepeatedLoginFailures => "notify on repeated login failures",
+            SecurityEvent::PasswordChanged => "notify on password change",
+            SecurityEvent::EmailChanged => "notify on email change",
+            SecurityEvent::PasswordResetRequested => "notify on password reset request",
+            SecurityEvent::AccountDisabled => "notify on account disabled",
+            SecurityEvent::AccountLocked => "notify on account locked",
+            SecurityEvent::AccountDeleted => "notify on account deletion",
+        }
+    }
+
+    /// The template used when no other template has been registered with [set_template]
+    pub fn default_template(&self) -> Template {
+        let (subject, body) = match self {
+            SecurityEvent::NewSourceLogin => (
+                "New sign in to your account",
+                "Hi {chosen_name},\n\nYour account was signed in to from {source} at {time}.\n\
+                 If this was not you, please reset your password.",
+            ),
+            SecurityEvent::RepeatedLoginFailures => (
+                "Failed sign in attempts on your account",
+                "Hi {chosen_name},\n\nThere have been repeated failed attempts to sign in to your \
+                 account, most recently from {source} at {time}.",
+            ),
+            SecurityEvent::PasswordChanged => (
+                "Your password has been changed",
+                "Hi {chosen_name},\n\nThe password for your account was changed at {time}.\n\
+                 If this was not you, please reset your password.",
+            ),
+            SecurityEvent::EmailChanged => (
+                "Your email address has been changed",
+                "Hi {chosen_name},\n\nThe email address for your account was changed from \
+                 {email} at {time}.\nIf this was not you, please contact support.",
+            ),
+            SecurityEvent::PasswordResetRequested => (
+                "Password reset requested",
+                "Hi {chosen_name},\n\nA password reset was requested for your account at {time}.\n\
+                 If this was not you, you can ignore this message.",
+            ),
+            SecurityEvent::AccountDisabled => (
+                "Your account has been disabled",
+                "Hi {chosen_name},\n\nYour account was disabled at {time}.",
+            ),
+            SecurityEvent::AccountLocked => (
+                "Your account has been locked",
+                "Hi {chosen_name},\n\nYour account was locked at {time} after too many failed \
+                 sign in attempts, most recently from {source}.\n\
+                 To unlock it, reset your password using this code: {token}",
+            ),
+            SecurityEvent::AccountDeleted => (
+                "Your account has been deleted",
+                "Hi {chosen_name},\n\nYour account was deleted at {time}.\n\
+                 If you change your mind, sign in to restore it, or reset your password using \
+                 this code: {token}",
+            ),
+        };
+        Template {
+            subject: subject.to_owned(),
+            body: body.to_owned(),
+        }
+    }
+}
+
+/// A subject and body for a notification. The placeholders {chosen_name}, {email}, {time},
+/// {source}, {ip}, {mac}, {identifier} and {token} are replaced when the template is rendered.
+/// {ip}, {mac}, {identifier} and {token} are replaced with an empty string if they are not
+/// known. {token} is only set for events which come with a token, such as AccountLocked.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Template {
+    pub subject: String,
+    pub body: String,
+}
+
+impl Template {
+    pub fn render(
+        &self,
+        user: &User,
+        source: Option<&Source>,
+        time: NaiveDateTime,
+    ) -> (String, String) {
+        self.render_with_token(user, source, None, time)
+    }
+
+    pub fn render_with_token(
+        &self,
+        user: &User,
+        source: Option<&Source>,
+        token: Option<&str>,
+        time: NaiveDateTime,
+    ) -> (String, String) {
+        let time = time.format("%Y-%m-%d %H:%M:%S UTC").to_string();
+        let source_text = source
+            .map(|s| s.to_string())
+            .unwrap_or_else(|| "unknown source".to_owned());
+        let ip = source
+            .and_then(|s| s.ip)
+            .map(|ip| ip.to_string())
+            .unwrap_or_default();
+        let mac = source.and_then(|s| s.mac_string()).unwrap_or_default();
+        let identifier = source
+            .and_then(|s| s.identifier.clone())
+            .unwrap_or_default();
+        let replace = |text: &str| {
+            text.replace("{chosen_name}", &user.chosen_name)
+                .replace("{email}", &user.email)
+                .replace("{time}", &time)
+                .replace("{source}", &source_text)
+                .replace("{ip}", &ip)
+                .replace("{mac}", &mac)
+                .replace("{token}", token.unwrap_or_default())
+                .replace("{identifier}", &identifier)
+        };
+        (replace(&self.subject), replace(&self.body))
+    }
+}
+
+/// A rendered notification, ready to send.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Notification {
+    pub event: SecurityEvent,
+    pub user_id: i32,
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+    pub time: NaiveDateTime,
+}
+
+/// Implement Notifier to deliver notifications. Pauth calls the registered notifier
+/// synchronously as part of the operation which triggered the event; a failure to send
+/// does not cause the operation itself to fail. A slow send holds up the operation (such as a
+/// login), so a notifier for a slow or unreliable transport should queue the notification and
+/// have the application deliver it in the background.
+pub trait Notifier: Send + Sync {
+    fn send(&self, notification: &Notification) -> Result<(), ApplicationError>;
+}
+
+lazy_static! {
+    static ref NOTIFIER: RwLock<Option<Box<dyn Notifier>>> = RwLock::new(None);
+    static ref TEMPLATES: RwLock<HashMap<SecurityEvent, Template>> = RwLock::new(HashMap::new());
+}
+
+#[cfg(test)]
+thread_local! {
+    static THREAD_NOTIFIER: RefCell<Option<Arc<dyn Notifier>>> = RefCell::new(None);
+}
+
+/// Register the notifier which pauth will use for all security events.
+pub fn set_notifier(notifier: Box<dyn Notifier>) {
+    *NOTIFIER.write().unwrap() = Some(notifier);
+}
+
+/// Stop sending notifications.
+pub fn clear_notifier() {
+    *NOTIFIER.write().unwrap() = None;
+}
+
+/// Use notifier instead of the registered one on the current thread, until the returned guard
+/// is dropped, so that tests running in parallel don't see each other's notifications.
+#[cfg(test)]
+pub(crate) fn set_thread_notifier<N: Notifier + 'static>(notifier: N) -> ThreadNotifier {
+    ThreadNotifier(THREAD_NOTIFIER.with(|n| n.replace(Some(Arc::new(notifier)))))
+}
+
+/// Puts back the current thread's previous notifier when dropped, see set_thread_notifier.
+#[cfg(test)]
+pub(crate) struct ThreadNotifier(Option<Arc<dyn Notifier>>);
+
+#[cfg(test)]
+impl Drop for ThreadNotifier {
+    fn drop(&mut self) {
+        let previous = self.0.take();
+        THREAD_NOTIFIER.with(|n| *n.borrow_mut() = previous);
+    }
+}
+
+/// Call f with the notifier, if one is registered.
+fn with_notifier<T>(f: impl FnOnce(&dyn Notifier) -> T) -> Option<T> {
+    #[cfg(test)]
+    {
+        if let Some(notifier) = THREAD_NOTIFIER.with(|n| n.borrow().clone()) {
+            return Some(f(&*notifier));
+        }
+    }
+    NOTIFIER.read().unwrap().as_deref().map(f)
+}
+
+/// Replace the default template for an event.
+pub fn set_template(event: SecurityEvent, template: Template) {
+    TEMPLATES.write().unwrap().insert(event, template);
+}
+
+/// Render and send a notification to the user, if a notifier is registered and the user has
+/// not opted out of the event. Returns whether a notification was sent.
+pub(crate) fn dispatch(
+    event: SecurityEvent,
+    user: &User,
+    source: Option<&Source>,
+) -> Result<bool, ApplicationError> {
+    dispatch_with_token(event, user, source, None)
+}
+
+/// As dispatch, for events which come with a token for the user, such as an unlock code.
+pub(crate) fn dispatch_with_token(
+    event: SecurityEvent,
+    user: &User,
+    source: Option<&Source>,
+    token: Option<&str>,
+) -> Result<bool, ApplicationError> {
+    if with_notifier(|_| ()).is_none() {
+        return Ok(false);
+    }
+    if !config::get_config_bool(event.config_key(), Some(user.id), true)? {
+        return Ok(false);
+    }
+    let template = TEMPLATES
+        .read()
+        .unwrap()
+        .get(&event)
+        .cloned()
+        .unwrap_or_else(|| event.default_template());
+    let time = Utc::now().naive_utc();
+    let (subject, body) = template.render_with_token(user, source, token, time);
+    let notification = Notification {
+        event,
+        user_id: user.id,
+        to: user.email.clone(),
+        subject,
+        body,
+        time,
+    };
+    with_notifier(|notifier| notifier.send(&notification))
+        .unwrap_or(Ok(()))
+        .map(|_| true)
+}
+
+/// The address, if it can be written in an SMTP command or a header as it is. Anything which
+/// could end the command or header early, such as a line break, is refused.
+fn address(address: &str) -> Result<&str, ApplicationError> {
+    if address.is_empty() || address.contains(|c: char| c.is_control() || c == '<' || c == '>') {
+        return Err(ApplicationError::Notification(format!(
+            "invalid address {:?}",
+            address
+        )));
+    }
+    Ok(address)
+}
+
+/// The text as a header value: line breaks become spaces, and text which isn't printable ASCII
+/// is written as RFC 2047 encoded words, each at most 75 characters and on its own line.
+fn header_value(text: &str) -> String {
+    let text = text.replace("\r\n", " ").replace(['\r', '\n'], " ");
+    if text.chars().all(|c| c == ' ' || c.is_ascii_graphic()) {
+        return text;
+    }
+    //"=?UTF-8?Q?" and "?=" take 12 of the 75
+    const MAX_ENCODED: usize = 75 - 12;
+    let mut words = vec![String::new()];
+    for c in text.chars() {
+        let mut encoded = String::new();
+        if c == ' ' {
+            encoded.push('_');
+        } else if c.is_ascii_alphanumeric() || "!*+-/".contains(c) {
+            encoded.push(c);
+        } else {
+            let mut bytes = [0; 4];
+            for byte in c.encode_utf8(&mut bytes).bytes() {
+                encoded.push_str(&format!("={:02X}", byte));
+            }
+        }
+        let word = words.last_mut().unwrap();
+        if word.len() + encoded.len() > MAX_ENCODED {
+            words.push(encoded);
+        } else {
+            word.push_str(&encoded);
+        }
+    }
+    words
+        .iter()
+        .map(|word| format!("=?UTF-8?Q?{}?=", word))
+        .collect::<Vec<_>>()
+        .join("\r\n ")
+}
+
+/// Sends notifications as plain text email via an SMTP server which accepts mail without
+/// authentication or TLS, such as a local relay. Fails without sending anything if the from or
+/// to address has a line break or angle brackets in it.
+///
+/// Connecting, and each read and write, give up after the timeout (5 seconds unless set with
+/// with_timeout). Sending still holds up the operation which triggered the event, so for a
+/// remote server, queue notifications instead (see Notifier).
+pub struct SmtpNotifier {
+    pub host: String,
+    pub port: u16,
+    pub from: String,
+    pub timeout: Duration,
+}
+
+impl SmtpNotifier {
+    pub fn new(host: &str, port: u16, from: &str) -> SmtpNotifier {
+        SmtpNotifier {
+            host: host.to_owned(),
+            port,
+            from: from.to_owned(),
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> SmtpNotifier {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Connect to the first of the host's addresses which accepts in time.
+    fn connect(&self) -> Result<TcpStream, ApplicationError> {
+        let mut last_error = smtp_error(format!("no address for {}", self.host));
+        for addr in (self.host.as_str(), self.port)
+            .to_socket_addrs()
+            .map_err(smtp_error)?
+        {
+            match TcpStream::connect_timeout(&addr, self.timeout) {
+                Ok(stream) => {
+                    stream
+                        .set_read_timeout(Some(self.timeout))
+                        .map_err(smtp_error)?;
+                    stream
+                        .set_write_timeout(Some(self.timeout))
+                        .map_err(smtp_error)?;
+                    return Ok(stream);
+                }
+                Err(e) => last_error = smtp_error(e),
+            }
+        }
+        Err(last_error)
+    }
+}
+
+fn smtp_error<E: std::fmt::Display>(e: E) -> ApplicationError {
+    ApplicationError::Notification(format!("SMTP error: {}", e))
+}
+
+fn smtp_command(
+    reader: &mut BufReader<TcpStream>,
+    command: Option<&str>,
+    expected: &str,
+) -> Result<(), ApplicationError> {
+    if let Some(command) = command {
+        let stream = reader.get_mut();
+        stream.write_all(command.as_bytes()).map_err(smtp_error)?;
+        stream.write_all(b"\r\n").map_err(smtp_error)?;
+    }
+    //replies can be multi line, in which case all but the last line have a '-' after the code
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).map_err(smtp_error)? == 0 {
+            return Err(smtp_error("connection closed"));
+        }
+        if !line.starts_with(expected) {
+            return Err(smtp_error(line.trim_end()));
+        }
+        if line.as_bytes().get(3) != Some(&b'-') {
+            return Ok(());
+        }
+    }
+}
+
+impl Notifier for SmtpNotifier {
+    fn send(&self, notification: &Notification) -> Result<(), ApplicationError> {
+        let (from, to) = (address(&self.from)?, address(&notification.to)?);
+        let stream = self.connect()?;
+        let mut reader = BufReader::new(stream);
+        smtp_command(&mut reader, None, "220")?;
+        smtp_command(&mut reader, Some("HELO pauth"), "250")?;
+        smtp_command(&mut reader, Some(&format!("MAIL FROM:<{}>", from)), "250")?;
+        smtp_command(&mut reader, Some(&format!("RCPT TO:<{}>", to)), "25")?;
+        smtp_command(&mut reader, Some("DATA"), "354")?;
+        let mut message = format!(
+            "From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nMIME-Version: 1.0\r\n\
+             Content-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: 8bit\r\n\r\n",
+            from,
+            to,
+            header_value(&notification.subject),
+            notification.time.format("%a, %d %b %Y %H:%M:%S +0000"),
+        );
+        //a carriage return on its own would be a line break to some servers
+        for line in notification.body.replace('\r', "").lines() {
+            //dot stuffing, so a line with a single '.' does not end the message
+            if line.starts_with('.') {
+                message.push('.');
+            }
+            message.push_str(line);
+            message.push_str("\r\n");
+        }
+        message.push('.');
+        smtp_command(&mut reader, Some(&message), "250")?;
+        smtp_command(&mut reader, Some("QUIT"), "221")
+    }
+}
+
+/// Appends notifications to a file in mbox format, which can be read by most mail clients.
+/// Addresses are checked, and subjects encoded, as SmtpNotifier does.
+pub struct MboxNotifier {
+    pub path: PathBuf,
+    pub from: String,
+}
+
+impl MboxNotifier {
+    pub fn new<P: Into<PathBuf>>(path: P, from: &str) -> MboxNotifier {
+        MboxNotifier {
+            path: path.into(),
+            from: from.to_owned(),
+        }
+    }
+}
+
+impl Notifier for MboxNotifier {
+    fn send(&self, notification: &Notification) -> Result<(), ApplicationError> {
+        let (from, to) = (address(&self.from)?, address(&notification.to)?);
+        let mut message = format!(
+            "From {} {}\nFrom: {}\nTo: {}\nSubject: {}\n\n",
+            from,
+            notification.time.format("%a %b %e %H:%M:%S %Y"),
+            from,
+            to,
+            header_value(&notification.subject).replace("\r\n", "\n"),
+        );
+        for line in notification.body.lines() {
+            if line.trim_start_matches('>').starts_with("From ") {
+                message.push('>');
+            }
+            message.push_str(line);
+            message.push('\n');
+        }
+        message.push('\n');
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(|e| ApplicationError::Notification(format!("mbox error: {}", e)))?;
+        file.write_all(message.as_bytes())
+            .map_err(|e| ApplicationError::Notification(format!("mbox error: {}", e)))
+    }
+}
+
+/// Records notifications in memory, for use in tests. Clones share the same record, so keep
+/// a clone to inspect after registering it with [set_notifier].
+#[derive(Clone, Default)]
+pub struct MemoryNotifier {
+    sent: Arc<Mutex<Vec<Notification>>>,
+}
+
+impl MemoryNotifier {
+    pub fn new() -> MemoryNotifier {
+        MemoryNotifier::default()
+    }
+    pub fn sent(&self) -> Vec<Notification> {
+        self.sent.lock().unwrap().clone()
+    }
+    pub fn sent_to(&self, to: &str) -> Vec<Notification> {
+        self.sent().into_iter().filter(|n| n.to == to).collect()
+    }
+    pub fn clear(&self) {
+        self.sent.lock().unwrap().clear()
+    }
+}
+
+impl Notifier for MemoryNotifier {
+    fn send(&self, notification: &Notification) -> Result<(), ApplicationError> {
+        self.sent.lock().unwrap().push(notification.clone());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::models::*;
+    use crate::notify::*;
+    use crate::test_db::TestDb;
+    use chrono::Utc;
+    use std::env;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use uuid::Uuid;
+
+    fn test_user() -> User {
+        User {
+            id: 1,
+            chosen_name: "Paul".to_owned(),
+            email: "paul@pr0.co.uk".to_owned(),
+            pass_hash: String::new(),
+            last_login: Utc::now().naive_utc(),
+            failed_logins: 0,
+            locked_at: None,
+            password_changed_at: Utc::now().naive_utc(),
+            must_change_password: false,
+            temporary_password_expires: None,
+            deleted_at: None,
+            is_admin: false,
+            disabled_at: None,
+            created: Utc::now().naive_utc(),
+        }
+    }
+
+    fn test_notification() -> Notification {
+        let (subject, body) = SecurityEvent::PasswordChanged.default_template().render(
+            &test_user(),
+            None,
+            Utc::now().naive_utc(),
+        );
+        Notification {
+            event: SecurityEvent::PasswordChanged,
+            user_id: 1,
+            to: "paul@pr0.co.uk".to_owned(),
+            subject,
+            body: body + "\n.\nFrom the start",
+            time: Utc::now().naive_utc(),
+        }
+    }
+
+    #[test]
+    fn render_template() {
+        let template = Template {
+            subject: "Hello {chosen_name}".to_owned(),
+            body: "{email} from {ip} ({identifier})".to_owned(),
+        };
+        let source = Source::from_ip("10.1.2.3".parse().unwrap()).with_identifier("phone");
+        let (subject, body) = template.render(&test_user(), Some(&source), Utc::now().naive_utc());
+        assert_eq!("Hello Paul", subject);
+        assert_eq!("paul@pr0.co.uk from 10.1.2.3 (phone)", body);
+    }
+
+    #[test]
+    fn smtp_sends_to_sink() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let sink = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            stream.write_all(b"220 sink\r\n").unwrap();
+            let mut received = String::new();
+            let mut in_data = false;
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    break;
+                }
+                received.push_str(&line);
+                if in_data {
+                    if line == ".\r\n" {
+                        in_data = false;
+                        stream.write_all(b"250 queued\r\n").unwrap();
+                    }
+                } else if line.starts_with("DATA") {
+                    in_data = true;
+                    stream.write_all(b"354 go ahead\r\n").unwrap();
+                } else if line.starts_with("QUIT") {
+                    stream.write_all(b"221 bye\r\n").unwrap();
+                    break;
+                } else {
+                    stream.write_all(b"250-ok\r\n250 ok\r\n").unwrap();
+                }
+            }
+            received
+        });
+        SmtpNotifier::new("127.0.0.1", port, "pauth@pr0.co.uk")
+            .send(&test_notification())
+            .unwrap();
+        let received = sink.join().unwrap();
+        assert!(received.contains("RCPT TO:<paul@pr0.co.uk>"));
+        assert!(received.contains("Subject: Your password has been changed"));
+        assert!(received.contains("\r\n..\r\nFrom the start\r\n.\r\n"));
+    }
+
+    #[test]
+    fn smtp_gives_up_on_a_silent_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        //accepts, but never greets
+        let sink = thread::spawn(move || listener.accept().unwrap());
+        let started = Instant::now();
+        assert!(matches!(
+            SmtpNotifier::new("127.0.0.1", port, "pauth@pr0.co.uk")
+                .with_timeout(Duration::from_millis(200))
+                .send(&test_notification()),
+            Err(ApplicationError::Notification(_))
+        ));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        drop(sink.join().unwrap());
+    }
+
+    #[test]
+    fn headers_cannot_be_injected() {
+        let mut notification = test_notification();
+        notification.to = "paul@pr0.co.uk>\r\nRCPT TO:<eve@pr0.co.uk".to_owned();
+        //refused before connecting, so nothing needs to be listening
+        assert!(matches!(
+            SmtpNotifier::new("127.0.0.1", 1, "pauth@pr0.co.uk").send(&notification),
+            Err(ApplicationError::Notification(_))
+        ));
+        let path = env::temp_dir().join(format!("pauth-{}.mbox", Uuid::new_v4()));
+        assert!(MboxNotifier::new(&path, "pauth@pr0.co.uk")
+            .send(&notification)
+            .is_err());
+        assert!(!path.exists());
+
+        assert_eq!(
+            "Changed Bcc: eve@pr0.co.uk",
+            header_value("Changed\r\nBcc: eve@pr0.co.uk")
+        );
+        assert_eq!("=?UTF-8?Q?Hi_Zo=C3=AB=3F?=", header_value("Hi Zoë?"));
+        let long = header_value(&"é".repeat(30));
+        assert!(long.lines().all(|line| line.trim_end().len() <= 76));
+        assert_eq!(3, long.lines().count());
+    }
+
+    #[test]
+    fn mbox_appends_messages() {
+        let path = env::temp_dir().join(format!("pauth-{}.mbox", Uuid::new_v4()));
+        let notifier = MboxNotifier::new(&path, "pauth@pr0.co.uk");
+        notifier.send(&test_notification()).unwrap();
+        notifier.send(&test_notification()).unwrap();
+        let mut contents = String::new();
+        std::fs::File::open(&path)
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            2,
+            contents
+                .matches("\nSubject: Your password has been changed\n")
+                .count()
+        );
+        assert!(contents.contains("\n>From the start\n"));
+    }
+
+    #[test]
+    fn password_and_email_changes_notify() {
+        let _db = TestDb::new();
+        let recorder = MemoryNotifier::new();
+        let _notifier = set_thread_notifier(recorder.clone());
+        let auth_id = match add_user("notify26", "notify26@pr0.co.uk", "pass26").unwrap() {
+            AddUserResult::Added(auth_id) => auth_id,
+            AddUserResult::NotAdded(_) => panic!("Test failure: user not added"),
+        };
+        change_details(&auth_id, &UserUpdate::with_password("pass26b").unwrap()).unwrap();
+        let sent = recorder.sent_to("notify26@pr0.co.uk");
+        assert_eq!(1, sent.len());
+        assert_eq!(SecurityEvent::PasswordChanged, sent[0].event);
+
+        //opt out of email change notifications
+        crate::config::set_user_config(
+            auth_id.user_id,
+            SecurityEvent::EmailChanged.config_key(),
+            "false",
+        )
+        .unwrap();
+        change_details(
+            &auth_id,
+            UserUpdate::new().with_email("notify26b@pr0.co.uk"),
+        )
+        .unwrap();
+        assert_eq!(1, recorder.sent_to("notify26@pr0.co.uk").len());
+        crate::config::remove_user_config(
+            auth_id.user_id,
+            SecurityEvent::EmailChanged.config_key(),
+        )
+        .unwrap();
+        change_details(
+            &auth_id,
+            UserUpdate::new().with_email("notify26c@pr0.co.uk"),
+        )
+        .unwrap();
+        let sent = recorder.sent_to("notify26b@pr0.co.uk");
+        assert_eq!(1, sent.len());
+        assert_eq!(SecurityEvent::EmailChanged, sent[0].event);
+
+        delete_user(&auth_id).unwrap();
+    }
+}
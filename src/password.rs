@@ -0,0 +1,335 @@
+//! The PasswordPolicy, checked when users are added (add_user) or change their password
+//! (UserUpdate::with_password and change_details). Every rule which fails is reported as
+//! PasswordInvalid with a reason which can be shown to the user.
+//!
+//! The policy is read from the config, and by default only asks for a password of some kind:
+//! - 'password min length' and 'password max length', in characters
+//! - 'password min strength': 0 to 4, the lowest zxcvbn score allowed. 0 allows anything, 3 is
+//!   a good choice for most services. Passwords which are too weak get zxcvbn's feedback.
+//! - 'password forbid identifiers': 'true' to reject passwords containing the username or email
+//! - 'breached passwords file': passwords known from data breaches, in the Have I Been Pwned
+//!   format (SHA-1 hashes with counts, as HASH:COUNT lines). Either a file of full hashes, or a
+//!   directory of range files named by hash prefix (such as 21BD1.txt, with SUFFIX:COUNT lines).
+//!   No network access is needed. An empty value turns the check off.
+use crate::models::UserActionFailure;
+use crate::pauth_error::ApplicationError;
+use crate::store;
+use sha1::{Digest, Sha1};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use zxcvbn::zxcvbn;
+
+/// Usernames and emails shorter than this are not looked for in passwords.
+const MIN_IDENTIFIER_LENGTH: usize = 3;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    pub max_length: usize,
+    pub min_strength: u8,
+    pub forbid_identifiers: bool,
+    pub breached_passwords_file: Option<PathBuf>,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        PasswordPolicy {
+            min_length: 1,
+            max_length: 1024,
+            min_strength: 0,
+            forbid_identifiers: false,
+            breached_passwords_file: None,
+        }
+    }
+}
+
+impl PasswordPolicy {
+    /// The policy set by the config, with the defaults for any keys which are missing.
+    pub fn from_config() -> Result<PasswordPolicy, ApplicationError> {
+        let store = store::current();
+        let get = |key: &str| -> Result<Option<String>, ApplicationError> {
+            Ok(store
+                .get_config(key, None)?
+                .map(|v| v.trim().to_owned())
+                .filter(|v| !v.is_empty()))
+        };
+        let default = PasswordPolicy::default();
+        Ok(PasswordPolicy {
+            min_length: get("password min length")?
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default.min_length),
+            max_length: get("password max length")?
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default.max_length),
+            min_strength: get("password min strength")?
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default.min_strength),
+            forbid_identifiers: get("password forbid identifiers")?
+                .map(|v| v == "true")
+                .unwrap_or(default.forbid_identifiers),
+            breached_passwords_file: get("breached passwords file")?.map(PathBuf::from),
+        })
+    }
+
+    /// The reasons the password is not allowed for the user with the name and email, if any.
+    pub fn check(
+        &self,
+        password: &str,
+        name: &str,
+        email: &str,
+    ) -> Result<Vec<String>, ApplicationError> {
+        let mut reasons = Vec::new();
+        let length = password.chars().count();
+        if length < self.min_length {
+            reasons.push(format!(
+                "Passwords must be at least {} characters long",
+                self.min_length
+            ));
+        }
+        if length > self.max_length {
+            reasons.push(format!(
+                "Passwords must be at most {} characters long",
+                self.max_length
+            ));
+        }
+        if self.forbid_identifiers {
+            let password = password.to_lowercase();
+            let contains = |identifier: &str| {
+                identifier.chars().count() >= MIN_IDENTIFIER_LENGTH
+                    && password.contains(&identifier.to_lowercase())
+            };
+            if contains(name) {
+                reasons.push("Passwords can't contain your username".to_owned());
+            }
+            let local_part = email.rsplit_once('@').map_or(email, |(local, _)| local);
+            if contains(email) || contains(local_part) {
+                reasons.push("Passwords can't contain your email address".to_owned());
+            }
+        }
+        if self.min_strength > 0 {
+            if let Some(reason) = self.weakness(password, name, email) {
+                reasons.push(reason);
+            }
+        }
+        if let Some(path) = &self.breached_passwords_file {
+            if breached(path, password)? {
+                reasons.push(
+                    "That password has appeared in a data breach, so is not safe to use".to_owned(),
+                );
+            }
+        }
+        Ok(reasons)
+    }
+
+    /// Why the password is too easy to guess, if it is.
+    fn weakness(&self, password: &str, name: &str, email: &str) -> Option<String> {
+        let entropy = match zxcvbn(password, &[name, email]) {
+            Ok(entropy) if entropy.score() >= self.min_strength => return None,
+            Ok(entropy) => entropy,
+            //zxcvbn rejects blank passwords, which are as weak as they come
+            Err(_) => return Some("That password is too easy to guess".to_owned()),
+        };
+        let mut reason = "That password is too easy to guess.".to_owned();
+        if let Some(feedback) = entropy.feedback() {
+            if let Some(warning) = feedback.warning() {
+                reason.push_str(&format!(" {}", warning));
+            }
+            for suggestion in feedback.suggestions() {
+                reason.push_str(&format!(" {}", suggestion));
+            }
+        }
+        Some(reason)
+    }
+}
+
+/// Whether the password's SHA-1 hash is in the HIBP file, or the range file for its prefix.
+fn breached(path: &Path, password: &str) -> Result<bool, ApplicationError> {
+    let hash = format!("{:X}", Sha1::digest(password.as_bytes()));
+    let (prefix, suffix) = hash.split_at(5);
+    let (file, wanted) = if path.is_dir() {
+        let range = path.join(format!("{}.txt", prefix));
+        if !range.exists() {
+            return Ok(false);
+        }
+        (range, suffix)
+    } else {
+        (path.to_owned(), hash.as_str())
+    };
+    let read_error = |e: std::io::Error| {
+        ApplicationError::ApplicationDataLogic(format!("Failed to read {}: {}", file.display(), e))
+    };
+    let reader = BufReader::new(File::open(&file).map_err(read_error)?);
+    for line in reader.lines() {
+        let line = line.map_err(read_error)?;
+        let listed = line.split(':').next().unwrap_or("").trim();
+        if listed.eq_ignore_ascii_case(wanted) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Check a new password for the user with the name and email against the policy.
+pub(crate) fn validate(
+    password: &str,
+    name: &str,
+    email: &str,
+) -> Result<Vec<UserActionFailure>, ApplicationError> {
+    Ok(PasswordPolicy::from_config()?
+        .check(password, name, email)?
+        .into_iter()
+        .map(UserActionFailure::PasswordInvalid)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db;
+    use crate::models::*;
+    use crate::password::*;
+    use crate::test_db::TestDb;
+    use diesel::prelude::*;
+    use std::env;
+    use std::fs;
+    use uuid::Uuid;
+
+    #[test]
+    fn policy_rules() {
+        let policy = PasswordPolicy {
+            min_length: 8,
+            max_length: 24,
+            min_strength: 3,
+            forbid_identifiers: true,
+            breached_passwords_file: None,
+        };
+        assert!(policy
+            .check("correct horse battery", "paul", "paul@pr0.co.uk")
+            .unwrap()
+            .is_empty());
+        let reasons = policy.check("pass", "paul", "p@pr0.co.uk").unwrap();
+        assert_eq!(2, reasons.len());
+        assert_eq!("Passwords must be at least 8 characters long", reasons[0]);
+        assert!(reasons[1].starts_with("That password is too easy to guess."));
+        assert_eq!(
+            vec!["Passwords must be at most 24 characters long".to_owned()],
+            policy
+                .check("t2H!q8vL#c0ZpW4rXy7eKm3Ju", "paul", "p@pr0.co.uk")
+                .unwrap()
+        );
+        assert_eq!(
+            vec![
+                "Passwords can't contain your username".to_owned(),
+                "Passwords can't contain your email address".to_owned()
+            ],
+            policy
+                .check("Q7!zSuperPaulX9", "SuperPaul", "superpaul@pr0.co.uk")
+                .unwrap()
+        );
+        //short identifiers would rule out too much
+        assert!(policy
+            .check("correct horse battery", "ba", "ba@pr0.co.uk")
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            1,
+            PasswordPolicy::default()
+                .check("", "paul", "p@pr0.co.uk")
+                .unwrap()
+                .len()
+        );
+        assert!(PasswordPolicy::default()
+            .check("paul", "paul", "p@pr0.co.uk")
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn breached_passwords() {
+        let hash = format!("{:X}", Sha1::digest(b"hunter2"));
+        let file = env::temp_dir().join(format!("pauth-{}.txt", Uuid::new_v4()));
+        fs::write(
+            &file,
+            format!(
+                "000000005AD76BD555C1D6D771DE417A4B87E4B4:4\n{}:17043\n",
+                hash.to_lowercase()
+            ),
+        )
+        .unwrap();
+        let dir = env::temp_dir().join(format!("pauth-{}", Uuid::new_v4()));
+        fs::create_dir(&dir).unwrap();
+        fs::write(
+            dir.join(format!("{}.txt", &hash[..5])),
+            format!("{}:17043\r\n", &hash[5..]),
+        )
+        .unwrap();
+
+        for path in &[&file, &dir] {
+            let policy = PasswordPolicy {
+                breached_passwords_file: Some(path.to_path_buf()),
+                ..PasswordPolicy::default()
+            };
+            assert_eq!(
+                vec![
+                    "That password has appeared in a data breach, so is not safe to use".to_owned()
+                ],
+                policy.check("hunter2", "paul", "p@pr0.co.uk").unwrap()
+            );
+            assert!(policy
+                .check("hunter3", "paul", "p@pr0.co.uk")
+                .unwrap()
+                .is_empty());
+        }
+        fs::remove_file(file).unwrap();
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn add_and_change_check_passwords() {
+        let _db = TestDb::new();
+        db::connection()
+            .unwrap()
+            .execute(
+                "update pauth.config set config_value = case config_key
+                    when 'password min length' then '10' else 'true' end
+                 where config_key in ('password min length', 'password forbid identifiers')",
+            )
+            .unwrap();
+        assert_eq!(
+            AddUserResult::NotAdded(vec![
+                UserActionFailure::PasswordInvalid(
+                    "Passwords must be at least 10 characters long".to_owned()
+                ),
+                UserActionFailure::PasswordInvalid(
+                    "Passwords can't contain your username".to_owned()
+                ),
+            ]),
+            add_user("Paul", "p@pr0.co.uk", "paul1").unwrap()
+        );
+        let auth_id = match add_user("Paul", "p@pr0.co.uk", "long enough").unwrap() {
+            AddUserResult::Added(auth_id) => auth_id,
+            r => panic!("Test failure: user not added, got {:?}", r),
+        };
+        assert_eq!(
+            ChangeDetailsResult::NotChanged(vec![UserActionFailure::PasswordInvalid(
+                "Passwords can't contain your username".to_owned()
+            )]),
+            change_details(
+                &auth_id,
+                &UserUpdate::with_password("paul is long").unwrap()
+            )
+            .unwrap()
+        );
+        //checked against the new name when both change
+        let mut changes = UserUpdate::with_password("paul is long").unwrap();
+        changes.with_chosen_name("Saul");
+        assert_eq!(
+            ChangeDetailsResult::PasswordChanged { sessions_ended: 0 },
+            change_details(&auth_id, &changes).unwrap()
+        );
+        assert!(matches!(
+            login("Saul", "paul is long").unwrap(),
+            LoginResult::LoggedIn(_)
+        ));
+    }
+}
@@ -1,4 +1,4 @@
-use diesel::result::Error as DieselError;
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
 use r2d2::Error as R2D2Error;
 
 pub type InternalErrorMessage = String;
@@ -8,6 +8,20 @@ pub enum ApplicationError {
     Database(diesel::result::Error),
     Connection(r2d2::Error),
     ApplicationDataLogic(InternalErrorMessage),
+    Notification(InternalErrorMessage),
+}
+
+impl ApplicationError {
+    /// Whether the error is a unique constraint failing, such as a username being taken.
+    pub(crate) fn is_unique_violation(&self) -> bool {
+        matches!(
+            self,
+            ApplicationError::Database(DieselError::DatabaseError(
+                DatabaseErrorKind::UniqueViolation,
+                _
+            ))
+        )
+    }
 }
 
 impl From<DieselError> for ApplicationError {
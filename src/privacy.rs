@@ -0,0 +1,567 @@
+//! Data protection requests: giving a user a copy of everything pauth holds about them, and
+//! erasing it. Both are for admins acting on the user's behalf, so need an admin (see the admin
+//! module) rather than the user's password, and are recorded in the admin audit.
+//!
+//! Erasure deletes the user straight away, whatever the config 'deletion grace period days'.
+//! Records which are counted - login history, failed logins and the admin audit - are kept, but
+//! without anything which identifies the user: their login history loses its sources, and their
+//! failed logins their source and the identifier tried, which is replaced with a random one.
+//! This includes failed logins with the usernames and emails the user had before, and login
+//! throttling for all of them is cleared.
+use crate::admin::{self, AdminResult};
+use crate::models::{self, AuthenticatedID, User};
+use crate::pauth_error::ApplicationError;
+use crate::risk;
+use crate::roles;
+use crate::schema::pauth::{
+    auth_failure, auth_history, login_history, source, source_confirmation, user_history,
+};
+use crate::source::Source;
+use crate::store::{self, Session, Store};
+use crate::throttle;
+use chrono::NaiveDateTime;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::sql_types::{Array, Integer};
+use ipnetwork::IpNetwork;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// Erased identifiers in the failed logins are this long (after "erased-").
+const PSEUDONYM_LENGTH: usize = 16;
+
+/// The ip address, mac address and identifier of a pauth.source row.
+type SourceRow = (Option<IpNetwork>, Option<[u8; 6]>, Option<String>);
+
+#[derive(Serialize)]
+struct UserData {
+    user: UserDetails,
+    sessions: Vec<Session>,
+    logins: Vec<AuthRecord>,
+    authentications: Vec<AuthRecord>,
+    failed_logins: Vec<FailedLogin>,
+    changes: Vec<Change>,
+    config: BTreeMap<String, String>,
+    roles: Vec<String>,
+}
+
+/// The user, without their password hash.
+#[derive(Serialize)]
+struct UserDetails {
+    id: i32,
+    chosen_name: String,
+    email: String,
+    last_login: NaiveDateTime,
+    failed_logins: i32,
+    locked_at: Option<NaiveDateTime>,
+    password_changed_at: NaiveDateTime,
+    must_change_password: bool,
+    temporary_password_expires: Option<NaiveDateTime>,
+    deleted_at: Option<NaiveDateTime>,
+    is_admin: bool,
+    disabled_at: Option<NaiveDateTime>,
+    created: NaiveDateTime,
+}
+
+impl From<User> for UserDetails {
+    fn from(user: User) -> UserDetails {
+        UserDetails {
+            id: user.id,
+            chosen_name: user.chosen_name,
+            email: user.email,
+            last_login: user.last_login,
+            failed_logins: user.failed_logins,
+            locked_at: user.locked_at,
+            password_changed_at: user.password_changed_at,
+            must_change_password: user.must_change_password,
+            temporary_password_expires: user.temporary_password_expires,
+            deleted_at: user.deleted_at,
+            is_admin: user.is_admin,
+            disabled_at: user.disabled_at,
+            created: user.created,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct AuthRecord {
+    time: Option<NaiveDateTime>,
+    route: Option<String>,
+    source: Option<SourceDetails>,
+}
+
+#[derive(Serialize)]
+struct FailedLogin {
+    time: NaiveDateTime,
+    identifier: String,
+    source: Option<SourceDetails>,
+}
+
+#[derive(Serialize)]
+struct SourceDetails {
+    ip: Option<String>,
+    mac: Option<String>,
+    identifier: Option<String>,
+}
+
+impl From<SourceRow> for SourceDetails {
+    fn from((ip, mac, identifier): SourceRow) -> SourceDetails {
+        let source = Source {
+            ip: ip.map(|network| network.ip()),
+            mac,
+            identifier,
+        };
+        SourceDetails {
+            ip: source.ip.map(|ip| ip.to_string()),
+            mac: source.mac_string(),
+            identifier: source.identifier,
+        }
+    }
+}
+
+/// A change to the user's details. The old password is not given, only that it changed.
+#[derive(Serialize)]
+struct Change {
+    time: Option<NaiveDateTime>,
+    old_chosen_name: Option<String>,
+    old_email: Option<String>,
+    password_changed: bool,
+}
+
+/// Everything pauth holds about the user as a JSON document. The document has the user (without
+/// their password hash), their sessions, their logins, authentications and failed logins with
+/// where they came from, changes to their details, their config overrides and their roles.
+/// Sources, authentications, failed logins and changes are only kept by the Postgres store, so
+/// are empty with other stores.
+pub fn export_user_data(
+    admin: &AuthenticatedID,
+    uid: i32,
+) -> Result<AdminResult<String>, ApplicationError> {
+    admin::as_admin(
+        "export_user_data",
+        admin,
+        uid,
+        "export user data",
+        |store, user| user_data(store, user),
+    )
+}
+
+fn user_data(store: &dyn Store, user: User) -> Result<String, ApplicationError> {
+    let uid = user.id;
+    let mut data = UserData {
+        user: UserDetails::from(user),
+        sessions: store.sessions(uid)?,
+        logins: Vec::new(),
+        authentications: Vec::new(),
+        failed_logins: Vec::new(),
+        changes: Vec::new(),
+        config: store.user_config(uid)?.into_iter().collect(),
+        roles: store.user_roles(uid)?,
+    };
+    match store::postgres_connection(store)? {
+        Some(conn) => add_postgres_records(&conn, uid, &mut data)?,
+        None => {
+            data.logins = store
+                .login_history(uid)?
+                .into_iter()
+                .map(|login| AuthRecord {
+                    time: Some(login.time),
+                    route: login.route,
+                    source: None,
+                })
+                .collect()
+        }
+    }
+    serde_json::to_string_pretty(&data)
+        .map_err(|e| ApplicationError::ApplicationDataLogic(e.to_string()))
+}
+
+fn add_postgres_records(
+    conn: &PgConnection,
+    uid: i32,
+    data: &mut UserData,
+) -> Result<(), ApplicationError> {
+    data.logins = login_history::table
+        .left_join(source::table)
+        .select((
+            login_history::login_time,
+            login_history::route,
+            (source::ip, source::mac, source::identifier).nullable(),
+        ))
+        .filter(login_history::user_id.eq(uid))
+        .order(login_history::id)
+        .load::<(Option<NaiveDateTime>, Option<String>, Option<SourceRow>)>(conn)?
+        .into_iter()
+        .map(|(time, route, source)| AuthRecord {
+            time,
+            route,
+            source: source.map(SourceDetails::from),
+        })
+        .collect();
+    data.authentications = auth_history::table
+        .left_join(source::table)
+        .select((
+            auth_history::auth_time,
+            auth_history::route,
+            (source::ip, source::mac, source::identifier).nullable(),
+        ))
+        .filter(auth_history::user_id.eq(uid))
+        .order(auth_history::id)
+        .load::<(Option<NaiveDateTime>, Option<String>, Option<SourceRow>)>(conn)?
+        .into_iter()
+        .map(|(time, route, source)| AuthRecord {
+            time,
+            route,
+            source: source.map(SourceDetails::from),
+        })
+        .collect();
+    data.failed_logins = auth_failure::table
+        .left_join(source::table)
+        .select((
+            auth_failure::failure_time,
+            auth_failure::attempted_identifier,
+            (source::ip, source::mac, source::identifier).nullable(),
+        ))
+        .filter(auth_failure::user_id.eq(uid))
+        .order(auth_failure::id)
+        .load::<(NaiveDateTime, String, Option<SourceRow>)>(conn)?
+        .into_iter()
+        .map(|(time, identifier, source)| FailedLogin {
+            time,
+            identifier,
+            source: source.map(SourceDetails::from),
+        })
+        .collect();
+    data.changes = user_history::table
+        .select((
+            user_history::change_time,
+            user_history::old_chosen_name,
+            user_history::old_email,
+            user_history::old_pass.is_not_null(),
+        ))
+        .filter(user_history::user_id.eq(uid))
+        .order(user_history::id)
+        .load::<(Option<NaiveDateTime>, Option<String>, Option<String>, bool)>(conn)?
+        .into_iter()
+        .map(
+            |(time, old_chosen_name, old_email, password_changed)| Change {
+                time,
+                old_chosen_name,
+                old_email,
+                password_changed,
+            },
+        )
+        .collect();
+    Ok(())
+}
+
+/// Delete the user and everything which identifies them, keeping what is counted without them
+/// (see the module documentation). NotAuthorised if the user is another admin, as for the admin
+/// functions which change a user.
+pub fn erase_user(admin: &AuthenticatedID, uid: i32) -> Result<AdminResult<()>, ApplicationError> {
+    admin::over_user("erase_user", admin, uid, "erase user", erase)
+}
+
+fn erase(store: &dyn Store, user: User) -> Result<(), ApplicationError> {
+    let conn = store::postgres_connection(store)?;
+    //failed logins and throttling are by what was tried, which may be an earlier name or email
+    let mut identifiers = vec![user.chosen_name.clone(), user.email.clone()];
+    if let Some(conn) = &conn {
+        identifiers.extend(former_identifiers(conn, user.id)?);
+    }
+    let identifiers: Vec<&str> = identifiers.iter().map(String::as_str).collect();
+    throttle::reset(store, &identifiers)?;
+    let sources = match &conn {
+        Some(conn) => unlink_postgres_records(conn, &user, &identifiers)?,
+        None => Vec::new(),
+    };
+    store.delete_user(user.id)?;
+    roles::sessions_ended(user.id);
+    if let Some(conn) = &conn {
+        delete_unused_sources(conn, &sources)?;
+    }
+    Ok(())
+}
+
+/// The usernames and emails the user had before their current ones.
+fn former_identifiers(conn: &PgConnection, uid: i32) -> Result<Vec<String>, ApplicationError> {
+    Ok(user_history::table
+        .select((user_history::old_chosen_name, user_history::old_email))
+        .filter(user_history::user_id.eq(uid))
+        .load::<(Option<String>, Option<String>)>(conn)?
+        .into_iter()
+        .flat_map(|(name, email)| name.into_iter().chain(email))
+        .collect())
+}
+
+/// Remove the user's identifiers and sources from the records which outlive them, returning the
+/// sources they used.
+fn unlink_postgres_records(
+    conn: &PgConnection,
+    user: &User,
+    identifiers: &[&str],
+) -> Result<Vec<i32>, ApplicationError> {
+    let identifiers: Vec<String> = identifiers
+        .iter()
+        .map(|i| risk::attempted_identifier(i))
+        .collect();
+    conn.transaction::<_, ApplicationError, _>(|| {
+        let failures = || {
+            auth_failure::table.filter(
+                auth_failure::user_id
+                    .eq(user.id)
+                    .or(auth_failure::attempted_identifier.eq_any(&identifiers)),
+            )
+        };
+        let mut sources = login_history::table
+            .select(login_history::source)
+            .filter(login_history::user_id.eq(user.id))
+            .load::<Option<i32>>(conn)?;
+        sources.extend(
+            auth_history::table
+                .select(auth_history::source)
+                .filter(auth_history::user_id.eq(user.id))
+                .load::<Option<i32>>(conn)?,
+        );
+        sources.extend(
+            failures()
+                .select(auth_failure::source)
+                .load::<Option<i32>>(conn)?,
+        );
+        sources.extend(
+            source_confirmation::table
+                .select(source_confirmation::source)
+                .filter(source_confirmation::user_id.eq(user.id))
+                .load::<Option<i32>>(conn)?,
+        );
+
+        diesel::update(failures())
+            .set(auth_failure::source.eq(None::<i32>))
+            .execute(conn)?;
+        //a pseudonym for each identifier, so that counts of distinct identifiers still add up
+        let attempted = failures()
+            .select(auth_failure::attempted_identifier)
+            .distinct()
+            .load::<String>(conn)?;
+        for identifier in attempted {
+            let pseudonym = format!(
+                "erased-{}",
+                models::generate_random_string(PSEUDONYM_LENGTH)
+            );
+            diesel::update(failures().filter(auth_failure::attempted_identifier.eq(identifier)))
+                .set(auth_failure::attempted_identifier.eq(pseudonym))
+                .execute(conn)?;
+        }
+        diesel::update(login_history::table.filter(login_history::user_id.eq(user.id)))
+            .set(login_history::source.eq(None::<i32>))
+            .execute(conn)?;
+        diesel::update(auth_history::table.filter(auth_history::user_id.eq(user.id)))
+            .set(auth_history::source.eq(None::<i32>))
+            .execute(conn)?;
+        let mut sources: Vec<i32> = sources.into_iter().flatten().collect();
+        sources.sort_unstable();
+        sources.dedup();
+        Ok(sources)
+    })
+}
+
+/// Delete the sources which are no longer used by anyone.
+fn delete_unused_sources(conn: &PgConnection, sources: &[i32]) -> Result<(), ApplicationError> {
+    diesel::sql_query(
+        "delete from pauth.source s where s.id = any($1) \
+         and not exists (select 1 from pauth.login_history where source = s.id) \
+         and not exists (select 1 from pauth.auth_history where source = s.id) \
+         and not exists (select 1 from pauth.auth_failure where source = s.id) \
+         and not exists (select 1 from pauth.source_confirmation where source = s.id)",
+    )
+    .bind::<Array<Integer>, _>(sources)
+    .execute(conn)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db;
+    use crate::models::*;
+    use crate::privacy::*;
+    use crate::store::{with_store, MemoryStore};
+    use crate::test_db::{admin, TestDb};
+    use diesel::sql_types::BigInt;
+    use serde_json::Value;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::sync::Arc;
+
+    #[derive(QueryableByName)]
+    struct Count {
+        #[sql_type = "BigInt"]
+        count: i64,
+    }
+
+    #[derive(QueryableByName)]
+    struct Table {
+        #[sql_type = "diesel::sql_types::Text"]
+        table_name: String,
+    }
+
+    fn export(admin: &AuthenticatedID, uid: i32) -> Value {
+        match export_user_data(admin, uid).unwrap() {
+            AdminResult::Done(json) => serde_json::from_str(&json).unwrap(),
+            r => panic!("Test failure: not exported, got {:?}", r),
+        }
+    }
+
+    fn count(conn: &PgConnection, query: &str) -> i64 {
+        diesel::sql_query(query)
+            .get_result::<Count>(conn)
+            .unwrap()
+            .count
+    }
+
+    #[test]
+    fn export_and_erase() {
+        let _db = TestDb::new();
+        let officer = admin("Officer46");
+        let auth_id = match add_user("Forgotten46", "forgotten46@pr0.co.uk", "pass46").unwrap() {
+            AddUserResult::Added(auth_id) => auth_id,
+            r => panic!("Test failure: user not added, got {:?}", r),
+        };
+        let uid = auth_id.user_id;
+        let from =
+            Source::from_ip(IpAddr::V4(Ipv4Addr::new(203, 0, 113, 46))).with_identifier("phone46");
+        assert!(matches!(
+            login_from("Forgotten46", "pass46", &from, Some("app"))
+                .unwrap()
+                .result,
+            LoginResult::LoggedIn(_)
+        ));
+        assert_eq!(
+            LoginResult::AuthenticationFailure,
+            login_from("forgotten46@pr0.co.uk", "wrong", &from, None)
+                .unwrap()
+                .result
+        );
+        crate::set_user_config(uid, "notify on new source login", "false").unwrap();
+        let conn = db::connection().unwrap();
+        conn.execute(&format!(
+            "insert into pauth.user_history(user_id, old_chosen_name, old_email, old_pass)
+             values ({}, 'Remembered46', 'remembered46@pr0.co.uk', 'hash46')",
+            uid
+        ))
+        .unwrap();
+
+        //a failed login with the name they had before
+        assert_eq!(
+            LoginResult::AuthenticationFailure,
+            login_from("Remembered46", "wrong", &from, None)
+                .unwrap()
+                .result
+        );
+
+        assert_eq!(
+            AdminResult::NotAuthorised,
+            export_user_data(&auth_id, uid).unwrap()
+        );
+        let data = export(&officer, uid);
+        assert_eq!("Forgotten46", data["user"]["chosen_name"]);
+        assert!(data["user"].get("pass_hash").is_none());
+        assert_eq!(2, data["sessions"].as_array().unwrap().len());
+        assert_eq!("app", data["logins"][0]["route"]);
+        assert_eq!("203.0.113.46", data["logins"][0]["source"]["ip"]);
+        assert_eq!("phone46", data["logins"][0]["source"]["identifier"]);
+        assert_eq!(
+            "forgotten46@pr0.co.uk",
+            data["failed_logins"][0]["identifier"]
+        );
+        assert_eq!("Remembered46", data["changes"][0]["old_chosen_name"]);
+        assert_eq!(true, data["changes"][0]["password_changed"]);
+        assert!(!data.to_string().contains("hash46"));
+        assert_eq!("false", data["config"]["notify on new source login"]);
+        assert_eq!(
+            AdminResult::NotFound,
+            export_user_data(&officer, -1).unwrap()
+        );
+
+        let logins = count(&conn, "select count(*) as count from pauth.login_history");
+        let failures = count(&conn, "select count(*) as count from pauth.auth_failure");
+        assert_eq!(
+            AdminResult::NotAuthorised,
+            erase_user(&auth_id, uid).unwrap()
+        );
+        assert_eq!(AdminResult::Done(()), erase_user(&officer, uid).unwrap());
+        assert_eq!(AdminResult::NotFound, erase_user(&officer, uid).unwrap());
+        assert_eq!(
+            AdminResult::NotFound,
+            export_user_data(&officer, uid).unwrap()
+        );
+        let actions: Vec<String> = store::current()
+            .admin_actions(uid)
+            .unwrap()
+            .into_iter()
+            .map(|a| a.action)
+            .collect();
+        assert_eq!(vec!["export user data", "erase user"], actions);
+
+        //nothing identifying the user is left anywhere, but the counts are
+        let tables = diesel::sql_query(
+            "select table_name::text from information_schema.tables \
+             where table_schema = 'pauth' and table_type = 'BASE TABLE'",
+        )
+        .load::<Table>(&conn)
+        .unwrap();
+        for table in tables {
+            let query = format!(
+                "select count(*) as count from pauth.{} t where t::text ilike '%forgotten46%' \
+                 or t::text ilike '%remembered46%' or t::text like '%203.0.113.46%' \
+                 or t::text like '%phone46%' or t::text like '%hash46%'",
+                table.table_name
+            );
+            assert_eq!(0, count(&conn, &query), "in {}", table.table_name);
+        }
+        assert_eq!(
+            logins,
+            count(&conn, "select count(*) as count from pauth.login_history")
+        );
+        assert_eq!(
+            failures,
+            count(&conn, "select count(*) as count from pauth.auth_failure")
+        );
+        assert_eq!(
+            0,
+            count(
+                &conn,
+                "select count(*) as count from pauth.config c where not exists \
+                 (select 1 from pauth.default_config d where d.config_id = c.id)"
+            )
+        );
+        assert_eq!(
+            LoginResult::AuthenticationFailure,
+            login("Forgotten46", "pass46").unwrap()
+        );
+    }
+
+    #[test]
+    fn memory_export_and_erase() {
+        with_store(Arc::new(MemoryStore::new()), || {
+            let officer = admin("Officer46");
+            let auth_id = match add_user("Memory46", "memory46@pr0.co.uk", "pass46").unwrap() {
+                AddUserResult::Added(auth_id) => auth_id,
+                r => panic!("Test failure: user not added, got {:?}", r),
+            };
+            let uid = auth_id.user_id;
+            assert!(matches!(
+                login("Memory46", "pass46").unwrap(),
+                LoginResult::LoggedIn(_)
+            ));
+            let data = export(&officer, uid);
+            assert_eq!("memory46@pr0.co.uk", data["user"]["email"]);
+            assert_eq!(2, data["sessions"].as_array().unwrap().len());
+            assert_eq!(1, data["logins"].as_array().unwrap().len());
+            assert!(data["logins"][0]["source"].is_null());
+            assert_eq!(AdminResult::Done(()), erase_user(&officer, uid).unwrap());
+            assert_eq!(
+                AdminResult::NotFound,
+                export_user_data(&officer, uid).unwrap()
+            );
+        });
+    }
+}
@@ -0,0 +1,402 @@
+//! Detection of attacks spread across many users, which per user and per source counters miss.
+//!
+//! Credential stuffing is one source trying many different usernames, typically with
+//! credentials leaked from other sites. Password spraying is one password being tried against
+//! many different users, typically from many sources. Failed passwords are never stored, only
+//! a keyed fingerprint (an HMAC), which is enough to spot the same password being used again.
+//!
+//! The fingerprint key is a secret, so isn't kept in the database. The application gives it
+//! with set_fingerprint_key, or in the environment variable PAUTH_FINGERPRINT_KEY. It should be
+//! the same for every process using the database, and kept as other secrets are. Without one, a
+//! random key is made for each process, so the same password is only spotted among the failures
+//! the process recorded itself.
+//!
+//! Attack detection needs the Postgres store, see the store module.
+use crate::config;
+use crate::models;
+use crate::pauth_error::ApplicationError;
+use crate::source::Source;
+use crate::store;
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::sql_types::{BigInt, Inet, Integer, Nullable, Text, Timestamp};
+use ipnetwork::IpNetwork;
+use lazy_static::lazy_static;
+use std::env;
+use std::sync::RwLock;
+
+/// Keys made for processes without one are this long, from letters and digits.
+const PROCESS_KEY_LENGTH: usize = 64;
+
+lazy_static! {
+    static ref FINGERPRINT_KEY: RwLock<Option<String>> = RwLock::new(
+        env::var("PAUTH_FINGERPRINT_KEY")
+            .ok()
+            .filter(|key| !key.is_empty())
+    );
+    static ref PROCESS_KEY: String = models::generate_random_string(PROCESS_KEY_LENGTH);
+}
+
+/// How likely it is that an attempt is part of an attack.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RiskLevel {
+    Normal,
+    Elevated,
+    High,
+}
+
+/// The assessed risk of a login attempt, and why.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RiskSignal {
+    pub level: RiskLevel,
+    /// distinct usernames/emails tried from the same ip address within the detection window
+    pub identifiers_from_source: i64,
+    /// distinct usernames/emails tried with the same password within the detection window
+    pub identifiers_with_password: i64,
+}
+
+impl RiskSignal {
+    pub fn normal() -> RiskSignal {
+        RiskSignal {
+            level: RiskLevel::Normal,
+            identifiers_from_source: 0,
+            identifiers_with_password: 0,
+        }
+    }
+}
+
+/// What login does about a High risk attempt, set by the config 'attack response'.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum AttackResponse {
+    None,
+    Delay(Duration),
+    Block,
+    StepUp,
+}
+
+pub(crate) fn attack_response() -> Result<AttackResponse, ApplicationError> {
+    Ok(
+        match config::get_config("attack response", None)?.as_deref() {
+            Some("delay") => AttackResponse::Delay(Duration::seconds(config::get_config_i64(
+                "attack delay seconds",
+                None,
+                30,
+            )?)),
+            Some("block") => AttackResponse::Block,
+            Some("step-up") => AttackResponse::StepUp,
+            _ => AttackResponse::None,
+        },
+    )
+}
+
+#[derive(QueryableByName)]
+struct Count {
+    #[sql_type = "BigInt"]
+    count: i64,
+}
+
+/// The level for a count of distinct identifiers, given the threshold for an attack.
+/// Half way to the threshold is Elevated.
+pub(crate) fn level(count: i64, threshold: i64) -> RiskLevel {
+    if count >= threshold {
+        RiskLevel::High
+    } else if count * 2 >= threshold {
+        RiskLevel::Elevated
+    } else {
+        RiskLevel::Normal
+    }
+}
+
+/// Set the key for password fingerprints, in place of PAUTH_FINGERPRINT_KEY (see the module
+/// docs).
+pub fn set_fingerprint_key(key: &str) -> Result<(), ApplicationError> {
+    if key.is_empty() {
+        return Err(ApplicationError::ApplicationDataLogic(
+            "The password fingerprint key is empty".to_owned(),
+        ));
+    }
+    *FINGERPRINT_KEY.write().unwrap() = Some(key.to_owned());
+    Ok(())
+}
+
+fn fingerprint_key() -> String {
+    FINGERPRINT_KEY
+        .read()
+        .unwrap()
+        .clone()
+        .unwrap_or_else(|| PROCESS_KEY.clone())
+}
+
+fn window_start() -> Result<NaiveDateTime, ApplicationError> {
+    let minutes = config::get_config_i64("attack detection window minutes", None, 60)?;
+    Ok(Utc::now().naive_utc() - Duration::minutes(minutes))
+}
+
+/// Assess the risk of an attempt to log in from the source with the password, based on the
+/// failures recorded within the config 'attack detection window minutes'.
+pub(crate) fn assess(
+    conn: &PgConnection,
+    from: &Source,
+    pass: &str,
+) -> Result<RiskSignal, ApplicationError> {
+    let since = window_start()?;
+    let identifiers_from_source = match from.ip {
+        Some(ip) => {
+            diesel::sql_query(
+                "select count(distinct f.attempted_identifier) as count \
+                 from pauth.auth_failure f join pauth.source s on f.source = s.id \
+                 where s.ip = $1 and f.failure_time > $2",
+            )
+            .bind::<Inet, _>(IpNetwork::from(ip))
+            .bind::<Timestamp, _>(since)
+            .get_result::<Count>(conn)?
+            .count
+        }
+        None => 0,
+    };
+    let identifiers_with_password = diesel::sql_query(
+        "select count(distinct attempted_identifier) as count from pauth.auth_failure \
+         where password_fingerprint = encode(pauth.hmac($1, $2, 'sha256'), 'hex') \
+         and failure_time > $3",
+    )
+    .bind::<Text, _>(pass)
+    .bind::<Text, _>(fingerprint_key())
+    .bind::<Timestamp, _>(since)
+    .get_result::<Count>(conn)?
+    .count;
+    let stuffing = level(
+        identifiers_from_source,
+        config::get_config_i64("stuffing distinct identifiers per source", None, 10)?,
+    );
+    let spraying = level(
+        identifiers_with_password,
+        config::get_config_i64("spraying distinct identifiers per password", None, 10)?,
+    );
+    Ok(RiskSignal {
+        level: stuffing.max(spraying),
+        identifiers_from_source,
+        identifiers_with_password,
+    })
+}
+
+/// Assess the risk of a login attempt without making it, for example to decide whether to show
+/// a captcha.
+pub fn assess_risk(from: &Source, pass: &str) -> Result<RiskSignal, ApplicationError> {
+    let conn = store::require_postgres(&*store::current(), "Attack detection")?;
+    assess(&conn, from, pass)
+}
+
+/// How an identifier tried is recorded, so that variations count as one.
+pub(crate) fn attempted_identifier(identifier: &str) -> String {
+    identifier.trim().to_lowercase()
+}
+
+/// Record a failed login for attack detection.
+pub(crate) fn record_failure(
+    conn: &PgConnection,
+    identifier: &str,
+    uid: Option<i32>,
+    source_id: Option<i32>,
+    pass: &str,
+) -> Result<(), ApplicationError> {
+    diesel::sql_query(
+        "insert into pauth.auth_failure \
+         (attempted_identifier, user_id, source, password_fingerprint) \
+         values ($1, $2, $3, encode(pauth.hmac($4, $5, 'sha256'), 'hex'))",
+    )
+    .bind::<Text, _>(attempted_identifier(identifier))
+    .bind::<Nullable<Integer>, _>(uid)
+    .bind::<Nullable<Integer>, _>(source_id)
+    .bind::<Text, _>(pass)
+    .bind::<Text, _>(fingerprint_key())
+    .execute(conn)?;
+    Ok(())
+}
+
+/// Delete failure records older than the config 'auth failure retention days'. Returns the
+/// number of records deleted.
+pub fn purge_auth_failures() -> Result<usize, ApplicationError> {
+    let conn = store::require_postgres(&*store::current(), "Attack detection")?;
+    let days = config::get_config_i64("auth failure retention days", None, 30)?;
+    Ok(
+        diesel::sql_query("delete from pauth.auth_failure where failure_time < $1")
+            .bind::<Timestamp, _>(Utc::now().naive_utc() - Duration::days(days))
+            .execute(&conn)?,
+    )
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AttackKind {
+    CredentialStuffing,
+    PasswordSpraying,
+}
+
+/// A summary of a suspected attack, for reporting.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AttackSummary {
+    pub kind: AttackKind,
+    /// the ip address the attack came from, for credential stuffing
+    pub ip: Option<IpNetwork>,
+    /// the fingerprint of the password used, for password spraying
+    pub password_fingerprint: Option<String>,
+    pub attempts: i64,
+    pub distinct_identifiers: i64,
+    pub distinct_sources: i64,
+    pub first_seen: NaiveDateTime,
+    pub last_seen: NaiveDateTime,
+}
+
+#[derive(QueryableByName)]
+struct SummaryRow {
+    #[sql_type = "Nullable<Inet>"]
+    ip: Option<IpNetwork>,
+    #[sql_type = "Nullable<Text>"]
+    password_fingerprint: Option<String>,
+    #[sql_type = "BigInt"]
+    attempts: i64,
+    #[sql_type = "BigInt"]
+    distinct_identifiers: i64,
+    #[sql_type = "BigInt"]
+    distinct_sources: i64,
+    #[sql_type = "Timestamp"]
+    first_seen: NaiveDateTime,
+    #[sql_type = "Timestamp"]
+    last_seen: NaiveDateTime,
+}
+
+impl SummaryRow {
+    fn into_summary(self, kind: AttackKind) -> AttackSummary {
+        AttackSummary {
+            kind,
+            ip: self.ip,
+            password_fingerprint: self.password_fingerprint,
+            attempts: self.attempts,
+            distinct_identifiers: self.distinct_identifiers,
+            distinct_sources: self.distinct_sources,
+            first_seen: self.first_seen,
+            last_seen: self.last_seen,
+        }
+    }
+}
+
+/// Suspected attacks since the given time, using the same thresholds as login. Most recent
+/// first.
+pub fn attack_summaries(since: NaiveDateTime) -> Result<Vec<AttackSummary>, ApplicationError> {
+    let conn = store::require_postgres(&*store::current(), "Attack detection")?;
+    let stuffing = diesel::sql_query(
+        "select s.ip as ip, null::text as password_fingerprint, count(*) as attempts, \
+         count(distinct f.attempted_identifier) as distinct_identifiers, \
+         count(distinct f.source) as distinct_sources, \
+         min(f.failure_time) as first_seen, max(f.failure_time) as last_seen \
+         from pauth.auth_failure f join pauth.source s on f.source = s.id \
+         where f.failure_time > $1 and s.ip is not null \
+         group by s.ip having count(distinct f.attempted_identifier) >= $2",
+    )
+    .bind::<Timestamp, _>(since)
+    .bind::<BigInt, _>(config::get_config_i64(
+        "stuffing distinct identifiers per source",
+        None,
+        10,
+    )?)
+    .load::<SummaryRow>(&conn)?;
+    let spraying = diesel::sql_query(
+        "select null::inet as ip, password_fingerprint, count(*) as attempts, \
+         count(distinct attempted_identifier) as distinct_identifiers, \
+         count(distinct source) as distinct_sources, \
+         min(failure_time) as first_seen, max(failure_time) as last_seen \
+         from pauth.auth_failure \
+         where failure_time > $1 \
+         group by password_fingerprint having count(distinct attempted_identifier) >= $2",
+    )
+    .bind::<Timestamp, _>(since)
+    .bind::<BigInt, _>(config::get_config_i64(
+        "spraying distinct identifiers per password",
+        None,
+        10,
+    )?)
+    .load::<SummaryRow>(&conn)?;
+    let mut summaries: Vec<AttackSummary> = stuffing
+        .into_iter()
+        .map(|r| r.into_summary(AttackKind::CredentialStuffing))
+        .chain(
+            spraying
+                .into_iter()
+                .map(|r| r.into_summary(AttackKind::PasswordSpraying)),
+        )
+        .collect();
+    summaries.sort_by_key(|s| std::cmp::Reverse(s.last_seen));
+    Ok(summaries)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::models::*;
+    use crate::risk::*;
+    use crate::source::Source;
+    use crate::test_db::TestDb;
+    use chrono::{Duration, Utc};
+
+    #[test]
+    fn levels() {
+        assert_eq!(RiskLevel::Normal, level(4, 10));
+        assert_eq!(RiskLevel::Elevated, level(5, 10));
+        assert_eq!(RiskLevel::High, level(10, 10));
+    }
+
+    #[test]
+    fn detect_stuffing_and_spraying() {
+        let _db = TestDb::new();
+        //the key is the application's, never in the config
+        assert_eq!(
+            None,
+            crate::get_config("password fingerprint key", None).unwrap()
+        );
+        assert!(set_fingerprint_key("").is_err());
+        let stuffer = Source::from_ip("198.51.100.30".parse().unwrap());
+        for i in 0..10 {
+            login_from(
+                &format!("stuffed30-{}", i),
+                &format!("leaked{}", i),
+                &stuffer,
+                None,
+            )
+            .unwrap();
+        }
+        let signal = assess_risk(&stuffer, "anything").unwrap();
+        assert_eq!(RiskLevel::High, signal.level);
+        assert!(signal.identifiers_from_source >= 10);
+        assert_eq!(
+            RiskLevel::Normal,
+            assess_risk(
+                &Source::from_ip("198.51.100.31".parse().unwrap()),
+                "anything"
+            )
+            .unwrap()
+            .level
+        );
+
+        //one password, each user from a different source
+        for i in 0..10 {
+            let sprayer = Source::from_ip(format!("203.0.113.{}", i).parse().unwrap());
+            login_from(&format!("sprayed30-{}", i), "Spring2030!", &sprayer, None).unwrap();
+        }
+        let signal = assess_risk(&Source::default(), "Spring2030!").unwrap();
+        assert_eq!(RiskLevel::High, signal.level);
+        assert_eq!(
+            RiskLevel::Normal,
+            assess_risk(&Source::default(), "Summer2030!")
+                .unwrap()
+                .level
+        );
+
+        let summaries = attack_summaries(Utc::now().naive_utc() - Duration::hours(1)).unwrap();
+        assert!(summaries
+            .iter()
+            .any(|s| s.kind == AttackKind::CredentialStuffing
+                && s.ip == Some("198.51.100.30".parse().unwrap())));
+        assert!(summaries
+            .iter()
+            .any(|s| s.kind == AttackKind::PasswordSpraying && s.distinct_sources >= 10));
+    }
+}
@@ -0,0 +1,316 @@
+//! Roles and permissions, for applications to decide what their users may do. A role is a named
+//! set of permissions (such as "invoices:read"), and a user has the permissions of all of their
+//! roles. Make roles with create_role, give them permissions with grant_permission, and give
+//! users roles with grant_role. These don't check who is calling, so call them from trusted code,
+//! or after checking that the caller has a permission to manage roles with has_permission.
+//!
+//! has_permission checks a permission for an AuthenticatedID. A session which has ended has no
+//! permissions, and nor does an impersonation (see the admin module). That the session is valid
+//! is cached in the process along with its permission set, for the config 'permission cache
+//! seconds', so that neither the token nor the user's roles are looked up for every check.
+//! Changes to roles, and sessions ended (by a password change, deleting the account,
+//! disable_user or force_logout), through this process clear the cache straight away, but
+//! changes made by other processes are only seen once the cached permissions expire. Set the
+//! config to 0 to turn the cache off.
+use crate::config;
+use crate::models::AuthenticatedID;
+use crate::pauth_error::ApplicationError;
+use crate::store;
+use lazy_static::lazy_static;
+use std::collections::{HashMap, HashSet};
+use std::sync::{RwLock, RwLockWriteGuard};
+use std::time::{Duration, Instant};
+
+lazy_static! {
+    static ref CACHE: RwLock<PermissionCache> = RwLock::new(PermissionCache::default());
+}
+
+#[derive(Default)]
+struct PermissionCache {
+    //changes whenever the cache is cleared, so that permissions read from the store before a
+    //change aren't cached after it
+    generation: u64,
+    //by user id and token, only for tokens which were valid
+    sessions: HashMap<(i32, String), CachedPermissions>,
+}
+
+struct CachedPermissions {
+    permissions: HashSet<String>,
+    expires: Instant,
+}
+
+fn cache() -> RwLockWriteGuard<'static, PermissionCache> {
+    CACHE.write().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Forget the cached permissions of the user, or of everyone.
+fn clear_cache(uid: Option<i32>) {
+    let mut cache = cache();
+    cache.generation += 1;
+    match uid {
+        Some(uid) => cache.sessions.retain(|(id, _), _| *id != uid),
+        None => cache.sessions.clear(),
+    }
+}
+
+/// Forget the cached sessions of the user, whose sessions have ended.
+pub(crate) fn sessions_ended(uid: i32) {
+    clear_cache(Some(uid));
+}
+
+/// Returns false if there is already a role with the name.
+pub fn create_role(name: &str) -> Result<bool, ApplicationError> {
+    let store = store::current();
+    store.api_call("create_role", &[name])?;
+    store.create_role(name)
+}
+
+/// Delete the role, taking it away from its users. Returns false if there is no such role.
+pub fn delete_role(name: &str) -> Result<bool, ApplicationError> {
+    let store = store::current();
+    store.api_call("delete_role", &[name])?;
+    let deleted = store.delete_role(name)?;
+    clear_cache(None);
+    Ok(deleted)
+}
+
+/// The roles' names, in order.
+pub fn roles() -> Result<Vec<String>, ApplicationError> {
+    let store = store::current();
+    store.api_call("roles", &[])?;
+    store.roles()
+}
+
+/// Give the role the permission. Returns false if there is no such role.
+pub fn grant_permission(role: &str, permission: &str) -> Result<bool, ApplicationError> {
+    let store = store::current();
+    store.api_call("grant_permission", &[role, permission])?;
+    let granted = store.grant_permission(role, permission)?;
+    clear_cache(None);
+    Ok(granted)
+}
+
+/// Take the permission away from the role. Returns false if the role didn't have it.
+pub fn revoke_permission(role: &str, permission: &str) -> Result<bool, ApplicationError> {
+    let store = store::current();
+    store.api_call("revoke_permission", &[role, permission])?;
+    let revoked = store.revoke_permission(role, permission)?;
+    clear_cache(None);
+    Ok(revoked)
+}
+
+/// The role's permissions, in order.
+pub fn role_permissions(role: &str) -> Result<Vec<String>, ApplicationError> {
+    let store = store::current();
+    store.api_call("role_permissions", &[role])?;
+    store.role_permissions(role)
+}
+
+/// Give the user the role. Returns false if there is no such user or role.
+pub fn grant_role(uid: i32, role: &str) -> Result<bool, ApplicationError> {
+    let store = store::current();
+    store.api_call("grant_role", &[&uid.to_string(), role])?;
+    let granted = store.grant_role(uid, role)?;
+    clear_cache(Some(uid));
+    Ok(granted)
+}
+
+/// Take the role away from the user. Returns false if they didn't have it.
+pub fn revoke_role(uid: i32, role: &str) -> Result<bool, ApplicationError> {
+    let store = store::current();
+    store.api_call("revoke_role", &[&uid.to_string(), role])?;
+    let revoked = store.revoke_role(uid, role)?;
+    clear_cache(Some(uid));
+    Ok(revoked)
+}
+
+/// The user's roles, in order.
+pub fn user_roles(uid: i32) -> Result<Vec<String>, ApplicationError> {
+    let store = store::current();
+    store.api_call("user_roles", &[&uid.to_string()])?;
+    store.user_roles(uid)
+}
+
+/// The permissions the user has from all of their roles, in order, read from the store.
+pub fn user_permissions(uid: i32) -> Result<Vec<String>, ApplicationError> {
+    let store = store::current();
+    store.api_call("user_permissions", &[&uid.to_string()])?;
+    store.user_permissions(uid)
+}
+
+/// Whether the AuthenticatedID is a valid login (not an impersonation), and the user has the
+/// permission from one of their roles. Uses the session's cached permissions if there are some
+/// (see the module docs).
+pub fn has_permission(
+    auth_token: &AuthenticatedID,
+    permission: &str,
+) -> Result<bool, ApplicationError> {
+    let store = store::current();
+    store.api_call(
+        "has_permission",
+        &[&auth_token.user_id.to_string(), permission],
+    )?;
+    let key = (auth_token.user_id, auth_token.token.clone());
+    let generation = {
+        let cache = CACHE.read().unwrap_or_else(|e| e.into_inner());
+        if let Some(cached) = cache.sessions.get(&key) {
+            if cached.expires > Instant::now() {
+                return Ok(cached.permissions.contains(permission));
+            }
+        }
+        cache.generation
+    };
+    //an impersonation's token isn't a login token, so it has no permissions
+    if !store.token_valid(auth_token.user_id, &auth_token.token)? {
+        return Ok(false);
+    }
+    let permissions: HashSet<String> = store
+        .user_permissions(auth_token.user_id)?
+        .into_iter()
+        .collect();
+    let has = permissions.contains(permission);
+    let seconds = config::get_config_i64("permission cache seconds", Some(auth_token.user_id), 60)?;
+    let now = Instant::now();
+    let mut cache = cache();
+    cache.sessions.retain(|_, cached| cached.expires > now);
+    if seconds > 0 && cache.generation == generation {
+        cache.sessions.insert(
+            key,
+            CachedPermissions {
+                permissions,
+                expires: now + Duration::from_secs(seconds as u64),
+            },
+        );
+    }
+    Ok(has)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::admin;
+    use crate::models::*;
+    use crate::roles::*;
+    use crate::test_db::{added, admin, TestDb, PASSWORD};
+
+    #[test]
+    fn roles_and_permissions() {
+        let _db = TestDb::new();
+        let clerk = added("Clerk50");
+        let manager = added("Manager50");
+        assert!(create_role("clerk").unwrap());
+        assert!(!create_role("clerk").unwrap());
+        assert!(create_role("manager").unwrap());
+        assert_eq!(vec!["clerk", "manager"], roles().unwrap());
+        assert!(grant_permission("clerk", "invoices:read").unwrap());
+        assert!(grant_permission("manager", "invoices:read").unwrap());
+        assert!(grant_permission("manager", "invoices:approve").unwrap());
+        assert!(!grant_permission("auditor", "invoices:read").unwrap());
+        assert!(grant_role(clerk.user_id, "clerk").unwrap());
+        assert!(grant_role(manager.user_id, "clerk").unwrap());
+        assert!(grant_role(manager.user_id, "manager").unwrap());
+        assert!(!grant_role(-1, "clerk").unwrap());
+        assert!(!grant_role(clerk.user_id, "auditor").unwrap());
+        assert_eq!(
+            vec!["clerk", "manager"],
+            user_roles(manager.user_id).unwrap()
+        );
+        assert_eq!(
+            vec!["invoices:approve", "invoices:read"],
+            user_permissions(manager.user_id).unwrap()
+        );
+
+        assert!(has_permission(&clerk, "invoices:read").unwrap());
+        assert!(!has_permission(&clerk, "invoices:approve").unwrap());
+        assert!(has_permission(&manager, "invoices:approve").unwrap());
+        let forged = AuthenticatedID {
+            user_id: manager.user_id,
+            token: clerk.token.clone(),
+        };
+        assert!(!has_permission(&forged, "invoices:read").unwrap());
+
+        //changes through pauth are seen straight away
+        assert!(revoke_role(manager.user_id, "manager").unwrap());
+        assert!(!has_permission(&manager, "invoices:approve").unwrap());
+        assert!(has_permission(&manager, "invoices:read").unwrap());
+        assert!(revoke_permission("clerk", "invoices:read").unwrap());
+        assert!(!has_permission(&clerk, "invoices:read").unwrap());
+
+        //but the permissions are cached for each session, so changes made elsewhere are not
+        store::current()
+            .grant_permission("clerk", "invoices:read")
+            .unwrap();
+        assert!(!has_permission(&clerk, "invoices:read").unwrap());
+        let clerk_again = match login("Clerk50", PASSWORD).unwrap() {
+            LoginResult::LoggedIn(id) => id,
+            r => panic!("Test failure: login failed, got {:?}", r),
+        };
+        assert!(has_permission(&clerk_again, "invoices:read").unwrap());
+        //unless the cache is turned off
+        crate::set_user_config(manager.user_id, "permission cache seconds", "0").unwrap();
+        assert!(revoke_role(manager.user_id, "clerk").unwrap());
+        assert!(!has_permission(&manager, "invoices:read").unwrap());
+        store::current()
+            .grant_role(manager.user_id, "clerk")
+            .unwrap();
+        assert!(has_permission(&manager, "invoices:read").unwrap());
+
+        assert!(delete_role("clerk").unwrap());
+        assert!(!has_permission(&clerk_again, "invoices:read").unwrap());
+        assert!(user_roles(clerk.user_id).unwrap().is_empty());
+    }
+
+    #[test]
+    fn ended_sessions_have_no_permissions() {
+        let _db = TestDb::new();
+        let admin = admin("Admin50");
+        let user = added("Ended50");
+        assert!(create_role("reader").unwrap());
+        assert!(grant_permission("reader", "invoices:read").unwrap());
+        assert!(grant_role(user.user_id, "reader").unwrap());
+        assert!(has_permission(&user, "invoices:read").unwrap());
+
+        //the session is cached with the permissions, so one ended elsewhere still has them
+        store::current().delete_tokens(user.user_id, None).unwrap();
+        assert!(has_permission(&user, "invoices:read").unwrap());
+        //but ending sessions through pauth is seen straight away
+        let login_again = || match login("Ended50", PASSWORD).unwrap() {
+            LoginResult::LoggedIn(id) => id,
+            r => panic!("Test failure: login failed, got {:?}", r),
+        };
+        let user = login_again();
+        assert!(has_permission(&user, "invoices:read").unwrap());
+        assert_eq!(
+            admin::AdminResult::Done(1),
+            admin::force_logout(&admin, user.user_id).unwrap()
+        );
+        assert!(!has_permission(&user, "invoices:read").unwrap());
+
+        let user = login_again();
+        let other = login_again();
+        assert!(has_permission(&other, "invoices:read").unwrap());
+        assert_eq!(
+            ChangeDetailsResult::PasswordChanged { sessions_ended: 1 },
+            change_details(&user, &UserUpdate::with_password("pass50b").unwrap()).unwrap()
+        );
+        assert!(!has_permission(&other, "invoices:read").unwrap());
+        assert!(has_permission(&user, "invoices:read").unwrap());
+
+        assert_eq!(
+            admin::AdminResult::Done(()),
+            admin::disable_user(&admin, user.user_id).unwrap()
+        );
+        assert!(!has_permission(&user, "invoices:read").unwrap());
+        assert_eq!(
+            admin::AdminResult::Done(()),
+            admin::enable_user(&admin, user.user_id).unwrap()
+        );
+
+        //an impersonation has no permissions
+        let impersonation = match admin::impersonate(&admin, user.user_id, "ticket 50").unwrap() {
+            admin::AdminResult::Done(id) => id,
+            r => panic!("Test failure: impersonation refused, got {:?}", r),
+        };
+        assert!(check_id(&impersonation).unwrap());
+        assert!(!has_permission(&impersonation, "invoices:read").unwrap());
+    }
+}
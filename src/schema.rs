@@ -1,4 +1,25 @@
 pub mod pauth {
+    table! {
+        pauth.admin_audit (id) {
+            id -> Int4,
+            admin_id -> Int4,
+            user_id -> Nullable<Int4>,
+            action -> Text,
+            action_time -> Timestamp,
+        }
+    }
+
+    table! {
+        pauth.auth_failure (id) {
+            id -> Int4,
+            attempted_identifier -> Varchar,
+            user_id -> Nullable<Int4>,
+            source -> Nullable<Int4>,
+            failure_time -> Timestamp,
+            password_fingerprint -> Text,
+        }
+    }
+
     table! {
         pauth.auth_history (id) {
             id -> Int4,
@@ -24,6 +45,31 @@ pub mod pauth {
         }
     }
 
+    table! {
+        pauth.impersonation (id) {
+            id -> Int4,
+            admin_id -> Int4,
+            user_id -> Int4,
+            token_hash -> Text,
+            reason -> Text,
+            started -> Timestamp,
+            expires -> Timestamp,
+            ended -> Nullable<Timestamp>,
+        }
+    }
+
+    table! {
+        pauth.ip_rule (id) {
+            id -> Int4,
+            user_id -> Nullable<Int4>,
+            email_domain -> Nullable<Varchar>,
+            network -> Cidr,
+            allow -> Bool,
+            note -> Nullable<Text>,
+            created -> Timestamp,
+        }
+    }
+
     table! {
         pauth.login_history (id) {
             id -> Int4,
@@ -34,6 +80,31 @@ pub mod pauth {
         }
     }
 
+    table! {
+        pauth.login_throttle (identifier) {
+            identifier -> Varchar,
+            failures -> Int4,
+            last_failure -> Timestamp,
+            blocked_until -> Nullable<Timestamp>,
+        }
+    }
+
+    table! {
+        pauth.password_change_token (id) {
+            id -> Int4,
+            user_id -> Int4,
+            token_hash -> Text,
+            expires -> Timestamp,
+        }
+    }
+
+    table! {
+        pauth.permission (id) {
+            id -> Int4,
+            name -> Varchar,
+        }
+    }
+
     table! {
         pauth.pw_reset (id) {
             id -> Int4,
@@ -43,6 +114,20 @@ pub mod pauth {
         }
     }
 
+    table! {
+        pauth.role (id) {
+            id -> Int4,
+            name -> Varchar,
+        }
+    }
+
+    table! {
+        pauth.role_permission (role_id, permission_id) {
+            role_id -> Int4,
+            permission_id -> Int4,
+        }
+    }
+
     table! {
         pauth.source (id) {
             id -> Int4,
@@ -52,6 +137,16 @@ pub mod pauth {
         }
     }
 
+    table! {
+        pauth.source_confirmation (id) {
+            id -> Int4,
+            user_id -> Int4,
+            source -> Nullable<Int4>,
+            token_hash -> Text,
+            expires -> Timestamp,
+        }
+    }
+
     table! {
         pauth.user_config (id) {
             id -> Int4,
@@ -78,6 +173,31 @@ pub mod pauth {
             token -> Text,
             created -> Timestamp,
             last_used -> Timestamp,
+            last_strong_auth -> Nullable<Timestamp>,
+        }
+    }
+
+    table! {
+        pauth.user_key (user_id) {
+            user_id -> Int4,
+            name_key -> Varchar,
+            email_key -> Varchar,
+            name_skeleton -> Nullable<Varchar>,
+            name_collision -> Bool,
+            email_collision -> Bool,
+        }
+    }
+
+    table! {
+        pauth.user_key_form (form) {
+            form -> Varchar,
+        }
+    }
+
+    table! {
+        pauth.user_role (user_id, role_id) {
+            user_id -> Int4,
+            role_id -> Int4,
         }
     }
 
@@ -88,30 +208,63 @@ pub mod pauth {
             email -> Varchar,
             pass_hash -> Text,
             last_login -> Timestamp,
+            failed_logins -> Int4,
+            locked_at -> Nullable<Timestamp>,
+            password_changed_at -> Timestamp,
+            must_change_password -> Bool,
+            temporary_password_expires -> Nullable<Timestamp>,
+            deleted_at -> Nullable<Timestamp>,
+            is_admin -> Bool,
+            disabled_at -> Nullable<Timestamp>,
+            created -> Timestamp,
         }
     }
 
+    joinable!(auth_failure -> source (source));
+    joinable!(auth_failure -> users (user_id));
     joinable!(auth_history -> source (source));
     joinable!(auth_history -> users (user_id));
     joinable!(default_config -> config (config_id));
+    joinable!(ip_rule -> users (user_id));
     joinable!(login_history -> source (source));
     joinable!(login_history -> users (user_id));
+    joinable!(password_change_token -> users (user_id));
     joinable!(pw_reset -> users (user_id));
+    joinable!(role_permission -> permission (permission_id));
+    joinable!(role_permission -> role (role_id));
+    joinable!(source_confirmation -> source (source));
+    joinable!(source_confirmation -> users (user_id));
     joinable!(user_config -> config (config_id));
     joinable!(user_config -> users (user_id));
     joinable!(user_history -> users (user_id));
+    joinable!(user_key -> users (user_id));
     joinable!(user_login_tokens -> users (user_id));
+    joinable!(user_role -> role (role_id));
+    joinable!(user_role -> users (user_id));
 
     allow_tables_to_appear_in_same_query!(
+        admin_audit,
+        auth_failure,
         auth_history,
         config,
         default_config,
+        impersonation,
+        ip_rule,
         login_history,
+        login_throttle,
+        password_change_token,
+        permission,
         pw_reset,
+        role,
+        role_permission,
         source,
+        source_confirmation,
         user_config,
         user_history,
+        user_key,
+        user_key_form,
         user_login_tokens,
+        user_role,
         users,
     );
 }
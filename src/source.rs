@@ -0,0 +1,169 @@
+use super::schema::pauth::login_history;
+use super::schema::pauth::source;
+use crate::config;
+use crate::pauth_error::ApplicationError;
+use crate::store::Store;
+use diesel::expression::bound::Bound;
+use diesel::pg::Pg;
+use diesel::prelude::*;
+use diesel::sql_types::{Bool, Inet};
+use diesel::IntoSql;
+use ipnetwork::IpNetwork;
+use std::fmt;
+use std::net::IpAddr;
+
+/// Where an authentication attempt came from. All of the fields are optional - supply
+/// whatever is known about the client. The identifier is free text, and could be a device id,
+/// a user agent or anything else which is meaningful to the implementing system.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Source {
+    pub ip: Option<IpAddr>,
+    pub mac: Option<[u8; 6]>,
+    pub identifier: Option<String>,
+}
+
+impl Source {
+    pub fn from_ip(ip: IpAddr) -> Source {
+        Source {
+            ip: Some(ip),
+            mac: None,
+            identifier: None,
+        }
+    }
+    pub fn with_mac(mut self, mac: [u8; 6]) -> Source {
+        self.mac = Some(mac);
+        self
+    }
+    pub fn with_identifier(mut self, identifier: &str) -> Source {
+        self.identifier = Some(identifier.to_owned());
+        self
+    }
+    pub fn mac_string(&self) -> Option<String> {
+        self.mac.map(|m| {
+            m.iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<Vec<_>>()
+                .join(":")
+        })
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let parts: Vec<String> = vec![
+            self.ip.map(|ip| ip.to_string()),
+            self.mac_string(),
+            self.identifier.clone(),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        if parts.is_empty() {
+            write!(f, "unknown source")
+        } else {
+            write!(f, "{}", parts.join(" "))
+        }
+    }
+}
+
+diesel_infix_operator!(IsContainedByOrEquals, " <<= ", backend: Pg);
+
+fn source_ip_in(subnet: IpNetwork) -> IsContainedByOrEquals<source::ip, Bound<Inet, IpNetwork>> {
+    IsContainedByOrEquals::new(source::ip, subnet.into_sql::<Inet>())
+}
+
+impl Source {
+    /// Whether there is anything known about this source
+    pub fn is_known(&self) -> bool {
+        self.ip.is_some() || self.mac.is_some() || self.identifier.is_some()
+    }
+
+    /// Find the id of the matching row in pauth.source, creating one if needed.
+    pub(crate) fn find_or_create(&self, conn: &PgConnection) -> Result<i32, ApplicationError> {
+        let ip = self.ip.map(IpNetwork::from);
+        let mut query = source::table.select(source::id).into_boxed();
+        query = match ip {
+            Some(ip) => query.filter(source::ip.eq(ip)),
+            None => query.filter(source::ip.is_null()),
+        };
+        query = match self.mac {
+            Some(mac) => query.filter(source::mac.eq(mac)),
+            None => query.filter(source::mac.is_null()),
+        };
+        query = match &self.identifier {
+            Some(identifier) => query.filter(source::identifier.eq(identifier)),
+            None => query.filter(source::identifier.is_null()),
+        };
+        if let Some(existing) = query.first::<i32>(conn).optional()? {
+            return Ok(existing);
+        }
+        Ok(diesel::insert_into(source::table)
+            .values((
+                source::ip.eq(ip),
+                source::mac.eq(self.mac),
+                source::identifier.eq(&self.identifier),
+            ))
+            .returning(source::id)
+            .get_result(conn)?)
+    }
+
+    /// Whether the user has previously logged in from this source. A source is familiar if the
+    /// identifier or mac address has been seen before, or if the ip address is in the same subnet
+    /// as one seen before. The subnet size is set by the config keys
+    /// 'familiar source ipv4 prefix' and 'familiar source ipv6 prefix'.
+    ///
+    /// If nothing is known about the source, there is nothing to compare, so it is treated
+    /// as familiar.
+    pub(crate) fn is_familiar(
+        &self,
+        conn: &PgConnection,
+        uid: i32,
+    ) -> Result<bool, ApplicationError> {
+        if !self.is_known() {
+            return Ok(true);
+        }
+        let mut query = login_history::table
+            .inner_join(source::table)
+            .select(login_history::id)
+            .filter(login_history::user_id.eq(uid))
+            .into_boxed();
+        //start with a condition that is never true, so the checks below can be or'ed on
+        let mut matches: Box<dyn BoxableExpression<_, Pg, SqlType = Bool>> =
+            Box::new(source::id.is_null());
+        if let Some(ip) = self.ip {
+            let prefix = match ip {
+                IpAddr::V4(_) => {
+                    config::get_config_i64("familiar source ipv4 prefix", Some(uid), 24)?
+                }
+                IpAddr::V6(_) => {
+                    config::get_config_i64("familiar source ipv6 prefix", Some(uid), 64)?
+                }
+            };
+            let subnet = IpNetwork::new(ip, prefix as u8)
+                .and_then(|n| IpNetwork::new(n.network(), prefix as u8))
+                .map_err(|e| ApplicationError::ApplicationDataLogic(e.to_string()))?;
+            matches = Box::new(matches.or(source_ip_in(subnet)));
+        }
+        if let Some(mac) = self.mac {
+            matches = Box::new(matches.or(source::mac.eq(mac)));
+        }
+        if let Some(identifier) = &self.identifier {
+            matches = Box::new(matches.or(source::identifier.eq(identifier.clone())));
+        }
+        query = query.filter(matches);
+        Ok(query.first::<i32>(conn).optional()?.is_some())
+    }
+}
+
+/// Record a successful login in the login history, if history is being kept.
+pub(crate) fn record_login(
+    store: &dyn Store,
+    uid: i32,
+    source_id: Option<i32>,
+    route: Option<&str>,
+) -> Result<(), ApplicationError> {
+    if config::get_config_bool("keep login history", Some(uid), true)? {
+        store.record_login(uid, source_id, route)?;
+    }
+    Ok(())
+}
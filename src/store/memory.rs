@@ -0,0 +1,1005 @@
+use super::{bcrypt_hash, bcrypt_verify, LoginRecord, Session, Store};
+use crate::admin::{AdminAction, Impersonation, PageRequest, SortKey, UserFilter};
+use crate::identifier::Canonicaliser;
+use crate::ip_rules::{IpRule, RuleScope};
+use crate::models::{CollisionKind, IdentifierCollision, User, UserActionFailure, UserUpdate};
+use crate::pauth_error::ApplicationError;
+use chrono::{NaiveDateTime, Utc};
+use diesel::result::{DatabaseErrorKind, Error};
+use ipnetwork::IpNetwork;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::{Mutex, MutexGuard};
+
+/// The default config values, as set by the Postgres migrations.
+const DEFAULT_CONFIG: &[(&str, &str)] = &[
+    ("max failed logins per source", "5"),
+    ("max failed logins per source reset time minutes", "1440"),
+    ("token validity minutes", "1440000"),
+    ("expire token if not used for minutes", "144000"),
+    ("password reset validity minutes", "720"),
+    ("keep login history", "true"),
+    ("keep reset history", "true"),
+    ("keep user change history", "true"),
+    ("login history retention days", "365"),
+    ("reset history retention days", "365"),
+    ("user change history retention", "365"),
+    ("notify on new source login", "true"),
+    ("notify on repeated login failures", "true"),
+    ("notify on password change", "true"),
+    ("notify on email change", "true"),
+    ("notify on password reset request", "true"),
+    ("notify on account disabled", "true"),
+    ("notify on account locked", "true"),
+    ("unfamiliar source action", "allow"),
+    ("source confirmation validity minutes", "30"),
+    ("familiar source ipv4 prefix", "24"),
+    ("familiar source ipv6 prefix", "64"),
+    ("failed logins before delay", "3"),
+    ("login delay seconds", "1"),
+    ("max login delay seconds", "900"),
+    ("failed login count reset minutes", "1440"),
+    ("max failed logins before lock", "10"),
+    ("identifier normalisation", "nfkc"),
+    ("identifier case folding", "true"),
+    ("check confusable usernames", "true"),
+    ("username min length", "1"),
+    ("username max length", "64"),
+    ("username allowed characters", "any"),
+    (
+        "reserved usernames",
+        "admin,administrator,root,support,postmaster,webmaster,security",
+    ),
+    ("username blocklist file", ""),
+    ("email max length", "254"),
+    ("disposable email domains file", ""),
+    ("password min length", "1"),
+    ("password max length", "1024"),
+    ("password min strength", "0"),
+    ("password forbid identifiers", "false"),
+    ("breached passwords file", ""),
+    ("max password age days", "0"),
+    ("password change token validity minutes", "30"),
+    ("temporary password validity minutes", "1440"),
+    ("end other sessions on password change", "true"),
+    ("reauthentication max age minutes", "15"),
+    ("deletion grace period days", "30"),
+    ("notify on account deletion", "true"),
+    ("impersonation validity minutes", "15"),
+    ("permission cache seconds", "60"),
+];
+
+#[derive(Default)]
+struct Data {
+    next_id: i32,
+    users: BTreeMap<i32, User>,
+    tokens: Vec<(i32, String, Session)>,
+    resets: Vec<(i32, String, Option<NaiveDateTime>)>,
+    password_change_tokens: Vec<(i32, String, NaiveDateTime)>,
+    //the user id is None once the user is deleted
+    history: Vec<(Option<i32>, LoginRecord)>,
+    //kept when users are deleted, as the Postgres audit table is
+    admin_actions: Vec<AdminAction>,
+    //with their token hashes, and likewise kept
+    impersonations: Vec<(Impersonation, String)>,
+    default_config: HashMap<String, String>,
+    user_config: HashMap<(i32, String), String>,
+    //role names to their permissions
+    roles: BTreeMap<String, BTreeSet<String>>,
+    user_roles: BTreeSet<(i32, String)>,
+    //throttle keys to their consecutive failures, last failure and block
+    throttle: HashMap<String, (i32, NaiveDateTime, Option<NaiveDateTime>)>,
+    ip_rules: BTreeMap<i32, IpRule>,
+    last_rule_id: i32,
+}
+
+/// Called by Store::api_call, see MemoryStore::with_api_hook.
+pub(crate) type ApiHook = Box<dyn Fn(&str, &[&str]) -> Result<(), ApplicationError> + Send + Sync>;
+
+/// A store which keeps everything in memory, and is lost when dropped. Intended for tests.
+pub struct MemoryStore {
+    data: Mutex<Data>,
+    api_hook: Option<ApiHook>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        let default_config = DEFAULT_CONFIG
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        MemoryStore {
+            data: Mutex::new(Data {
+                next_id: 1,
+                default_config,
+                ..Data::default()
+            }),
+            api_hook: None,
+        }
+    }
+
+    /// A store which passes each API call to the hook, which can fail it. Used by the fake in
+    /// pauth::testing.
+    #[cfg(feature = "testing")]
+    pub(crate) fn with_api_hook(hook: ApiHook) -> MemoryStore {
+        MemoryStore {
+            api_hook: Some(hook),
+            ..MemoryStore::new()
+        }
+    }
+
+    fn data(&self) -> MutexGuard<'_, Data> {
+        self.data.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Data {
+    fn conflicts(
+        &self,
+        canonicaliser: &Canonicaliser,
+        name: Option<&str>,
+        email: Option<&str>,
+        except: Option<i32>,
+    ) -> Vec<UserActionFailure> {
+        let others: Vec<&User> = self
+            .users
+            .values()
+            .filter(|u| Some(u.id) != except)
+            .collect();
+        let mut conflicts = Vec::new();
+        if let Some(name) = name {
+            let (key, skeleton) = (canonicaliser.key(name), canonicaliser.skeleton(name));
+            if others.iter().any(|u| {
+                canonicaliser.key(&u.chosen_name) == key
+                    || canonicaliser.key(&u.email) == key
+                    || (canonicaliser.check_confusables
+                        && canonicaliser.skeleton(&u.chosen_name) == skeleton)
+            }) {
+                conflicts.push(UserActionFailure::UsernameExists);
+            }
+        }
+        if let Some(email) = email {
+            let key = canonicaliser.key(email);
+            if others.iter().any(|u| {
+                canonicaliser.key(&u.email) == key || canonicaliser.key(&u.chosen_name) == key
+            }) {
+                conflicts.push(UserActionFailure::EmailExists);
+            }
+        }
+        conflicts
+    }
+
+    /// The error Postgres and SQLite give, so that callers handle every store the same way.
+    fn unique_violation(conflicts: Vec<UserActionFailure>) -> ApplicationError {
+        ApplicationError::Database(Error::DatabaseError(
+            DatabaseErrorKind::UniqueViolation,
+            Box::new(format!("duplicate user: {:?}", conflicts)),
+        ))
+    }
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Store for MemoryStore {
+    fn hash(&self, secret: &str) -> Result<String, ApplicationError> {
+        bcrypt_hash(secret)
+    }
+
+    fn verify(&self, secret: &str, hash: &str) -> Result<bool, ApplicationError> {
+        bcrypt_verify(secret, hash)
+    }
+
+    fn find_users(&self, name_or_email: &str) -> Result<Vec<User>, ApplicationError> {
+        let canonicaliser = Canonicaliser::new(self)?;
+        let key = canonicaliser.key(name_or_email);
+        Ok(self
+            .data()
+            .users
+            .values()
+            .filter(|u| {
+                canonicaliser.key(&u.chosen_name) == key || canonicaliser.key(&u.email) == key
+            })
+            .cloned()
+            .collect())
+    }
+
+    fn user_by_id(&self, uid: i32) -> Result<Option<User>, ApplicationError> {
+        Ok(self.data().users.get(&uid).cloned())
+    }
+
+    fn user_by_name(&self, name: &str) -> Result<Option<User>, ApplicationError> {
+        let canonicaliser = Canonicaliser::new(self)?;
+        let key = canonicaliser.key(name);
+        Ok(self
+            .data()
+            .users
+            .values()
+            .find(|u| canonicaliser.key(&u.chosen_name) == key)
+            .cloned())
+    }
+
+    fn user_by_email(&self, email: &str) -> Result<Option<User>, ApplicationError> {
+        let canonicaliser = Canonicaliser::new(self)?;
+        let key = canonicaliser.key(email);
+        Ok(self
+            .data()
+            .users
+            .values()
+            .find(|u| canonicaliser.key(&u.email) == key)
+            .cloned())
+    }
+
+    fn conflicts(
+        &self,
+        name: Option<&str>,
+        email: Option<&str>,
+        except: Option<i32>,
+    ) -> Result<Vec<UserActionFailure>, ApplicationError> {
+        let canonicaliser = Canonicaliser::new(self)?;
+        Ok(self.data().conflicts(&canonicaliser, name, email, except))
+    }
+
+    fn identifier_collisions(&self) -> Result<Vec<IdentifierCollision>, ApplicationError> {
+        let canonicaliser = Canonicaliser::new(self)?;
+        let data = self.data();
+        let keys: Vec<(i32, String, String)> = data
+            .users
+            .values()
+            .map(|u| {
+                (
+                    u.id,
+                    canonicaliser.key(&u.chosen_name),
+                    canonicaliser.key(&u.email),
+                )
+            })
+            .collect();
+        let mut collisions = Vec::new();
+        for (uid, name, email) in &keys {
+            for (other, other_name, other_email) in &keys {
+                let mut add = |kind| {
+                    collisions.push(IdentifierCollision {
+                        user_id: *uid,
+                        other_user_id: *other,
+                        kind,
+                    })
+                };
+                if uid < other && name == other_name {
+                    add(CollisionKind::Username);
+                }
+                if uid < other && email == other_email {
+                    add(CollisionKind::Email);
+                }
+                if uid != other && name == other_email {
+                    add(CollisionKind::UsernameIsEmail);
+                }
+            }
+        }
+        Ok(collisions)
+    }
+
+    fn rekey_users(&self, _form: &str) -> Result<usize, ApplicationError> {
+        //keys are made from the users as they are needed
+        Ok(0)
+    }
+
+    fn insert_user(
+        &self,
+        name: &str,
+        email: &str,
+        pass_hash: &str,
+    ) -> Result<i32, ApplicationError> {
+        let canonicaliser = Canonicaliser::new(self)?;
+        let mut data = self.data();
+        let conflicts = data.conflicts(&canonicaliser, Some(name), Some(email), None);
+        if !conflicts.is_empty() {
+            return Err(Data::unique_violation(conflicts));
+        }
+        let id = data.next_id;
+        data.next_id += 1;
+        data.users.insert(
+            id,
+            User {
+                id,
+                chosen_name: name.to_owned(),
+                email: email.to_owned(),
+                pass_hash: pass_hash.to_owned(),
+                last_login: Utc::now().naive_utc(),
+                failed_logins: 0,
+                locked_at: None,
+                password_changed_at: Utc::now().naive_utc(),
+                must_change_password: false,
+                temporary_password_expires: None,
+                deleted_at: None,
+                is_admin: false,
+                disabled_at: None,
+                created: Utc::now().naive_utc(),
+            },
+        );
+        Ok(id)
+    }
+
+    fn update_user(&self, uid: i32, changes: &UserUpdate) -> Result<bool, ApplicationError> {
+        let canonicaliser = Canonicaliser::new(self)?;
+        let mut data = self.data();
+        let conflicts = data.conflicts(
+            &canonicaliser,
+            changes.chosen_name(),
+            changes.email(),
+            Some(uid),
+        );
+        if !conflicts.is_empty() {
+            return Err(Data::unique_violation(conflicts));
+        }
+        match data.users.get_mut(&uid) {
+            Some(user) => {
+                changes.apply(user);
+                if changes.pass_hash().is_some() {
+                    data.resets.retain(|(id, _, _)| *id != uid);
+                }
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn delete_user(&self, uid: i32) -> Result<bool, ApplicationError> {
+        let mut data = self.data();
+        if data.users.remove(&uid).is_none() {
+            return Ok(false);
+        }
+        data.tokens.retain(|(id, _, _)| *id != uid);
+        data.resets.retain(|(id, _, _)| *id != uid);
+        data.password_change_tokens.retain(|(id, _, _)| *id != uid);
+        for (id, _) in data.history.iter_mut().filter(|(id, _)| *id == Some(uid)) {
+            *id = None;
+        }
+        data.user_config.retain(|(id, _), _| *id != uid);
+        data.user_roles.retain(|(id, _)| *id != uid);
+        data.ip_rules
+            .retain(|_, rule| rule.scope != RuleScope::User(uid));
+        Ok(true)
+    }
+
+    fn set_deleted(
+        &self,
+        uid: i32,
+        deleted_at: Option<NaiveDateTime>,
+    ) -> Result<bool, ApplicationError> {
+        let mut data = self.data();
+        if deleted_at.is_none() {
+            data.resets.retain(|(id, _, _)| *id != uid);
+        }
+        match data.users.get_mut(&uid) {
+            Some(user) => {
+                user.deleted_at = deleted_at;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn search_users(
+        &self,
+        filter: &UserFilter,
+        page: &PageRequest,
+        limit: i64,
+    ) -> Result<Vec<User>, ApplicationError> {
+        let canonicaliser = Canonicaliser::new(self)?;
+        let data = self.data();
+        let mut users: Vec<(SortKey, &User)> = data
+            .users
+            .values()
+            .filter(|u| filter.matches(u, &canonicaliser))
+            .map(|u| (SortKey::of(page.sort, u, &canonicaliser), u))
+            .collect();
+        users.sort_by(|(a, a_user), (b, b_user)| (a, a_user.id).cmp(&(b, b_user.id)));
+        if page.descending {
+            users.reverse();
+        }
+        let after = page.after.as_ref().map(|after| (&after.key, after.id));
+        Ok(users
+            .into_iter()
+            .filter(|(key, u)| match after {
+                Some(after) if page.descending => (key, u.id) < after,
+                Some(after) => (key, u.id) > after,
+                None => true,
+            })
+            .take(limit as usize)
+            .map(|(_, u)| u.clone())
+            .collect())
+    }
+
+    fn count_users(&self, filter: &UserFilter) -> Result<i64, ApplicationError> {
+        let canonicaliser = Canonicaliser::new(self)?;
+        Ok(self
+            .data()
+            .users
+            .values()
+            .filter(|u| filter.matches(u, &canonicaliser))
+            .count() as i64)
+    }
+
+    fn set_admin(&self, uid: i32, is_admin: bool) -> Result<bool, ApplicationError> {
+        match self.data().users.get_mut(&uid) {
+            Some(user) => {
+                user.is_admin = is_admin;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn set_disabled(
+        &self,
+        uid: i32,
+        disabled_at: Option<NaiveDateTime>,
+    ) -> Result<bool, ApplicationError> {
+        match self.data().users.get_mut(&uid) {
+            Some(user) => {
+                user.disabled_at = disabled_at;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn deleted_users(&self) -> Result<Vec<User>, ApplicationError> {
+        let mut users: Vec<User> = self
+            .data()
+            .users
+            .values()
+            .filter(|u| u.deleted_at.is_some())
+            .cloned()
+            .collect();
+        users.sort_by_key(|u| (u.deleted_at, u.id));
+        Ok(users)
+    }
+
+    fn set_last_login(&self, uid: i32, time: NaiveDateTime) -> Result<(), ApplicationError> {
+        if let Some(user) = self.data().users.get_mut(&uid) {
+            user.last_login = time;
+        }
+        Ok(())
+    }
+
+    fn add_failed_login(&self, uid: i32) -> Result<i32, ApplicationError> {
+        let mut data = self.data();
+        let user = data
+            .users
+            .get_mut(&uid)
+            .ok_or_else(|| ApplicationError::ApplicationDataLogic("No such user".to_owned()))?;
+        user.failed_logins += 1;
+        Ok(user.failed_logins)
+    }
+
+    fn lock_user(&self, uid: i32, time: NaiveDateTime) -> Result<bool, ApplicationError> {
+        match self.data().users.get_mut(&uid) {
+            Some(user) if user.locked_at.is_none() => {
+                user.locked_at = Some(time);
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn unlock_user(&self, uid: i32) -> Result<bool, ApplicationError> {
+        let mut data = self.data();
+        data.resets.retain(|(id, _, _)| *id != uid);
+        match data.users.get_mut(&uid) {
+            Some(user) => {
+                user.locked_at = None;
+                user.failed_logins = 0;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn insert_token(
+        &self,
+        uid: i32,
+        token_hash: &str,
+        strong_auth: Option<NaiveDateTime>,
+    ) -> Result<(), ApplicationError> {
+        let now = Utc::now().naive_utc();
+        let session = Session {
+            created: now,
+            last_used: now,
+            last_strong_auth: strong_auth,
+        };
+        self.data()
+            .tokens
+            .push((uid, token_hash.to_owned(), session));
+        Ok(())
+    }
+
+    fn token_valid(&self, uid: i32, token: &str) -> Result<bool, ApplicationError> {
+        let hashes: Vec<String> = self
+            .data()
+            .tokens
+            .iter()
+            .filter(|(id, _, _)| *id == uid)
+            .map(|(_, hash, _)| hash.clone())
+            .collect();
+        for hash in hashes {
+            if bcrypt_verify(token, &hash)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn strong_auth_time(
+        &self,
+        uid: i32,
+        token: &str,
+    ) -> Result<Option<NaiveDateTime>, ApplicationError> {
+        let data = self.data();
+        for (id, hash, session) in &data.tokens {
+            if *id == uid && bcrypt_verify(token, hash)? {
+                return Ok(session.last_strong_auth);
+            }
+        }
+        Ok(None)
+    }
+
+    fn set_strong_auth_time(
+        &self,
+        uid: i32,
+        token: &str,
+        time: NaiveDateTime,
+    ) -> Result<bool, ApplicationError> {
+        let mut data = self.data();
+        for (id, hash, session) in data.tokens.iter_mut() {
+            if *id == uid && bcrypt_verify(token, hash)? {
+                session.last_strong_auth = Some(time);
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn sessions(&self, uid: i32) -> Result<Vec<Session>, ApplicationError> {
+        Ok(self
+            .data()
+            .tokens
+            .iter()
+            .filter(|(id, _, _)| *id == uid)
+            .map(|(_, _, session)| session.clone())
+            .collect())
+    }
+
+    fn delete_tokens(&self, uid: i32, except: Option<&str>) -> Result<usize, ApplicationError> {
+        let mut data = self.data();
+        let mut ended = Vec::new();
+        for (i, (id, hash, _)) in data.tokens.iter().enumerate() {
+            let kept = match except {
+                Some(token) => bcrypt_verify(token, hash)?,
+                None => false,
+            };
+            if *id == uid && !kept {
+                ended.push(i);
+            }
+        }
+        for i in ended.iter().rev() {
+            data.tokens.remove(*i);
+        }
+        Ok(ended.len())
+    }
+
+    fn insert_reset(
+        &self,
+        uid: i32,
+        token_hash: &str,
+        expires: Option<NaiveDateTime>,
+    ) -> Result<(), ApplicationError> {
+        self.data()
+            .resets
+            .push((uid, token_hash.to_owned(), expires));
+        Ok(())
+    }
+
+    fn take_reset(
+        &self,
+        uid: i32,
+        token: &str,
+        now: NaiveDateTime,
+    ) -> Result<bool, ApplicationError> {
+        let hashes: Vec<String> = self
+            .data()
+            .resets
+            .iter()
+            .filter(|(id, _, expires)| *id == uid && expires.is_none_or(|e| e > now))
+            .map(|(_, hash, _)| hash.clone())
+            .collect();
+        for hash in hashes {
+            if bcrypt_verify(token, &hash)? {
+                //only the caller which removes the reset gets to use it
+                let mut data = self.data();
+                let before = data.resets.len();
+                data.resets.retain(|(id, h, _)| *id != uid || *h != hash);
+                return Ok(data.resets.len() < before);
+            }
+        }
+        Ok(false)
+    }
+
+    fn insert_password_change_token(
+        &self,
+        uid: i32,
+        token_hash: &str,
+        expires: NaiveDateTime,
+    ) -> Result<(), ApplicationError> {
+        self.data()
+            .password_change_tokens
+            .push((uid, token_hash.to_owned(), expires));
+        Ok(())
+    }
+
+    fn password_change_token_valid(
+        &self,
+        uid: i32,
+        token: &str,
+        now: NaiveDateTime,
+    ) -> Result<bool, ApplicationError> {
+        let hashes: Vec<String> = self
+            .data()
+            .password_change_tokens
+            .iter()
+            .filter(|(id, _, expires)| *id == uid && *expires > now)
+            .map(|(_, hash, _)| hash.clone())
+            .collect();
+        for hash in hashes {
+            if bcrypt_verify(token, &hash)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn delete_password_change_tokens(&self, uid: i32) -> Result<(), ApplicationError> {
+        self.data()
+            .password_change_tokens
+            .retain(|(id, _, _)| *id != uid);
+        Ok(())
+    }
+
+    fn record_login(
+        &self,
+        uid: i32,
+        _source: Option<i32>,
+        route: Option<&str>,
+    ) -> Result<(), ApplicationError> {
+        self.data().history.push((
+            Some(uid),
+            LoginRecord {
+                time: Utc::now().naive_utc(),
+                route: route.map(|r| r.to_owned()),
+            },
+        ));
+        Ok(())
+    }
+
+    fn login_history(&self, uid: i32) -> Result<Vec<LoginRecord>, ApplicationError> {
+        Ok(self
+            .data()
+            .history
+            .iter()
+            .filter(|(id, _)| *id == Some(uid))
+            .map(|(_, record)| record.clone())
+            .collect())
+    }
+
+    fn login_count(&self) -> Result<i64, ApplicationError> {
+        Ok(self.data().history.len() as i64)
+    }
+
+    fn record_admin_action(
+        &self,
+        admin_id: i32,
+        uid: Option<i32>,
+        action: &str,
+    ) -> Result<(), ApplicationError> {
+        self.data().admin_actions.push(AdminAction {
+            admin_id,
+            user_id: uid,
+            action: action.to_owned(),
+            time: Utc::now().naive_utc(),
+        });
+        Ok(())
+    }
+
+    fn admin_actions(&self, uid: i32) -> Result<Vec<AdminAction>, ApplicationError> {
+        Ok(self
+            .data()
+            .admin_actions
+            .iter()
+            .filter(|a| a.user_id == Some(uid))
+            .cloned()
+            .collect())
+    }
+
+    fn insert_impersonation(
+        &self,
+        admin_id: i32,
+        uid: i32,
+        token_hash: &str,
+        reason: &str,
+        expires: NaiveDateTime,
+    ) -> Result<(), ApplicationError> {
+        let mut data = self.data();
+        let id = data.impersonations.len() as i32 + 1;
+        data.impersonations.push((
+            Impersonation {
+                id,
+                admin_id,
+                user_id: uid,
+                reason: reason.to_owned(),
+                started: Utc::now().naive_utc(),
+                expires,
+                ended: None,
+            },
+            token_hash.to_owned(),
+        ));
+        Ok(())
+    }
+
+    fn impersonation(
+        &self,
+        uid: i32,
+        token: &str,
+    ) -> Result<Option<Impersonation>, ApplicationError> {
+        let open: Vec<(Impersonation, String)> = self
+            .data()
+            .impersonations
+            .iter()
+            .filter(|(i, _)| i.user_id == uid && i.ended.is_none())
+            .cloned()
+            .collect();
+        for (impersonation, hash) in open {
+            if bcrypt_verify(token, &hash)? {
+                return Ok(Some(impersonation));
+            }
+        }
+        Ok(None)
+    }
+
+    fn expired_impersonations(
+        &self,
+        now: NaiveDateTime,
+    ) -> Result<Vec<Impersonation>, ApplicationError> {
+        Ok(self
+            .data()
+            .impersonations
+            .iter()
+            .map(|(i, _)| i)
+            .filter(|i| i.ended.is_none() && i.expires <= now)
+            .cloned()
+            .collect())
+    }
+
+    fn end_impersonation(&self, id: i32, time: NaiveDateTime) -> Result<bool, ApplicationError> {
+        let mut data = self.data();
+        match data
+            .impersonations
+            .iter_mut()
+            .find(|(i, _)| i.id == id && i.ended.is_none())
+        {
+            Some((impersonation, _)) => {
+                impersonation.ended = Some(time);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn create_role(&self, name: &str) -> Result<bool, ApplicationError> {
+        let mut data = self.data();
+        if data.roles.contains_key(name) {
+            return Ok(false);
+        }
+        data.roles.insert(name.to_owned(), BTreeSet::new());
+        Ok(true)
+    }
+
+    fn delete_role(&self, name: &str) -> Result<bool, ApplicationError> {
+        let mut data = self.data();
+        data.user_roles.retain(|(_, role)| role != name);
+        Ok(data.roles.remove(name).is_some())
+    }
+
+    fn roles(&self) -> Result<Vec<String>, ApplicationError> {
+        Ok(self.data().roles.keys().cloned().collect())
+    }
+
+    fn grant_permission(&self, role: &str, permission: &str) -> Result<bool, ApplicationError> {
+        match self.data().roles.get_mut(role) {
+            Some(permissions) => {
+                permissions.insert(permission.to_owned());
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn revoke_permission(&self, role: &str, permission: &str) -> Result<bool, ApplicationError> {
+        Ok(self
+            .data()
+            .roles
+            .get_mut(role)
+            .is_some_and(|permissions| permissions.remove(permission)))
+    }
+
+    fn role_permissions(&self, role: &str) -> Result<Vec<String>, ApplicationError> {
+        Ok(self
+            .data()
+            .roles
+            .get(role)
+            .map(|permissions| permissions.iter().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    fn grant_role(&self, uid: i32, role: &str) -> Result<bool, ApplicationError> {
+        let mut data = self.data();
+        if !data.users.contains_key(&uid) || !data.roles.contains_key(role) {
+            return Ok(false);
+        }
+        data.user_roles.insert((uid, role.to_owned()));
+        Ok(true)
+    }
+
+    fn revoke_role(&self, uid: i32, role: &str) -> Result<bool, ApplicationError> {
+        Ok(self.data().user_roles.remove(&(uid, role.to_owned())))
+    }
+
+    fn user_roles(&self, uid: i32) -> Result<Vec<String>, ApplicationError> {
+        Ok(self
+            .data()
+            .user_roles
+            .iter()
+            .filter(|(id, _)| *id == uid)
+            .map(|(_, role)| role.clone())
+            .collect())
+    }
+
+    fn user_permissions(&self, uid: i32) -> Result<Vec<String>, ApplicationError> {
+        let data = self.data();
+        let permissions: BTreeSet<&String> = data
+            .user_roles
+            .iter()
+            .filter(|(id, _)| *id == uid)
+            .filter_map(|(_, role)| data.roles.get(role))
+            .flatten()
+            .collect();
+        Ok(permissions.into_iter().cloned().collect())
+    }
+
+    fn throttle_blocked_until(&self, key: &str) -> Result<Option<NaiveDateTime>, ApplicationError> {
+        Ok(self
+            .data()
+            .throttle
+            .get(key)
+            .and_then(|(_, _, blocked_until)| *blocked_until))
+    }
+
+    fn add_throttle_failure(
+        &self,
+        key: &str,
+        now: NaiveDateTime,
+        reset_before: NaiveDateTime,
+    ) -> Result<i32, ApplicationError> {
+        let mut data = self.data();
+        let entry = data
+            .throttle
+            .entry(key.to_owned())
+            .or_insert((0, now, None));
+        if entry.1 < reset_before {
+            entry.0 = 0;
+        }
+        entry.0 += 1;
+        entry.1 = now;
+        Ok(entry.0)
+    }
+
+    fn block_throttle_key(&self, key: &str, until: NaiveDateTime) -> Result<(), ApplicationError> {
+        if let Some(entry) = self.data().throttle.get_mut(key) {
+            entry.2 = Some(until);
+        }
+        Ok(())
+    }
+
+    fn reset_throttle(&self, keys: &[String]) -> Result<(), ApplicationError> {
+        let mut data = self.data();
+        for key in keys {
+            data.throttle.remove(key);
+        }
+        Ok(())
+    }
+
+    fn insert_ip_rule(
+        &self,
+        scope: &RuleScope,
+        network: IpNetwork,
+        allow: bool,
+        note: Option<&str>,
+    ) -> Result<i32, ApplicationError> {
+        let mut data = self.data();
+        if let RuleScope::User(uid) = scope {
+            if !data.users.contains_key(uid) {
+                return Err(ApplicationError::ApplicationDataLogic(format!(
+                    "no user {}",
+                    uid
+                )));
+            }
+        }
+        data.last_rule_id += 1;
+        let id = data.last_rule_id;
+        data.ip_rules.insert(
+            id,
+            IpRule {
+                id,
+                scope: scope.clone(),
+                network,
+                allow,
+                note: note.map(|n| n.to_owned()),
+            },
+        );
+        Ok(id)
+    }
+
+    fn delete_ip_rule(&self, rule_id: i32) -> Result<bool, ApplicationError> {
+        Ok(self.data().ip_rules.remove(&rule_id).is_some())
+    }
+
+    fn ip_rules(&self, scope: &RuleScope) -> Result<Vec<IpRule>, ApplicationError> {
+        Ok(self
+            .data()
+            .ip_rules
+            .values()
+            .filter(|rule| rule.scope == *scope)
+            .cloned()
+            .collect())
+    }
+
+    fn get_config(&self, key: &str, uid: Option<i32>) -> Result<Option<String>, ApplicationError> {
+        let data = self.data();
+        if let Some(value) = uid.and_then(|uid| data.user_config.get(&(uid, key.to_owned()))) {
+            return Ok(Some(value.clone()));
+        }
+        Ok(data.default_config.get(key).cloned())
+    }
+
+    fn set_user_config(&self, uid: i32, key: &str, value: &str) -> Result<(), ApplicationError> {
+        self.data()
+            .user_config
+            .insert((uid, key.to_owned()), value.to_owned());
+        Ok(())
+    }
+
+    fn remove_user_config(&self, uid: i32, key: &str) -> Result<(), ApplicationError> {
+        self.data().user_config.remove(&(uid, key.to_owned()));
+        Ok(())
+    }
+
+    fn user_config(&self, uid: i32) -> Result<Vec<(String, String)>, ApplicationError> {
+        let mut config: Vec<(String, String)> = self
+            .data()
+            .user_config
+            .iter()
+            .filter(|((id, _), _)| *id == uid)
+            .map(|((_, key), value)| (key.clone(), value.clone()))
+            .collect();
+        config.sort();
+        Ok(config)
+    }
+
+    fn api_call(&self, name: &str, args: &[&str]) -> Result<(), ApplicationError> {
+        match &self.api_hook {
+            Some(hook) => hook(name, args),
+            None => Ok(()),
+        }
+    }
+}
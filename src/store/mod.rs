@@ -0,0 +1,1271 @@
+//! Storage backends. By default pauth keeps its data in Postgres (PgStore), but the core data -
+//! users, authentication tokens, password resets, login history and config - can instead be kept
+//! in SQLite (SqliteStore, with the "sqlite" cargo feature) or in memory (MemoryStore), which
+//! suit command line tools, desktop apps and unit tests. The public API is the same whichever
+//! store is used.
+//!
+//! Login throttling and ip address rules work with every store. Attack detection and source
+//! confirmation and familiarity are only available with Postgres. With other stores, logins
+//! aren't checked for attacks or new sources, and logging in fails with an error if the config
+//! asks for them ('attack response' other than 'none', or 'unfamiliar source action' of
+//! 'confirm'), as do the risk module's functions.
+//!
+//! Choose the store with set_store on startup, or with_store to use one on the current thread
+//! only (useful in tests).
+
+/// Filter a boxed query of users joined to their keys by a UserFilter, for the SQL stores. The
+/// prefixes are the LIKE patterns for the canonical name and email keys.
+macro_rules! filter_users {
+    ($query:expr, $filter:expr, $prefixes:expr, $users:ident, $user_key:ident) => {{
+        let filter: &crate::admin::UserFilter = $filter;
+        let (name_pattern, email_pattern): (Option<String>, Option<String>) = $prefixes;
+        let mut query = $query;
+        if let Some(pattern) = name_pattern {
+            query = query.filter($user_key::name_key.like(pattern).escape('\\'));
+        }
+        if let Some(pattern) = email_pattern {
+            query = query.filter($user_key::email_key.like(pattern).escape('\\'));
+        }
+        if let Some(since) = filter.created_since {
+            query = query.filter($users::created.ge(since));
+        }
+        if let Some(before) = filter.created_before {
+            query = query.filter($users::created.lt(before));
+        }
+        if let Some(since) = filter.last_login_since {
+            query = query.filter($users::last_login.ge(since));
+        }
+        if let Some(before) = filter.last_login_before {
+            query = query.filter($users::last_login.lt(before));
+        }
+        match filter.disabled {
+            Some(true) => query = query.filter($users::disabled_at.is_not_null()),
+            Some(false) => query = query.filter($users::disabled_at.is_null()),
+            None => {}
+        }
+        match filter.locked {
+            Some(true) => query = query.filter($users::locked_at.is_not_null()),
+            Some(false) => query = query.filter($users::locked_at.is_null()),
+            None => {}
+        }
+        match filter.pending_deletion {
+            Some(true) => query = query.filter($users::deleted_at.is_not_null()),
+            Some(false) => query = query.filter($users::deleted_at.is_null()),
+            None => {}
+        }
+        if let Some(admin) = filter.admin {
+            query = query.filter($users::is_admin.eq(admin));
+        }
+        query
+    }};
+}
+
+/// Order a boxed query by the column then id, starting after the (key, id) if there is one.
+macro_rules! keyset {
+    ($query:expr, $column:expr, $id:expr, $descending:expr, $after:expr) => {{
+        let query = if $descending {
+            $query.order(($column.desc(), $id.desc()))
+        } else {
+            $query.order(($column.asc(), $id.asc()))
+        };
+        match $after {
+            Some((key, id)) if $descending => {
+                query.filter($column.lt(key.clone()).or($column.eq(key).and($id.lt(id))))
+            }
+            Some((key, id)) => {
+                query.filter($column.gt(key.clone()).or($column.eq(key).and($id.gt(id))))
+            }
+            None => query,
+        }
+    }};
+}
+
+/// Order and start a boxed query of users joined to their keys as the PageRequest says, for the
+/// SQL stores.
+macro_rules! page_users {
+    ($query:expr, $page:expr, $users:ident, $user_key:ident) => {{
+        use crate::admin::UserSort;
+        let page: &crate::admin::PageRequest = $page;
+        let query = $query;
+        let after = page.after.as_ref();
+        match page.sort {
+            UserSort::Id => {
+                let query = if page.descending {
+                    query.order($users::id.desc())
+                } else {
+                    query.order($users::id.asc())
+                };
+                match after {
+                    Some(after) if page.descending => query.filter($users::id.lt(after.id)),
+                    Some(after) => query.filter($users::id.gt(after.id)),
+                    None => query,
+                }
+            }
+            UserSort::Name => keyset!(
+                query,
+                $user_key::name_key,
+                $users::id,
+                page.descending,
+                after.map(|a| (a.key.text(), a.id))
+            ),
+            UserSort::Email => keyset!(
+                query,
+                $user_key::email_key,
+                $users::id,
+                page.descending,
+                after.map(|a| (a.key.text(), a.id))
+            ),
+            UserSort::Created => keyset!(
+                query,
+                $users::created,
+                $users::id,
+                page.descending,
+                after.map(|a| (a.key.time(), a.id))
+            ),
+            UserSort::LastLogin => keyset!(
+                query,
+                $users::last_login,
+                $users::id,
+                page.descending,
+                after.map(|a| (a.key.time(), a.id))
+            ),
+        }
+    }};
+}
+
+mod memory;
+pub(crate) mod postgres;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+
+pub use memory::MemoryStore;
+pub use postgres::PgStore;
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteStore;
+
+use crate::admin::{AdminAction, Impersonation, PageRequest, UserFilter};
+use crate::db::{self, DbConnection};
+use crate::identifier::Canonicaliser;
+use crate::ip_rules::{IpRule, RuleScope};
+use crate::models::{CollisionKind, IdentifierCollision, User, UserActionFailure, UserUpdate};
+use crate::pauth_error::ApplicationError;
+use chrono::NaiveDateTime;
+use ipnetwork::IpNetwork;
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::cell::RefCell;
+use std::sync::{Arc, RwLock};
+
+/// A successful login, as recorded in the login history.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LoginRecord {
+    pub time: NaiveDateTime,
+    pub route: Option<String>,
+}
+
+/// A login token the user holds, such as one for each device they are logged in on.
+#[derive(Queryable, Clone, Debug, PartialEq, Serialize)]
+pub struct Session {
+    pub created: NaiveDateTime,
+    pub last_used: NaiveDateTime,
+    pub last_strong_auth: Option<NaiveDateTime>,
+}
+
+/// Where pauth keeps its data. Passwords and tokens are hashed by the store, so that Postgres
+/// can use pgcrypto and the other stores bcrypt - the hashes are compatible.
+pub trait Store: Send + Sync {
+    fn hash(&self, secret: &str) -> Result<String, ApplicationError>;
+    fn verify(&self, secret: &str, hash: &str) -> Result<bool, ApplicationError>;
+
+    /// Users whose username or email is name_or_email.
+    fn find_users(&self, name_or_email: &str) -> Result<Vec<User>, ApplicationError>;
+    fn user_by_id(&self, uid: i32) -> Result<Option<User>, ApplicationError>;
+    fn user_by_name(&self, name: &str) -> Result<Option<User>, ApplicationError>;
+    fn user_by_email(&self, email: &str) -> Result<Option<User>, ApplicationError>;
+    /// UsernameExists and/or EmailExists if another user (not except) has the name or email,
+    /// compared in canonical form (see the identifier module). A name is also taken if it is
+    /// another user's email or looks like another username, and an email if it is another
+    /// user's username.
+    fn conflicts(
+        &self,
+        name: Option<&str>,
+        email: Option<&str>,
+        except: Option<i32>,
+    ) -> Result<Vec<UserActionFailure>, ApplicationError>;
+    /// Add a user, returning the new id. Fails with a unique violation if the name or email
+    /// conflicts with another user's.
+    fn insert_user(
+        &self,
+        name: &str,
+        email: &str,
+        pass_hash: &str,
+    ) -> Result<i32, ApplicationError>;
+    /// Existing users who can't be told apart, each pair once.
+    fn identifier_collisions(&self) -> Result<Vec<IdentifierCollision>, ApplicationError>;
+    /// Make every user's identifier keys again if the stored ones weren't made in the form (see
+    /// the identifier module). Returns how many users were keyed, which is 0 for stores which
+    /// don't keep keys.
+    fn rekey_users(&self, form: &str) -> Result<usize, ApplicationError>;
+    /// Returns false if there is no such user. Fails with a unique violation as insert_user.
+    fn update_user(&self, uid: i32, changes: &UserUpdate) -> Result<bool, ApplicationError>;
+    /// Delete a user for good, with their tokens, resets and config. Their login history is kept
+    /// without them, where the store keeps it. Returns false if there is no such user.
+    fn delete_user(&self, uid: i32) -> Result<bool, ApplicationError>;
+    /// Mark the user as pending deletion since the time, or restore them with None, which also
+    /// deletes the user's resets (the restore token among them). Returns false if there is no
+    /// such user.
+    fn set_deleted(
+        &self,
+        uid: i32,
+        deleted_at: Option<NaiveDateTime>,
+    ) -> Result<bool, ApplicationError>;
+    /// Users pending deletion, longest pending first.
+    fn deleted_users(&self) -> Result<Vec<User>, ApplicationError>;
+    /// Users matching the filter, ordered and starting as the page says, at most limit of them.
+    fn search_users(
+        &self,
+        filter: &UserFilter,
+        page: &PageRequest,
+        limit: i64,
+    ) -> Result<Vec<User>, ApplicationError>;
+    fn count_users(&self, filter: &UserFilter) -> Result<i64, ApplicationError>;
+    /// Returns false if there is no such user.
+    fn set_admin(&self, uid: i32, is_admin: bool) -> Result<bool, ApplicationError>;
+    /// Disable the user since the time, or enable them with None. Returns false if there is no
+    /// such user.
+    fn set_disabled(
+        &self,
+        uid: i32,
+        disabled_at: Option<NaiveDateTime>,
+    ) -> Result<bool, ApplicationError>;
+    fn set_last_login(&self, uid: i32, time: NaiveDateTime) -> Result<(), ApplicationError>;
+    /// Count a failed login, returning the number of consecutive failures.
+    fn add_failed_login(&self, uid: i32) -> Result<i32, ApplicationError>;
+    /// Lock the account, unless it is already locked. Returns whether this call locked it.
+    fn lock_user(&self, uid: i32, time: NaiveDateTime) -> Result<bool, ApplicationError>;
+    /// Clear any lock and the failed login count. Returns false if there is no such user.
+    fn unlock_user(&self, uid: i32) -> Result<bool, ApplicationError>;
+
+    /// strong_auth is when the user last gave their password for this token, if they did.
+    fn insert_token(
+        &self,
+        uid: i32,
+        token_hash: &str,
+        strong_auth: Option<NaiveDateTime>,
+    ) -> Result<(), ApplicationError>;
+    fn token_valid(&self, uid: i32, token: &str) -> Result<bool, ApplicationError>;
+    /// When the user last gave their password for the token, or None if they never have or
+    /// there is no such token.
+    fn strong_auth_time(
+        &self,
+        uid: i32,
+        token: &str,
+    ) -> Result<Option<NaiveDateTime>, ApplicationError>;
+    /// Returns false if there is no such token.
+    fn set_strong_auth_time(
+        &self,
+        uid: i32,
+        token: &str,
+        time: NaiveDateTime,
+    ) -> Result<bool, ApplicationError>;
+    /// The user's login tokens, oldest first.
+    fn sessions(&self, uid: i32) -> Result<Vec<Session>, ApplicationError>;
+    /// Delete the user's tokens, other than except, returning how many were deleted.
+    fn delete_tokens(&self, uid: i32, except: Option<&str>) -> Result<usize, ApplicationError>;
+
+    fn insert_reset(
+        &self,
+        uid: i32,
+        token_hash: &str,
+        expires: Option<NaiveDateTime>,
+    ) -> Result<(), ApplicationError>;
+    /// Whether the user has a reset with this token which has not expired by now, deleting it
+    /// if so: each reset works once. A password change or unlock deletes all of the user's resets.
+    fn take_reset(
+        &self,
+        uid: i32,
+        token: &str,
+        now: NaiveDateTime,
+    ) -> Result<bool, ApplicationError>;
+
+    /// A token from LoginResult::PasswordChangeRequired, which only allows changing the password.
+    fn insert_password_change_token(
+        &self,
+        uid: i32,
+        token_hash: &str,
+        expires: NaiveDateTime,
+    ) -> Result<(), ApplicationError>;
+    /// Whether the user has a password change token with this token which has not expired by now.
+    fn password_change_token_valid(
+        &self,
+        uid: i32,
+        token: &str,
+        now: NaiveDateTime,
+    ) -> Result<bool, ApplicationError>;
+    fn delete_password_change_tokens(&self, uid: i32) -> Result<(), ApplicationError>;
+
+    /// The source is the id of a row in pauth.source, so is only kept by PgStore.
+    fn record_login(
+        &self,
+        uid: i32,
+        source: Option<i32>,
+        route: Option<&str>,
+    ) -> Result<(), ApplicationError>;
+    /// The user's logins, oldest first.
+    fn login_history(&self, uid: i32) -> Result<Vec<LoginRecord>, ApplicationError>;
+    /// How many logins there are in the history, counting those of deleted users, whose logins
+    /// are kept without them.
+    fn login_count(&self) -> Result<i64, ApplicationError>;
+
+    fn record_admin_action(
+        &self,
+        admin_id: i32,
+        uid: Option<i32>,
+        action: &str,
+    ) -> Result<(), ApplicationError>;
+    /// Admin actions on the user, oldest first.
+    fn admin_actions(&self, uid: i32) -> Result<Vec<AdminAction>, ApplicationError>;
+
+    /// An impersonation starting now.
+    fn insert_impersonation(
+        &self,
+        admin_id: i32,
+        uid: i32,
+        token_hash: &str,
+        reason: &str,
+        expires: NaiveDateTime,
+    ) -> Result<(), ApplicationError>;
+    /// The user's impersonation with this token, unless it has been ended. It may have expired.
+    fn impersonation(
+        &self,
+        uid: i32,
+        token: &str,
+    ) -> Result<Option<Impersonation>, ApplicationError>;
+    /// Impersonations which haven't been ended, but had expired by now, oldest first.
+    fn expired_impersonations(
+        &self,
+        now: NaiveDateTime,
+    ) -> Result<Vec<Impersonation>, ApplicationError>;
+    /// Record that the impersonation ended at the time, unless it already has. Returns whether
+    /// this call ended it.
+    fn end_impersonation(&self, id: i32, time: NaiveDateTime) -> Result<bool, ApplicationError>;
+
+    /// Returns false if there is already a role with the name.
+    fn create_role(&self, name: &str) -> Result<bool, ApplicationError>;
+    /// Returns false if there is no such role.
+    fn delete_role(&self, name: &str) -> Result<bool, ApplicationError>;
+    /// The roles' names, in order.
+    fn roles(&self) -> Result<Vec<String>, ApplicationError>;
+    /// Give the role the permission, adding the permission if it is new. Returns false if there
+    /// is no such role.
+    fn grant_permission(&self, role: &str, permission: &str) -> Result<bool, ApplicationError>;
+    /// Returns false if the role didn't have the permission.
+    fn revoke_permission(&self, role: &str, permission: &str) -> Result<bool, ApplicationError>;
+    /// The role's permissions, in order.
+    fn role_permissions(&self, role: &str) -> Result<Vec<String>, ApplicationError>;
+    /// Returns false if there is no such user or role.
+    fn grant_role(&self, uid: i32, role: &str) -> Result<bool, ApplicationError>;
+    /// Returns false if the user didn't have the role.
+    fn revoke_role(&self, uid: i32, role: &str) -> Result<bool, ApplicationError>;
+    /// The user's roles, in order.
+    fn user_roles(&self, uid: i32) -> Result<Vec<String>, ApplicationError>;
+    /// The permissions of all of the user's roles, in order and without repeats.
+    fn user_permissions(&self, uid: i32) -> Result<Vec<String>, ApplicationError>;
+
+    /// When attempts with the throttle key (see the throttle module) are blocked until, if they
+    /// have been blocked.
+    fn throttle_blocked_until(&self, key: &str) -> Result<Option<NaiveDateTime>, ApplicationError>;
+    /// Count a failed login with the throttle key at now, starting again from 1 if the last was
+    /// before reset_before. Returns the consecutive failures.
+    fn add_throttle_failure(
+        &self,
+        key: &str,
+        now: NaiveDateTime,
+        reset_before: NaiveDateTime,
+    ) -> Result<i32, ApplicationError>;
+    /// Block attempts with the throttle key until the time.
+    fn block_throttle_key(&self, key: &str, until: NaiveDateTime) -> Result<(), ApplicationError>;
+    /// Forget the failures with the throttle keys.
+    fn reset_throttle(&self, keys: &[String]) -> Result<(), ApplicationError>;
+
+    /// Add an ip address rule, returning its id.
+    fn insert_ip_rule(
+        &self,
+        scope: &RuleScope,
+        network: IpNetwork,
+        allow: bool,
+        note: Option<&str>,
+    ) -> Result<i32, ApplicationError>;
+    /// Returns false if there was no rule with the id.
+    fn delete_ip_rule(&self, rule_id: i32) -> Result<bool, ApplicationError>;
+    /// The rules in the scope (not those of other scopes which also apply), in order of id.
+    fn ip_rules(&self, scope: &RuleScope) -> Result<Vec<IpRule>, ApplicationError>;
+
+    /// The user's override for the key if there is one, otherwise the default value (if any).
+    fn get_config(&self, key: &str, uid: Option<i32>) -> Result<Option<String>, ApplicationError>;
+    fn set_user_config(&self, uid: i32, key: &str, value: &str) -> Result<(), ApplicationError>;
+    fn remove_user_config(&self, uid: i32, key: &str) -> Result<(), ApplicationError>;
+    /// The user's overrides, by key.
+    fn user_config(&self, uid: i32) -> Result<Vec<(String, String)>, ApplicationError>;
+
+    /// Whether this is the Postgres store, so that the Postgres only features apply.
+    fn is_postgres(&self) -> bool {
+        false
+    }
+
+    /// Called at the start of each public API function with the function name and the
+    /// identifiers it was passed (never passwords or tokens). An error fails the call. Used by
+    /// the fake in pauth::testing to record and fail calls.
+    fn api_call(&self, _name: &str, _args: &[&str]) -> Result<(), ApplicationError> {
+        Ok(())
+    }
+}
+
+/// A row of the identifier_collision view, for the SQL stores.
+#[derive(QueryableByName)]
+struct CollisionRow {
+    #[sql_type = "diesel::sql_types::Integer"]
+    user_id: i32,
+    #[sql_type = "diesel::sql_types::Integer"]
+    other_user_id: i32,
+    #[sql_type = "diesel::sql_types::Text"]
+    kind: String,
+}
+
+impl CollisionRow {
+    fn into_collision(self) -> IdentifierCollision {
+        IdentifierCollision {
+            user_id: self.user_id,
+            other_user_id: self.other_user_id,
+            kind: match self.kind.as_str() {
+                "username" => CollisionKind::Username,
+                "email" => CollisionKind::Email,
+                _ => CollisionKind::UsernameIsEmail,
+            },
+        }
+    }
+}
+
+/// The LIKE patterns for the canonical keys starting with the filter's prefixes.
+fn prefix_patterns(
+    store: &dyn Store,
+    filter: &UserFilter,
+) -> Result<(Option<String>, Option<String>), ApplicationError> {
+    let canonicaliser = Canonicaliser::new(store)?;
+    let pattern = |prefix: &String| {
+        let key = canonicaliser.key(prefix);
+        let mut pattern = String::with_capacity(key.len() + 1);
+        for c in key.chars() {
+            if matches!(c, '%' | '_' | '\\') {
+                pattern.push('\\');
+            }
+            pattern.push(c);
+        }
+        pattern.push('%');
+        pattern
+    };
+    Ok((
+        filter.name_prefix.as_ref().map(pattern),
+        filter.email_prefix.as_ref().map(pattern),
+    ))
+}
+
+/// bcrypt cost, the same as pgcrypto uses for gen_salt('bf')
+const BCRYPT_COST: u32 = 6;
+
+fn bcrypt_hash(secret: &str) -> Result<String, ApplicationError> {
+    bcrypt::hash(secret, BCRYPT_COST)
+        .map_err(|e| ApplicationError::ApplicationDataLogic(e.to_string()))
+}
+
+fn bcrypt_verify(secret: &str, hash: &str) -> Result<bool, ApplicationError> {
+    //anything which is not a bcrypt hash can't match
+    Ok(bcrypt::verify(secret, hash).unwrap_or(false))
+}
+
+lazy_static! {
+    static ref STORE: RwLock<Arc<dyn Store>> = RwLock::new(Arc::new(PgStore));
+}
+
+thread_local! {
+    static THREAD_STORE: RefCell<Option<Arc<dyn Store>>> = RefCell::new(None);
+}
+
+/// Use the store for all pauth calls, replacing the default Postgres store.
+pub fn set_store(store: Arc<dyn Store>) {
+    *STORE.write().unwrap_or_else(|e| e.into_inner()) = store;
+}
+
+/// Use the store for pauth calls made on this thread while f runs.
+pub fn with_store<T, F: FnOnce() -> T>(store: Arc<dyn Store>, f: F) -> T {
+    struct Restore(Option<Arc<dyn Store>>);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            let previous = self.0.take();
+            THREAD_STORE.with(|s| *s.borrow_mut() = previous);
+        }
+    }
+    let _restore = Restore(THREAD_STORE.with(|s| s.borrow_mut().replace(store)));
+    f()
+}
+
+/// The store in use on this thread.
+pub(crate) fn current() -> Arc<dyn Store> {
+    THREAD_STORE
+        .with(|s| s.borrow().clone())
+        .unwrap_or_else(|| STORE.read().unwrap_or_else(|e| e.into_inner()).clone())
+}
+
+/// A connection for the Postgres only features, if the store is Postgres.
+pub(crate) fn postgres_connection(
+    store: &dyn Store,
+) -> Result<Option<DbConnection>, ApplicationError> {
+    if store.is_postgres() {
+        Ok(Some(db::connection()?))
+    } else {
+        Ok(None)
+    }
+}
+
+/// The error for using a Postgres only feature with another store, rather than quietly going
+/// without it.
+pub(crate) fn needs_postgres(feature: &str) -> ApplicationError {
+    ApplicationError::ApplicationDataLogic(format!("{} needs the Postgres store", feature))
+}
+
+/// A connection for a Postgres only feature, failing with other stores.
+pub(crate) fn require_postgres(
+    store: &dyn Store,
+    feature: &str,
+) -> Result<DbConnection, ApplicationError> {
+    postgres_connection(store)?.ok_or_else(|| needs_postgres(feature))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ip_rules::*;
+    use crate::models::*;
+    use crate::source::Source;
+    use crate::store::*;
+    use crate::test_db::TestDb;
+    use chrono::{Duration, NaiveDate, Utc};
+
+    /// The behaviour every store must have. Names are suffixed so that stores sharing a
+    /// database don't collide.
+    fn conformance(store: &dyn Store, suffix: &str) {
+        let name = format!("store33{}", suffix);
+        let email = format!("store33{}@pr0.co.uk", suffix);
+        let hash = store.hash("pass33").unwrap();
+        assert!(store.verify("pass33", &hash).unwrap());
+        assert!(!store.verify("wrong", &hash).unwrap());
+
+        let uid = store.insert_user(&name, &email, &hash).unwrap();
+        let user = store.user_by_id(uid).unwrap().unwrap();
+        assert_eq!(
+            (name.as_str(), email.as_str()),
+            (&*user.chosen_name, &*user.email)
+        );
+        assert_eq!(0, user.failed_logins);
+        assert_eq!(Some(uid), store.user_by_name(&name).unwrap().map(|u| u.id));
+        assert_eq!(
+            Some(uid),
+            store.user_by_email(&email).unwrap().map(|u| u.id)
+        );
+        assert_eq!(1, store.find_users(&name).unwrap().len());
+        assert_eq!(1, store.find_users(&email).unwrap().len());
+        assert!(store.find_users("nobody33").unwrap().is_empty());
+
+        let new_email = format!("changed33{}@pr0.co.uk", suffix);
+        assert!(store
+            .update_user(uid, UserUpdate::new().with_email(&new_email))
+            .unwrap());
+        assert_eq!(new_email, store.user_by_id(uid).unwrap().unwrap().email);
+        assert!(!store.update_user(-1, &UserUpdate::new()).unwrap());
+
+        let shouted = name.to_uppercase();
+        assert_eq!(
+            vec![UserActionFailure::UsernameExists],
+            store
+                .conflicts(Some(&shouted), Some("free33@pr0.co.uk"), None)
+                .unwrap()
+        );
+        assert!(store
+            .conflicts(Some(&shouted), Some(&new_email.to_uppercase()), Some(uid))
+            .unwrap()
+            .is_empty());
+        //a name can't be someone else's email, or an email someone else's name
+        assert_eq!(
+            vec![UserActionFailure::UsernameExists],
+            store.conflicts(Some(&new_email), None, None).unwrap()
+        );
+        assert_eq!(
+            vec![UserActionFailure::EmailExists],
+            store.conflicts(None, Some(&name), None).unwrap()
+        );
+        assert!(store.identifier_collisions().unwrap().is_empty());
+        assert!(store
+            .insert_user(&shouted, "free33@pr0.co.uk", &hash)
+            .unwrap_err()
+            .is_unique_violation());
+        let other = store
+            .insert_user("other33", "other33@pr0.co.uk", &hash)
+            .unwrap();
+        assert!(store
+            .update_user(other, UserUpdate::new().with_email(&new_email))
+            .unwrap_err()
+            .is_unique_violation());
+        assert!(store.deleted_users().unwrap().is_empty());
+        let deleted_at = NaiveDate::from_ymd_opt(2026, 10, 1)
+            .and_then(|d| d.and_hms_opt(12, 0, 0))
+            .unwrap();
+        assert!(store.set_deleted(other, Some(deleted_at)).unwrap());
+        assert!(!store.set_deleted(-1, Some(deleted_at)).unwrap());
+        let deleted = store.deleted_users().unwrap();
+        assert_eq!(
+            vec![other],
+            deleted.iter().map(|u| u.id).collect::<Vec<_>>()
+        );
+        assert_eq!(Some(deleted_at), deleted[0].deleted_at);
+        assert!(store.set_deleted(other, None).unwrap());
+        assert!(store.deleted_users().unwrap().is_empty());
+        assert!(!store.user_by_id(other).unwrap().unwrap().is_admin);
+        assert!(store.set_admin(other, true).unwrap());
+        assert!(store.user_by_id(other).unwrap().unwrap().is_admin);
+        assert!(!store.set_admin(-1, true).unwrap());
+        assert!(store.set_disabled(other, Some(deleted_at)).unwrap());
+        assert_eq!(
+            Some(deleted_at),
+            store.user_by_id(other).unwrap().unwrap().disabled_at
+        );
+        assert!(store.set_disabled(other, None).unwrap());
+        assert!(!store.set_disabled(-1, None).unwrap());
+        assert!(store.delete_user(other).unwrap());
+
+        assert_eq!(1, store.add_failed_login(uid).unwrap());
+        assert_eq!(2, store.add_failed_login(uid).unwrap());
+        let now = Utc::now().naive_utc();
+        assert!(store.lock_user(uid, now).unwrap());
+        assert!(!store.lock_user(uid, now).unwrap());
+        assert!(store.user_by_id(uid).unwrap().unwrap().locked_at.is_some());
+        assert!(store.unlock_user(uid).unwrap());
+        let user = store.user_by_id(uid).unwrap().unwrap();
+        assert_eq!((0, None), (user.failed_logins, user.locked_at));
+
+        store
+            .insert_token(uid, &store.hash("token33").unwrap(), None)
+            .unwrap();
+        assert!(store.token_valid(uid, "token33").unwrap());
+        assert!(!store.token_valid(uid, "other").unwrap());
+        assert_eq!(None, store.strong_auth_time(uid, "token33").unwrap());
+        let strong = NaiveDate::from_ymd_opt(2026, 10, 19)
+            .and_then(|d| d.and_hms_opt(12, 0, 0))
+            .unwrap();
+        assert!(store.set_strong_auth_time(uid, "token33", strong).unwrap());
+        assert!(!store.set_strong_auth_time(uid, "other", strong).unwrap());
+        assert_eq!(
+            Some(strong),
+            store.strong_auth_time(uid, "token33").unwrap()
+        );
+        assert_eq!(None, store.strong_auth_time(uid, "other").unwrap());
+        let sessions = store.sessions(uid).unwrap();
+        assert_eq!(1, sessions.len());
+        assert_eq!(Some(strong), sessions[0].last_strong_auth);
+        assert!(store.sessions(-1).unwrap().is_empty());
+
+        store
+            .insert_reset(
+                uid,
+                &store.hash("reset33").unwrap(),
+                Some(now + Duration::hours(1)),
+            )
+            .unwrap();
+        store
+            .insert_reset(
+                uid,
+                &store.hash("old33").unwrap(),
+                Some(now - Duration::hours(1)),
+            )
+            .unwrap();
+        store
+            .insert_reset(uid, &store.hash("forever33").unwrap(), None)
+            .unwrap();
+        assert!(store.take_reset(uid, "reset33", now).unwrap());
+        assert!(!store.take_reset(uid, "reset33", now).unwrap());
+        assert!(!store.take_reset(uid, "old33", now).unwrap());
+        assert!(store.take_reset(uid, "forever33", now).unwrap());
+        assert!(!store.take_reset(uid, "forever33", now).unwrap());
+        store
+            .insert_reset(uid, &store.hash("unlocked33").unwrap(), None)
+            .unwrap();
+        assert!(store.unlock_user(uid).unwrap());
+        assert!(!store.take_reset(uid, "unlocked33", now).unwrap());
+        store
+            .insert_reset(uid, &store.hash("restored33").unwrap(), None)
+            .unwrap();
+        assert!(store.set_deleted(uid, None).unwrap());
+        assert!(!store.take_reset(uid, "restored33", now).unwrap());
+
+        store
+            .insert_password_change_token(
+                uid,
+                &store.hash("change41").unwrap(),
+                now + Duration::minutes(30),
+            )
+            .unwrap();
+        store
+            .insert_password_change_token(
+                uid,
+                &store.hash("old41").unwrap(),
+                now - Duration::minutes(1),
+            )
+            .unwrap();
+        assert!(store
+            .password_change_token_valid(uid, "change41", now)
+            .unwrap());
+        assert!(!store
+            .password_change_token_valid(uid, "old41", now)
+            .unwrap());
+        assert!(!store.token_valid(uid, "change41").unwrap());
+        store.delete_password_change_tokens(uid).unwrap();
+        assert!(!store
+            .password_change_token_valid(uid, "change41", now)
+            .unwrap());
+
+        store.record_login(uid, None, Some("cli")).unwrap();
+        let history = store.login_history(uid).unwrap();
+        assert_eq!(1, history.len());
+        assert_eq!(Some("cli".to_owned()), history[0].route);
+
+        assert_eq!(
+            Some("720".to_owned()),
+            store
+                .get_config("password reset validity minutes", Some(uid))
+                .unwrap()
+        );
+        store
+            .set_user_config(uid, "password reset validity minutes", "5")
+            .unwrap();
+        store
+            .set_user_config(uid, "password reset validity minutes", "10")
+            .unwrap();
+        store
+            .set_user_config(uid, "deletion grace period days", "7")
+            .unwrap();
+        assert_eq!(
+            vec![
+                ("deletion grace period days".to_owned(), "7".to_owned()),
+                (
+                    "password reset validity minutes".to_owned(),
+                    "10".to_owned()
+                ),
+            ],
+            store.user_config(uid).unwrap()
+        );
+        assert_eq!(
+            Some("10".to_owned()),
+            store
+                .get_config("password reset validity minutes", Some(uid))
+                .unwrap()
+        );
+        assert_eq!(
+            Some("720".to_owned()),
+            store
+                .get_config("password reset validity minutes", None)
+                .unwrap()
+        );
+        store
+            .remove_user_config(uid, "password reset validity minutes")
+            .unwrap();
+        assert_eq!(
+            Some("720".to_owned()),
+            store
+                .get_config("password reset validity minutes", Some(uid))
+                .unwrap()
+        );
+        assert_eq!(None, store.get_config("no such key", Some(uid)).unwrap());
+
+        store
+            .record_admin_action(42, Some(uid), "issue password reset")
+            .unwrap();
+        let actions = store.admin_actions(uid).unwrap();
+        assert_eq!(1, actions.len());
+        assert_eq!(
+            (42, "issue password reset"),
+            (actions[0].admin_id, &*actions[0].action)
+        );
+
+        let expires = NaiveDate::from_ymd_opt(2030, 1, 1)
+            .and_then(|d| d.and_hms_opt(12, 0, 0))
+            .unwrap();
+        let token_hash = store.hash("impersonation49").unwrap();
+        store
+            .insert_impersonation(42, uid, &token_hash, "ticket 49", expires)
+            .unwrap();
+        let impersonation = store
+            .impersonation(uid, "impersonation49")
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            (42, uid, "ticket 49", expires, None),
+            (
+                impersonation.admin_id,
+                impersonation.user_id,
+                &*impersonation.reason,
+                impersonation.expires,
+                impersonation.ended
+            )
+        );
+        assert_eq!(None, store.impersonation(uid, "wrong").unwrap());
+        assert_eq!(None, store.impersonation(-1, "impersonation49").unwrap());
+        assert!(!store.token_valid(uid, "impersonation49").unwrap());
+        let expired = |now| {
+            store
+                .expired_impersonations(now)
+                .unwrap()
+                .iter()
+                .any(|i| i.id == impersonation.id)
+        };
+        assert!(!expired(expires - Duration::minutes(1)));
+        assert!(expired(expires));
+        assert!(store.end_impersonation(impersonation.id, expires).unwrap());
+        assert!(!store.end_impersonation(impersonation.id, expires).unwrap());
+        assert_eq!(None, store.impersonation(uid, "impersonation49").unwrap());
+        assert!(!expired(expires));
+
+        let role = format!("role50{}", suffix);
+        assert!(store.create_role(&role).unwrap());
+        assert!(!store.create_role(&role).unwrap());
+        assert!(store.roles().unwrap().contains(&role));
+        assert!(store.grant_permission(&role, "write50").unwrap());
+        assert!(store.grant_permission(&role, "read50").unwrap());
+        assert!(store.grant_permission(&role, "read50").unwrap());
+        assert!(!store.grant_permission("no such role", "read50").unwrap());
+        assert_eq!(
+            vec!["read50", "write50"],
+            store.role_permissions(&role).unwrap()
+        );
+        assert!(store.grant_role(uid, &role).unwrap());
+        assert!(store.grant_role(uid, &role).unwrap());
+        assert!(!store.grant_role(-1, &role).unwrap());
+        assert!(!store.grant_role(uid, "no such role").unwrap());
+        assert_eq!(vec![role.clone()], store.user_roles(uid).unwrap());
+        assert_eq!(
+            vec!["read50", "write50"],
+            store.user_permissions(uid).unwrap()
+        );
+        assert!(store.revoke_permission(&role, "write50").unwrap());
+        assert!(!store.revoke_permission(&role, "write50").unwrap());
+        assert_eq!(vec!["read50"], store.user_permissions(uid).unwrap());
+        assert!(store.revoke_role(uid, &role).unwrap());
+        assert!(!store.revoke_role(uid, &role).unwrap());
+        assert!(store.user_permissions(uid).unwrap().is_empty());
+        //deleting a role takes it from its users
+        assert!(store.grant_role(uid, &role).unwrap());
+        assert!(store.delete_role(&role).unwrap());
+        assert!(!store.delete_role(&role).unwrap());
+        assert!(store.user_roles(uid).unwrap().is_empty());
+        assert!(store.create_role(&role).unwrap());
+        assert!(store.grant_role(uid, &role).unwrap());
+
+        assert!(store.delete_user(uid).unwrap());
+        assert!(store.user_by_id(uid).unwrap().is_none());
+        assert_eq!(1, store.admin_actions(uid).unwrap().len());
+        assert!(store.user_roles(uid).unwrap().is_empty());
+        assert!(!store.token_valid(uid, "token33").unwrap());
+        assert!(!store.delete_user(uid).unwrap());
+    }
+
+    /// The public API on top of the store.
+    fn api_conformance(suffix: &str) {
+        let name = format!("api33{}", suffix);
+        let email = format!("api33{}@pr0.co.uk", suffix);
+        let auth_id = match add_user(&name, &email, "pass33").unwrap() {
+            AddUserResult::Added(auth_id) => auth_id,
+            AddUserResult::NotAdded(_) => panic!("Test failure: user not added"),
+        };
+        assert!(matches!(
+            add_user(&name, "other33@pr0.co.uk", "pass33").unwrap(),
+            AddUserResult::NotAdded(_)
+        ));
+        assert!(check_id(&auth_id).unwrap());
+        assert_eq!(
+            LoginResult::AuthenticationFailure,
+            login(&name, "wrong").unwrap()
+        );
+        let logged_in = match login(&email, "pass33").unwrap() {
+            LoginResult::LoggedIn(id) => id,
+            r => panic!("Test failure: login failed, got {:?}", r),
+        };
+        assert!(check_id_and_password(&logged_in, "pass33").unwrap());
+        let token = generate_pw_reset(&name, None).unwrap().unwrap();
+        let reset = match validate_pw_reset(&name, token.clone()).unwrap() {
+            LoginResult::LoggedIn(id) => id,
+            r => panic!("Test failure: reset failed, got {:?}", r),
+        };
+        //a reset works once
+        assert_eq!(
+            LoginResult::AuthenticationFailure,
+            validate_pw_reset(&name, token).unwrap()
+        );
+        let outstanding = generate_pw_reset(&name, None).unwrap().unwrap();
+        let max_age = Duration::minutes(5);
+        assert_eq!(
+            RecentAuthResult::Recent,
+            require_recent_auth(&logged_in, max_age).unwrap()
+        );
+        assert_eq!(
+            RecentAuthResult::ReauthRequired,
+            require_recent_auth(&reset, max_age).unwrap()
+        );
+        assert!(!reauthenticate(&reset, "wrong").unwrap());
+        assert!(reauthenticate(&reset, "pass33").unwrap());
+        assert_eq!(
+            RecentAuthResult::Recent,
+            require_recent_auth(&reset, max_age).unwrap()
+        );
+        //the other sessions, from login and the reset, end
+        assert_eq!(
+            ChangeDetailsResult::PasswordChanged { sessions_ended: 2 },
+            change_details(&auth_id, &UserUpdate::with_password("pass33b").unwrap()).unwrap()
+        );
+        assert!(check_id(&auth_id).unwrap());
+        assert!(!check_id(&logged_in).unwrap());
+        //and so do any resets issued before the change
+        assert_eq!(
+            LoginResult::AuthenticationFailure,
+            validate_pw_reset(&name, outstanding).unwrap()
+        );
+        assert!(matches!(
+            login(&name, "pass33b").unwrap(),
+            LoginResult::LoggedIn(_)
+        ));
+        assert_eq!(email, get_user(&auth_id).unwrap().unwrap().email);
+
+        assert!(require_password_change(auth_id.user_id).unwrap());
+        let limited = match login(&name, "pass33b").unwrap() {
+            LoginResult::PasswordChangeRequired(id) => id,
+            r => panic!("Test failure: password change not required, got {:?}", r),
+        };
+        assert!(!check_id(&limited).unwrap());
+        assert_eq!(
+            ChangeDetailsResult::PasswordChanged { sessions_ended: 2 },
+            change_details(&limited, &UserUpdate::with_password("pass33c").unwrap()).unwrap()
+        );
+        assert!(!check_id(&auth_id).unwrap());
+        let auth_id = match login(&name, "pass33c").unwrap() {
+            LoginResult::LoggedIn(id) => id,
+            r => panic!("Test failure: login failed, got {:?}", r),
+        };
+        assert!(!get_user(&auth_id).unwrap().unwrap().must_change_password);
+        assert!(matches!(
+            delete_user(&auth_id).unwrap(),
+            DeleteUserResult::Deleted
+        ));
+        assert!(!check_id(&auth_id).unwrap());
+        assert_eq!(
+            LoginResult::DeletionPending,
+            login(&name, "pass33c").unwrap()
+        );
+        let auth_id = match restore_account(&name, "pass33c").unwrap() {
+            LoginResult::LoggedIn(id) => id,
+            r => panic!("Test failure: account not restored, got {:?}", r),
+        };
+        assert_eq!(DeleteUserResult::Deleted, delete_user(&auth_id).unwrap());
+        assert_eq!(0, purge_deleted_users().unwrap());
+        let uid = auth_id.user_id;
+        let store = current();
+        let long_ago = Utc::now().naive_utc() - Duration::days(31);
+        assert!(store.set_deleted(uid, Some(long_ago)).unwrap());
+        let logins = store.login_count().unwrap();
+        assert!(!store.login_history(uid).unwrap().is_empty());
+        assert_eq!(1, purge_deleted_users().unwrap());
+        assert_eq!(None, store.user_by_id(uid).unwrap());
+        //the logins are kept without the user
+        assert_eq!(logins, store.login_count().unwrap());
+        assert!(store.login_history(uid).unwrap().is_empty());
+        assert_eq!(
+            LoginResult::AuthenticationFailure,
+            login(&name, "pass33c").unwrap()
+        );
+    }
+
+    /// Searching users with the admin API.
+    fn search_conformance(suffix: &str) {
+        use crate::admin::*;
+        let admin = match add_user(
+            &format!("admin48{}", suffix),
+            &format!("admin48{}@pr0.co.uk", suffix),
+            "pass48",
+        )
+        .unwrap()
+        {
+            AddUserResult::Added(auth_id) => auth_id,
+            r => panic!("Test failure: user not added, got {:?}", r),
+        };
+        let store = current();
+        assert!(set_admin(admin.user_id, true).unwrap());
+        let start = Utc::now().naive_utc() - Duration::days(1);
+        let mut ids = Vec::new();
+        for (i, c) in ["e", "c", "a", "d", "b"].iter().enumerate() {
+            let name = format!("find48{}{}", suffix, c);
+            let email = format!("{}{}@find48.pr0.co.uk", c, suffix);
+            let uid = store
+                .insert_user(&name, &email, &store.hash("pass48").unwrap())
+                .unwrap();
+            store
+                .set_last_login(uid, start + Duration::minutes(i as i64))
+                .unwrap();
+            ids.push(uid);
+        }
+        let done = |result| match result {
+            AdminResult::Done(page) => page,
+            r => panic!("Test failure: search failed, got {:?}", r),
+        };
+        let filter = UserFilter {
+            name_prefix: Some(format!("FIND48{}", suffix)),
+            ..UserFilter::default()
+        };
+        let names = |page: &UserPage| -> Vec<String> {
+            page.users
+                .iter()
+                .map(|u| u.chosen_name[u.chosen_name.len() - 1..].to_owned())
+                .collect()
+        };
+
+        //pages by name, with the cursor passed on as a string
+        let mut page = PageRequest {
+            sort: UserSort::Name,
+            size: 2,
+            ..PageRequest::default()
+        };
+        let mut seen = Vec::new();
+        loop {
+            let found: UserPage = done(search_users(&admin, &filter, &page).unwrap());
+            assert_eq!(5, found.total);
+            seen.extend(names(&found));
+            match found.next {
+                Some(next) => page.after = Some(next.to_string().parse().unwrap()),
+                None => break,
+            }
+        }
+        assert_eq!(vec!["a", "b", "c", "d", "e"], seen);
+
+        let by_login = PageRequest {
+            sort: UserSort::LastLogin,
+            descending: true,
+            size: 3,
+            ..PageRequest::default()
+        };
+        let found = done(search_users(&admin, &filter, &by_login).unwrap());
+        assert_eq!(vec!["b", "d", "a"], names(&found));
+        let found = done(
+            search_users(
+                &admin,
+                &filter,
+                &PageRequest {
+                    after: found.next,
+                    ..by_login
+                },
+            )
+            .unwrap(),
+        );
+        assert_eq!(vec!["c", "e"], names(&found));
+        assert_eq!(None, found.next);
+
+        let found = done(
+            search_users(
+                &admin,
+                &filter,
+                &PageRequest {
+                    descending: true,
+                    ..PageRequest::default()
+                },
+            )
+            .unwrap(),
+        );
+        let mut descending = ids.clone();
+        descending.reverse();
+        assert_eq!(
+            descending,
+            found.users.iter().map(|u| u.id).collect::<Vec<_>>()
+        );
+
+        let count = |filter: UserFilter| {
+            done(search_users(&admin, &filter, &PageRequest::default()).unwrap()).total
+        };
+        let email_filter = UserFilter {
+            email_prefix: Some(format!("a{}@", suffix)),
+            ..UserFilter::default()
+        };
+        assert_eq!(1, count(email_filter));
+        //LIKE wildcards in a prefix are matched as themselves
+        let wildcard = UserFilter {
+            name_prefix: Some("find4_".to_owned()),
+            ..UserFilter::default()
+        };
+        assert_eq!(0, count(wildcard));
+        let since = UserFilter {
+            last_login_since: Some(start + Duration::minutes(3)),
+            ..filter.clone()
+        };
+        assert_eq!(2, count(since));
+        let before = UserFilter {
+            last_login_before: Some(start + Duration::minutes(3)),
+            created_since: Some(start),
+            ..filter.clone()
+        };
+        assert_eq!(3, count(before));
+        let future = UserFilter {
+            created_since: Some(Utc::now().naive_utc() + Duration::hours(1)),
+            ..filter.clone()
+        };
+        assert_eq!(0, count(future));
+
+        let counts = |admin| match user_counts(admin).unwrap() {
+            AdminResult::Done(counts) => counts,
+            r => panic!("Test failure: counts failed, got {:?}", r),
+        };
+        let before_disabling = counts(&admin);
+        assert!(store
+            .set_disabled(ids[0], Some(Utc::now().naive_utc()))
+            .unwrap());
+        let disabled = UserFilter {
+            disabled: Some(true),
+            ..filter.clone()
+        };
+        assert_eq!(1, count(disabled));
+        let admins = UserFilter {
+            admin: Some(true),
+            name_prefix: Some(format!("admin48{}", suffix)),
+            ..UserFilter::default()
+        };
+        assert_eq!(1, count(admins));
+        let after_disabling = counts(&admin);
+        assert_eq!(before_disabling.total, after_disabling.total);
+        assert_eq!(before_disabling.disabled + 1, after_disabling.disabled);
+        assert!(after_disabling.admins >= 1);
+
+        let mismatched = PageRequest {
+            sort: UserSort::Email,
+            after: Some(format!("n.{}.a", ids[0]).parse().unwrap()),
+            ..PageRequest::default()
+        };
+        assert!(search_users(&admin, &filter, &mismatched).is_err());
+        assert!("x.1".parse::<UserCursor>().is_err());
+        assert!("c.1.yesterday".parse::<UserCursor>().is_err());
+        let user = match login(&format!("find48{}a", suffix), "pass48").unwrap() {
+            LoginResult::LoggedIn(id) => id,
+            r => panic!("Test failure: login failed, got {:?}", r),
+        };
+        assert_eq!(
+            AdminResult::NotAuthorised,
+            search_users(&user, &filter, &PageRequest::default()).unwrap()
+        );
+        assert_eq!(AdminResult::NotAuthorised, user_counts(&user).unwrap());
+    }
+
+    /// Throttling and ip address rules, which every store enforces, and config for the Postgres
+    /// only features, which the other stores refuse.
+    fn security_conformance(suffix: &str) {
+        let name = format!("secure33{}", suffix);
+        let email = format!("secure33{}@pr0.co.uk", suffix);
+        let auth_id = match add_user(&name, &email, "pass33").unwrap() {
+            AddUserResult::Added(auth_id) => auth_id,
+            r => panic!("Test failure: user not added, got {:?}", r),
+        };
+        let uid = auth_id.user_id;
+        for _ in 0..3 {
+            assert!(!matches!(
+                login(&name, "wrong").unwrap(),
+                LoginResult::LoggedIn(_)
+            ));
+        }
+        assert!(matches!(
+            login(&name, "pass33").unwrap(),
+            LoginResult::Throttled(_)
+        ));
+
+        let office = Source::from_ip("192.0.2.33".parse().unwrap());
+        let cafe = Source::from_ip("198.51.100.33".parse().unwrap());
+        let rule = add_ip_rule(
+            &RuleScope::User(uid),
+            "192.0.2.1/24".parse().unwrap(),
+            true,
+            Some("office"),
+        )
+        .unwrap();
+        let rules = list_ip_rules(&RuleScope::User(uid)).unwrap();
+        assert_eq!(1, rules.len());
+        assert_eq!(
+            (rule, "192.0.2.0/24".parse().unwrap(), Some("office")),
+            (rules[0].id, rules[0].network, rules[0].note.as_deref())
+        );
+        let domain = RuleScope::EmailDomain("PR0.co.uk".to_owned());
+        let domain_rule =
+            add_ip_rule(&domain, "203.0.113.0/24".parse().unwrap(), false, None).unwrap();
+        assert_eq!(1, list_ip_rules(&domain).unwrap().len());
+        assert_eq!(
+            LoginResult::SourceNotAllowed,
+            login_from(&email, "pass33", &cafe, None).unwrap().result
+        );
+        assert!(matches!(
+            login_from(&email, "pass33", &office, None).unwrap().result,
+            LoginResult::LoggedIn(_)
+        ));
+        assert_eq!(
+            IdCheckResult::SourceNotAllowed,
+            check_id_from(&auth_id, &cafe).unwrap()
+        );
+        assert!(remove_ip_rule(rule).unwrap());
+        assert!(!remove_ip_rule(rule).unwrap());
+        assert_eq!(
+            IdCheckResult::Valid,
+            check_id_from(&auth_id, &cafe).unwrap()
+        );
+        assert_eq!(
+            IdCheckResult::SourceNotAllowed,
+            check_id_from(&auth_id, &Source::from_ip("203.0.113.33".parse().unwrap())).unwrap()
+        );
+        assert!(remove_ip_rule(domain_rule).unwrap());
+
+        crate::set_user_config(uid, "unfamiliar source action", "confirm").unwrap();
+        let confirming = login_from(&email, "pass33", &office, None);
+        if current().is_postgres() {
+            assert!(confirming.is_ok());
+        } else {
+            assert!(confirming.is_err());
+        }
+    }
+
+    #[test]
+    fn postgres_store() {
+        let _db = TestDb::new();
+        conformance(&PgStore, "pg");
+        api_conformance("pg");
+        search_conformance("pg");
+        security_conformance("pg");
+    }
+
+    #[test]
+    fn memory_store() {
+        let store = Arc::new(MemoryStore::new());
+        conformance(&*store, "mem");
+        with_store(store, || {
+            api_conformance("mem");
+            search_conformance("mem");
+            security_conformance("mem");
+        });
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn sqlite_store() {
+        let store = Arc::new(SqliteStore::open(":memory:").unwrap());
+        conformance(&*store, "lite");
+        with_store(store, || {
+            api_conformance("lite");
+            search_conformance("lite");
+            security_conformance("lite");
+        });
+    }
+}
@@ -0,0 +1,1092 @@
+use super::{prefix_patterns, CollisionRow, LoginRecord, Session, Store};
+use crate::admin::{AdminAction, Impersonation, PageRequest, UserFilter};
+use crate::db;
+use crate::identifier::{Canonicaliser, UserKeys};
+use crate::ip_rules::{IpRule, RuleScope};
+use crate::models::{IdentifierCollision, User, UserActionFailure, UserUpdate};
+use crate::pauth_error::ApplicationError;
+use crate::schema::pauth::{
+    admin_audit, config, default_config, impersonation, ip_rule, login_history, login_throttle,
+    password_change_token, permission, pw_reset, role, role_permission, user_config, user_history,
+    user_key, user_key_form, user_login_tokens, user_role, users,
+};
+use chrono::NaiveDateTime;
+use diesel::dsl::exists;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::select;
+use diesel::sql_types::{Integer, Text, Timestamp};
+use ipnetwork::IpNetwork;
+
+sql_function! {
+    #[sql_name="pauth.crypt"]
+    fn crypt(pw: Text, salt: Text) -> Text;
+}
+
+sql_function! {
+    #[sql_name="pauth.gen_salt"]
+    fn gen_salt(type_: Text) -> Text;
+}
+
+#[derive(QueryableByName)]
+struct FailureCount {
+    #[sql_type = "Integer"]
+    failures: i32,
+}
+
+#[derive(Queryable)]
+struct IpRuleRow {
+    id: i32,
+    user_id: Option<i32>,
+    email_domain: Option<String>,
+    network: IpNetwork,
+    allow: bool,
+    note: Option<String>,
+}
+
+impl From<IpRuleRow> for IpRule {
+    fn from(row: IpRuleRow) -> IpRule {
+        IpRule {
+            id: row.id,
+            scope: RuleScope::from_columns(row.user_id, row.email_domain),
+            network: row.network,
+            allow: row.allow,
+            note: row.note,
+        }
+    }
+}
+
+/// The default store, using the database at DATABASE_URL. Hashing is done by pgcrypto.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PgStore;
+
+impl PgStore {
+    /// Fill in the identifier keys the migrations couldn't compute: name skeletons, keys for
+    /// users added other than through pauth, and every user's keys if they were made in another
+    /// form. Returns how many users were keyed.
+    pub(crate) fn fill_keys(&self) -> Result<usize, ApplicationError> {
+        let canonicaliser = Canonicaliser::configured(self)?;
+        let form = canonicaliser.form();
+        let conn = db::connection()?;
+        conn.transaction::<_, ApplicationError, _>(|| {
+            //one process keys users at a time, and no users are added or renamed meanwhile
+            conn.execute("lock table pauth.user_key in share row exclusive mode")?;
+            let current = user_key_form::table
+                .select(user_key_form::form)
+                .first::<String>(&conn)
+                .optional()?;
+            let query = users::table
+                .left_join(user_key::table)
+                .select((users::id, users::chosen_name, users::email))
+                .order(users::id)
+                .into_boxed();
+            let unkeyed = if current.as_ref() == Some(&form) {
+                query
+                    .filter(user_key::name_skeleton.is_null())
+                    .load::<(i32, String, String)>(&conn)?
+            } else {
+                diesel::delete(user_key::table).execute(&conn)?;
+                diesel::delete(user_key_form::table).execute(&conn)?;
+                diesel::insert_into(user_key_form::table)
+                    .values(user_key_form::form.eq(&form))
+                    .execute(&conn)?;
+                query.load::<(i32, String, String)>(&conn)?
+            };
+            for (uid, name, email) in &unkeyed {
+                let keys = canonicaliser.user_keys(name, email);
+                //users whose key is already taken keep it, as a collision
+                let name_collision = select(exists(
+                    user_key::table
+                        .filter(user_key::name_key.eq(&keys.name_key))
+                        .filter(user_key::name_collision.eq(false)),
+                ))
+                .get_result::<bool>(&conn)?;
+                let email_collision = select(exists(
+                    user_key::table
+                        .filter(user_key::email_key.eq(&keys.email_key))
+                        .filter(user_key::email_collision.eq(false)),
+                ))
+                .get_result::<bool>(&conn)?;
+                write_keys(&conn, *uid, &keys, name_collision, email_collision)?;
+            }
+            Ok(unkeyed.len())
+        })
+    }
+}
+
+/// Store the user's keys. A collision (see fill_keys) is kept until the key changes.
+fn save_keys(conn: &PgConnection, uid: i32, keys: &UserKeys) -> QueryResult<usize> {
+    let (name_collision, email_collision) = user_key::table
+        .find(uid)
+        .select((
+            user_key::name_key,
+            user_key::email_key,
+            user_key::name_collision,
+            user_key::email_collision,
+        ))
+        .first::<(String, String, bool, bool)>(conn)
+        .optional()?
+        .map_or((false, false), |(name_key, email_key, name, email)| {
+            (
+                name && name_key == keys.name_key,
+                email && email_key == keys.email_key,
+            )
+        });
+    write_keys(conn, uid, keys, name_collision, email_collision)
+}
+
+fn write_keys(
+    conn: &PgConnection,
+    uid: i32,
+    keys: &UserKeys,
+    name_collision: bool,
+    email_collision: bool,
+) -> QueryResult<usize> {
+    let values = (
+        user_key::name_key.eq(&keys.name_key),
+        user_key::email_key.eq(&keys.email_key),
+        user_key::name_skeleton.eq(&keys.name_skeleton),
+        user_key::name_collision.eq(name_collision),
+        user_key::email_collision.eq(email_collision),
+    );
+    diesel::insert_into(user_key::table)
+        .values((user_key::user_id.eq(uid), values))
+        .on_conflict(user_key::user_id)
+        .do_update()
+        .set(values)
+        .execute(conn)
+}
+
+impl Store for PgStore {
+    fn hash(&self, secret: &str) -> Result<String, ApplicationError> {
+        let conn = db::connection()?;
+        Ok(select(crypt(secret, gen_salt("bf"))).first::<String>(&conn)?)
+    }
+
+    fn verify(&self, secret: &str, hash: &str) -> Result<bool, ApplicationError> {
+        let conn = db::connection()?;
+        Ok(select(crypt(secret, hash).eq(hash)).first::<bool>(&conn)?)
+    }
+
+    fn find_users(&self, name_or_email: &str) -> Result<Vec<User>, ApplicationError> {
+        let key = Canonicaliser::new(self)?.key(name_or_email);
+        let conn = db::connection()?;
+        Ok(users::table
+            .inner_join(user_key::table)
+            .select(users::all_columns)
+            .filter(user_key::email_key.eq(&key).or(user_key::name_key.eq(&key)))
+            .order(users::id)
+            .load::<User>(&conn)?)
+    }
+
+    fn user_by_id(&self, uid: i32) -> Result<Option<User>, ApplicationError> {
+        let conn = db::connection()?;
+        Ok(users::table.find(uid).first::<User>(&conn).optional()?)
+    }
+
+    fn user_by_name(&self, name: &str) -> Result<Option<User>, ApplicationError> {
+        let key = Canonicaliser::new(self)?.key(name);
+        let conn = db::connection()?;
+        Ok(users::table
+            .inner_join(user_key::table)
+            .select(users::all_columns)
+            .filter(user_key::name_key.eq(key))
+            .first::<User>(&conn)
+            .optional()?)
+    }
+
+    fn user_by_email(&self, email: &str) -> Result<Option<User>, ApplicationError> {
+        let key = Canonicaliser::new(self)?.key(email);
+        let conn = db::connection()?;
+        Ok(users::table
+            .inner_join(user_key::table)
+            .select(users::all_columns)
+            .filter(user_key::email_key.eq(key))
+            .first::<User>(&conn)
+            .optional()?)
+    }
+
+    fn conflicts(
+        &self,
+        name: Option<&str>,
+        email: Option<&str>,
+        except: Option<i32>,
+    ) -> Result<Vec<UserActionFailure>, ApplicationError> {
+        let canonicaliser = Canonicaliser::new(self)?;
+        let conn = db::connection()?;
+        let others = || {
+            let query = user_key::table.select(user_key::user_id).into_boxed();
+            match except {
+                Some(uid) => query.filter(user_key::user_id.ne(uid)),
+                None => query,
+            }
+        };
+        let mut conflicts = Vec::new();
+        if let Some(name) = name {
+            let key = canonicaliser.key(name);
+            let taken = select(exists(
+                others().filter(user_key::name_key.eq(&key).or(user_key::email_key.eq(&key))),
+            ))
+            .get_result::<bool>(&conn)?;
+            let looks_taken = canonicaliser.check_confusables
+                && select(exists(
+                    others().filter(user_key::name_skeleton.eq(canonicaliser.skeleton(name))),
+                ))
+                .get_result::<bool>(&conn)?;
+            if taken || looks_taken {
+                conflicts.push(UserActionFailure::UsernameExists);
+            }
+        }
+        if let Some(email) = email {
+            let key = canonicaliser.key(email);
+            if select(exists(others().filter(
+                user_key::email_key.eq(&key).or(user_key::name_key.eq(&key)),
+            )))
+            .get_result::<bool>(&conn)?
+            {
+                conflicts.push(UserActionFailure::EmailExists);
+            }
+        }
+        Ok(conflicts)
+    }
+
+    fn identifier_collisions(&self) -> Result<Vec<IdentifierCollision>, ApplicationError> {
+        //rekeying first if the config has changed
+        Canonicaliser::new(self)?;
+        let conn = db::connection()?;
+        Ok(diesel::sql_query(
+            "select user_id, other_user_id, kind from pauth.identifier_collision \
+                order by user_id, other_user_id, kind",
+        )
+        .load::<CollisionRow>(&conn)?
+        .into_iter()
+        .map(CollisionRow::into_collision)
+        .collect())
+    }
+
+    fn rekey_users(&self, form: &str) -> Result<usize, ApplicationError> {
+        let current = user_key_form::table
+            .select(user_key_form::form)
+            .first::<String>(&db::connection()?)
+            .optional()?;
+        if current.as_deref() == Some(form) {
+            Ok(0)
+        } else {
+            self.fill_keys()
+        }
+    }
+
+    fn insert_user(
+        &self,
+        name: &str,
+        email: &str,
+        pass_hash: &str,
+    ) -> Result<i32, ApplicationError> {
+        let keys = Canonicaliser::new(self)?.user_keys(name, email);
+        let conn = db::connection()?;
+        conn.transaction::<_, ApplicationError, _>(|| {
+            let uid = diesel::insert_into(users::table)
+                .values((
+                    users::chosen_name.eq(name),
+                    users::email.eq(email),
+                    users::pass_hash.eq(pass_hash),
+                ))
+                .returning(users::id)
+                .get_result::<i32>(&conn)?;
+            save_keys(&conn, uid, &keys)?;
+            Ok(uid)
+        })
+    }
+
+    fn update_user(&self, uid: i32, changes: &UserUpdate) -> Result<bool, ApplicationError> {
+        if changes.is_empty() {
+            return Ok(self.user_by_id(uid)?.is_some());
+        }
+        let canonicaliser = Canonicaliser::new(self)?;
+        let conn = db::connection()?;
+        conn.transaction::<_, ApplicationError, _>(|| {
+            let user = diesel::update(users::table.find(uid))
+                .set(changes.changeset())
+                .get_result::<User>(&conn)
+                .optional()?;
+            match user {
+                Some(user) => {
+                    if changes.chosen_name().is_some() || changes.email().is_some() {
+                        let keys = canonicaliser.user_keys(&user.chosen_name, &user.email);
+                        save_keys(&conn, uid, &keys)?;
+                    }
+                    if changes.pass_hash().is_some() {
+                        diesel::delete(pw_reset::table.filter(pw_reset::user_id.eq(uid)))
+                            .execute(&conn)?;
+                    }
+                    Ok(true)
+                }
+                None => Ok(false),
+            }
+        })
+    }
+
+    fn delete_user(&self, uid: i32) -> Result<bool, ApplicationError> {
+        let conn = db::connection()?;
+        conn.transaction::<_, ApplicationError, _>(|| {
+            //the history's user_id is set to null by the foreign key, and the old details go too
+            diesel::update(user_history::table.filter(user_history::user_id.eq(uid)))
+                .set((
+                    user_history::old_chosen_name.eq(None::<String>),
+                    user_history::old_email.eq(None::<String>),
+                    user_history::old_pass.eq(None::<String>),
+                ))
+                .execute(&conn)?;
+            //the overrides' config rows aren't linked to the user, so would be left behind
+            let config_ids: Vec<i32> = user_config::table
+                .select(user_config::config_id)
+                .filter(user_config::user_id.eq(uid))
+                .load::<Option<i32>>(&conn)?
+                .into_iter()
+                .flatten()
+                .collect();
+            diesel::delete(user_config::table.filter(user_config::user_id.eq(uid)))
+                .execute(&conn)?;
+            diesel::delete(config::table.filter(config::id.eq_any(&config_ids))).execute(&conn)?;
+            Ok(diesel::delete(users::table.find(uid)).execute(&conn)? > 0)
+        })
+    }
+
+    fn set_deleted(
+        &self,
+        uid: i32,
+        deleted_at: Option<NaiveDateTime>,
+    ) -> Result<bool, ApplicationError> {
+        let conn = db::connection()?;
+        conn.transaction::<_, ApplicationError, _>(|| {
+            if deleted_at.is_none() {
+                diesel::delete(pw_reset::table.filter(pw_reset::user_id.eq(uid))).execute(&conn)?;
+            }
+            Ok(diesel::update(users::table.find(uid))
+                .set(users::deleted_at.eq(deleted_at))
+                .execute(&conn)?
+                > 0)
+        })
+    }
+
+    fn search_users(
+        &self,
+        filter: &UserFilter,
+        page: &PageRequest,
+        limit: i64,
+    ) -> Result<Vec<User>, ApplicationError> {
+        let prefixes = prefix_patterns(self, filter)?;
+        let conn = db::connection()?;
+        let query = users::table
+            .inner_join(user_key::table)
+            .select(users::all_columns)
+            .into_boxed();
+        let query = filter_users!(query, filter, prefixes, users, user_key);
+        Ok(page_users!(query, page, users, user_key)
+            .limit(limit)
+            .load::<User>(&conn)?)
+    }
+
+    fn count_users(&self, filter: &UserFilter) -> Result<i64, ApplicationError> {
+        let prefixes = prefix_patterns(self, filter)?;
+        let conn = db::connection()?;
+        let query = users::table
+            .inner_join(user_key::table)
+            .select(diesel::dsl::count_star())
+            .into_boxed();
+        Ok(filter_users!(query, filter, prefixes, users, user_key).get_result::<i64>(&conn)?)
+    }
+
+    fn set_admin(&self, uid: i32, is_admin: bool) -> Result<bool, ApplicationError> {
+        let conn = db::connection()?;
+        Ok(diesel::update(users::table.find(uid))
+            .set(users::is_admin.eq(is_admin))
+            .execute(&conn)?
+            > 0)
+    }
+
+    fn set_disabled(
+        &self,
+        uid: i32,
+        disabled_at: Option<NaiveDateTime>,
+    ) -> Result<bool, ApplicationError> {
+        let conn = db::connection()?;
+        Ok(diesel::update(users::table.find(uid))
+            .set(users::disabled_at.eq(disabled_at))
+            .execute(&conn)?
+            > 0)
+    }
+
+    fn deleted_users(&self) -> Result<Vec<User>, ApplicationError> {
+        let conn = db::connection()?;
+        Ok(users::table
+            .filter(users::deleted_at.is_not_null())
+            .order((users::deleted_at, users::id))
+            .load::<User>(&conn)?)
+    }
+
+    fn set_last_login(&self, uid: i32, time: NaiveDateTime) -> Result<(), ApplicationError> {
+        let conn = db::connection()?;
+        diesel::update(users::table.find(uid))
+            .set(users::last_login.eq(time))
+            .execute(&conn)?;
+        Ok(())
+    }
+
+    fn add_failed_login(&self, uid: i32) -> Result<i32, ApplicationError> {
+        let conn = db::connection()?;
+        Ok(diesel::update(users::table.find(uid))
+            .set(users::failed_logins.eq(users::failed_logins + 1))
+            .returning(users::failed_logins)
+            .get_result::<i32>(&conn)?)
+    }
+
+    fn lock_user(&self, uid: i32, time: NaiveDateTime) -> Result<bool, ApplicationError> {
+        let conn = db::connection()?;
+        Ok(
+            diesel::update(users::table.find(uid).filter(users::locked_at.is_null()))
+                .set(users::locked_at.eq(time))
+                .execute(&conn)?
+                > 0,
+        )
+    }
+
+    fn unlock_user(&self, uid: i32) -> Result<bool, ApplicationError> {
+        let conn = db::connection()?;
+        conn.transaction::<_, ApplicationError, _>(|| {
+            diesel::delete(pw_reset::table.filter(pw_reset::user_id.eq(uid))).execute(&conn)?;
+            Ok(diesel::update(users::table.find(uid))
+                .set((
+                    users::locked_at.eq(None::<NaiveDateTime>),
+                    users::failed_logins.eq(0),
+                ))
+                .execute(&conn)?
+                > 0)
+        })
+    }
+
+    fn insert_token(
+        &self,
+        uid: i32,
+        token_hash: &str,
+        strong_auth: Option<NaiveDateTime>,
+    ) -> Result<(), ApplicationError> {
+        let conn = db::connection()?;
+        diesel::insert_into(user_login_tokens::table)
+            .values((
+                user_login_tokens::user_id.eq(uid),
+                user_login_tokens::token.eq(token_hash),
+                user_login_tokens::last_strong_auth.eq(strong_auth),
+            ))
+            .execute(&conn)?;
+        Ok(())
+    }
+
+    fn token_valid(&self, uid: i32, token: &str) -> Result<bool, ApplicationError> {
+        let conn = db::connection()?;
+        Ok(select(exists(
+            user_login_tokens::table
+                .filter(user_login_tokens::user_id.eq(uid))
+                .filter(user_login_tokens::token.eq(crypt(token, user_login_tokens::token))),
+        ))
+        .get_result(&conn)?)
+    }
+
+    fn strong_auth_time(
+        &self,
+        uid: i32,
+        token: &str,
+    ) -> Result<Option<NaiveDateTime>, ApplicationError> {
+        let conn = db::connection()?;
+        Ok(user_login_tokens::table
+            .select(user_login_tokens::last_strong_auth)
+            .filter(user_login_tokens::user_id.eq(uid))
+            .filter(user_login_tokens::token.eq(crypt(token, user_login_tokens::token)))
+            .first::<Option<NaiveDateTime>>(&conn)
+            .optional()?
+            .flatten())
+    }
+
+    fn set_strong_auth_time(
+        &self,
+        uid: i32,
+        token: &str,
+        time: NaiveDateTime,
+    ) -> Result<bool, ApplicationError> {
+        let conn = db::connection()?;
+        Ok(diesel::update(
+            user_login_tokens::table
+                .filter(user_login_tokens::user_id.eq(uid))
+                .filter(user_login_tokens::token.eq(crypt(token, user_login_tokens::token))),
+        )
+        .set(user_login_tokens::last_strong_auth.eq(time))
+        .execute(&conn)?
+            > 0)
+    }
+
+    fn sessions(&self, uid: i32) -> Result<Vec<Session>, ApplicationError> {
+        let conn = db::connection()?;
+        Ok(user_login_tokens::table
+            .select((
+                user_login_tokens::created,
+                user_login_tokens::last_used,
+                user_login_tokens::last_strong_auth,
+            ))
+            .filter(user_login_tokens::user_id.eq(uid))
+            .order(user_login_tokens::id)
+            .load::<Session>(&conn)?)
+    }
+
+    fn delete_tokens(&self, uid: i32, except: Option<&str>) -> Result<usize, ApplicationError> {
+        let conn = db::connection()?;
+        let tokens = user_login_tokens::table.filter(user_login_tokens::user_id.eq(uid));
+        Ok(match except {
+            Some(token) => diesel::delete(
+                tokens.filter(user_login_tokens::token.ne(crypt(token, user_login_tokens::token))),
+            )
+            .execute(&conn)?,
+            None => diesel::delete(tokens).execute(&conn)?,
+        })
+    }
+
+    fn insert_reset(
+        &self,
+        uid: i32,
+        token_hash: &str,
+        expires: Option<NaiveDateTime>,
+    ) -> Result<(), ApplicationError> {
+        let conn = db::connection()?;
+        diesel::insert_into(pw_reset::table)
+            .values((
+                pw_reset::user_id.eq(uid),
+                pw_reset::user_token_hash.eq(token_hash),
+                pw_reset::expires.eq(expires),
+            ))
+            .execute(&conn)?;
+        Ok(())
+    }
+
+    fn take_reset(
+        &self,
+        uid: i32,
+        token: &str,
+        now: NaiveDateTime,
+    ) -> Result<bool, ApplicationError> {
+        let conn = db::connection()?;
+        Ok(diesel::delete(
+            pw_reset::table
+                .filter(pw_reset::user_id.eq(uid))
+                .filter(pw_reset::user_token_hash.eq(crypt(token, pw_reset::user_token_hash)))
+                .filter(pw_reset::expires.is_null().or(pw_reset::expires.gt(now))),
+        )
+        .execute(&conn)?
+            > 0)
+    }
+
+    fn insert_password_change_token(
+        &self,
+        uid: i32,
+        token_hash: &str,
+        expires: NaiveDateTime,
+    ) -> Result<(), ApplicationError> {
+        let conn = db::connection()?;
+        diesel::insert_into(password_change_token::table)
+            .values((
+                password_change_token::user_id.eq(uid),
+                password_change_token::token_hash.eq(token_hash),
+                password_change_token::expires.eq(expires),
+            ))
+            .execute(&conn)?;
+        Ok(())
+    }
+
+    fn password_change_token_valid(
+        &self,
+        uid: i32,
+        token: &str,
+        now: NaiveDateTime,
+    ) -> Result<bool, ApplicationError> {
+        let conn = db::connection()?;
+        Ok(select(exists(
+            password_change_token::table
+                .filter(password_change_token::user_id.eq(uid))
+                .filter(
+                    password_change_token::token_hash
+                        .eq(crypt(token, password_change_token::token_hash)),
+                )
+                .filter(password_change_token::expires.gt(now)),
+        ))
+        .get_result(&conn)?)
+    }
+
+    fn delete_password_change_tokens(&self, uid: i32) -> Result<(), ApplicationError> {
+        let conn = db::connection()?;
+        diesel::delete(password_change_token::table.filter(password_change_token::user_id.eq(uid)))
+            .execute(&conn)?;
+        Ok(())
+    }
+
+    fn record_login(
+        &self,
+        uid: i32,
+        source: Option<i32>,
+        route: Option<&str>,
+    ) -> Result<(), ApplicationError> {
+        let conn = db::connection()?;
+        diesel::insert_into(login_history::table)
+            .values((
+                login_history::user_id.eq(uid),
+                login_history::source.eq(source),
+                login_history::route.eq(route),
+            ))
+            .execute(&conn)?;
+        Ok(())
+    }
+
+    fn login_history(&self, uid: i32) -> Result<Vec<LoginRecord>, ApplicationError> {
+        let conn = db::connection()?;
+        Ok(login_history::table
+            .select((login_history::login_time, login_history::route))
+            .filter(login_history::user_id.eq(uid))
+            .order(login_history::id)
+            .load::<(Option<NaiveDateTime>, Option<String>)>(&conn)?
+            .into_iter()
+            .filter_map(|(time, route)| time.map(|time| LoginRecord { time, route }))
+            .collect())
+    }
+
+    fn login_count(&self) -> Result<i64, ApplicationError> {
+        let conn = db::connection()?;
+        Ok(login_history::table.count().get_result(&conn)?)
+    }
+
+    fn record_admin_action(
+        &self,
+        admin_id: i32,
+        uid: Option<i32>,
+        action: &str,
+    ) -> Result<(), ApplicationError> {
+        let conn = db::connection()?;
+        diesel::insert_into(admin_audit::table)
+            .values((
+                admin_audit::admin_id.eq(admin_id),
+                admin_audit::user_id.eq(uid),
+                admin_audit::action.eq(action),
+            ))
+            .execute(&conn)?;
+        Ok(())
+    }
+
+    fn admin_actions(&self, uid: i32) -> Result<Vec<AdminAction>, ApplicationError> {
+        let conn = db::connection()?;
+        Ok(admin_audit::table
+            .select((
+                admin_audit::admin_id,
+                admin_audit::user_id,
+                admin_audit::action,
+                admin_audit::action_time,
+            ))
+            .filter(admin_audit::user_id.eq(uid))
+            .order(admin_audit::id)
+            .load::<AdminAction>(&conn)?)
+    }
+
+    fn insert_impersonation(
+        &self,
+        admin_id: i32,
+        uid: i32,
+        token_hash: &str,
+        reason: &str,
+        expires: NaiveDateTime,
+    ) -> Result<(), ApplicationError> {
+        let conn = db::connection()?;
+        diesel::insert_into(impersonation::table)
+            .values((
+                impersonation::admin_id.eq(admin_id),
+                impersonation::user_id.eq(uid),
+                impersonation::token_hash.eq(token_hash),
+                impersonation::reason.eq(reason),
+                impersonation::expires.eq(expires),
+            ))
+            .execute(&conn)?;
+        Ok(())
+    }
+
+    fn impersonation(
+        &self,
+        uid: i32,
+        token: &str,
+    ) -> Result<Option<Impersonation>, ApplicationError> {
+        let conn = db::connection()?;
+        Ok(impersonation::table
+            .select((
+                impersonation::id,
+                impersonation::admin_id,
+                impersonation::user_id,
+                impersonation::reason,
+                impersonation::started,
+                impersonation::expires,
+                impersonation::ended,
+            ))
+            .filter(impersonation::user_id.eq(uid))
+            .filter(impersonation::ended.is_null())
+            .filter(impersonation::token_hash.eq(crypt(token, impersonation::token_hash)))
+            .first::<Impersonation>(&conn)
+            .optional()?)
+    }
+
+    fn expired_impersonations(
+        &self,
+        now: NaiveDateTime,
+    ) -> Result<Vec<Impersonation>, ApplicationError> {
+        let conn = db::connection()?;
+        Ok(impersonation::table
+            .select((
+                impersonation::id,
+                impersonation::admin_id,
+                impersonation::user_id,
+                impersonation::reason,
+                impersonation::started,
+                impersonation::expires,
+                impersonation::ended,
+            ))
+            .filter(impersonation::ended.is_null())
+            .filter(impersonation::expires.le(now))
+            .order(impersonation::id)
+            .load::<Impersonation>(&conn)?)
+    }
+
+    fn end_impersonation(&self, id: i32, time: NaiveDateTime) -> Result<bool, ApplicationError> {
+        let conn = db::connection()?;
+        Ok(diesel::update(
+            impersonation::table
+                .find(id)
+                .filter(impersonation::ended.is_null()),
+        )
+        .set(impersonation::ended.eq(time))
+        .execute(&conn)?
+            > 0)
+    }
+
+    fn create_role(&self, name: &str) -> Result<bool, ApplicationError> {
+        let conn = db::connection()?;
+        Ok(diesel::insert_into(role::table)
+            .values(role::name.eq(name))
+            .on_conflict_do_nothing()
+            .execute(&conn)?
+            > 0)
+    }
+
+    fn delete_role(&self, name: &str) -> Result<bool, ApplicationError> {
+        let conn = db::connection()?;
+        Ok(diesel::delete(role::table.filter(role::name.eq(name))).execute(&conn)? > 0)
+    }
+
+    fn roles(&self) -> Result<Vec<String>, ApplicationError> {
+        let conn = db::connection()?;
+        Ok(role::table
+            .select(role::name)
+            .order(role::name)
+            .load::<String>(&conn)?)
+    }
+
+    fn grant_permission(&self, role: &str, permission: &str) -> Result<bool, ApplicationError> {
+        let conn = db::connection()?;
+        conn.transaction::<_, ApplicationError, _>(|| {
+            let role_id = match role::table
+                .select(role::id)
+                .filter(role::name.eq(role))
+                .first::<i32>(&conn)
+                .optional()?
+            {
+                Some(id) => id,
+                None => return Ok(false),
+            };
+            diesel::insert_into(permission::table)
+                .values(permission::name.eq(permission))
+                .on_conflict_do_nothing()
+                .execute(&conn)?;
+            let permission_id = permission::table
+                .select(permission::id)
+                .filter(permission::name.eq(permission))
+                .first::<i32>(&conn)?;
+            diesel::insert_into(role_permission::table)
+                .values((
+                    role_permission::role_id.eq(role_id),
+                    role_permission::permission_id.eq(permission_id),
+                ))
+                .on_conflict_do_nothing()
+                .execute(&conn)?;
+            Ok(true)
+        })
+    }
+
+    fn revoke_permission(&self, role: &str, permission: &str) -> Result<bool, ApplicationError> {
+        let conn = db::connection()?;
+        let role_ids = role::table.select(role::id).filter(role::name.eq(role));
+        let permission_ids = permission::table
+            .select(permission::id)
+            .filter(permission::name.eq(permission));
+        Ok(diesel::delete(
+            role_permission::table
+                .filter(role_permission::role_id.eq_any(role_ids))
+                .filter(role_permission::permission_id.eq_any(permission_ids)),
+        )
+        .execute(&conn)?
+            > 0)
+    }
+
+    fn role_permissions(&self, role: &str) -> Result<Vec<String>, ApplicationError> {
+        let conn = db::connection()?;
+        Ok(role_permission::table
+            .inner_join(role::table)
+            .inner_join(permission::table)
+            .select(permission::name)
+            .filter(role::name.eq(role))
+            .order(permission::name)
+            .load::<String>(&conn)?)
+    }
+
+    fn grant_role(&self, uid: i32, role: &str) -> Result<bool, ApplicationError> {
+        let conn = db::connection()?;
+        let role_id = match role::table
+            .select(role::id)
+            .filter(role::name.eq(role))
+            .first::<i32>(&conn)
+            .optional()?
+        {
+            Some(id) => id,
+            None => return Ok(false),
+        };
+        if !select(exists(users::table.find(uid))).get_result::<bool>(&conn)? {
+            return Ok(false);
+        }
+        diesel::insert_into(user_role::table)
+            .values((user_role::user_id.eq(uid), user_role::role_id.eq(role_id)))
+            .on_conflict_do_nothing()
+            .execute(&conn)?;
+        Ok(true)
+    }
+
+    fn revoke_role(&self, uid: i32, role: &str) -> Result<bool, ApplicationError> {
+        let conn = db::connection()?;
+        let role_ids = role::table.select(role::id).filter(role::name.eq(role));
+        Ok(diesel::delete(
+            user_role::table
+                .filter(user_role::user_id.eq(uid))
+                .filter(user_role::role_id.eq_any(role_ids)),
+        )
+        .execute(&conn)?
+            > 0)
+    }
+
+    fn user_roles(&self, uid: i32) -> Result<Vec<String>, ApplicationError> {
+        let conn = db::connection()?;
+        Ok(user_role::table
+            .inner_join(role::table)
+            .select(role::name)
+            .filter(user_role::user_id.eq(uid))
+            .order(role::name)
+            .load::<String>(&conn)?)
+    }
+
+    fn user_permissions(&self, uid: i32) -> Result<Vec<String>, ApplicationError> {
+        let conn = db::connection()?;
+        let role_ids = user_role::table
+            .select(user_role::role_id)
+            .filter(user_role::user_id.eq(uid));
+        Ok(role_permission::table
+            .inner_join(permission::table)
+            .select(permission::name)
+            .filter(role_permission::role_id.eq_any(role_ids))
+            .distinct()
+            .order(permission::name)
+            .load::<String>(&conn)?)
+    }
+
+    fn throttle_blocked_until(&self, key: &str) -> Result<Option<NaiveDateTime>, ApplicationError> {
+        Ok(login_throttle::table
+            .select(login_throttle::blocked_until)
+            .filter(login_throttle::identifier.eq(key))
+            .first::<Option<NaiveDateTime>>(&db::connection()?)
+            .optional()?
+            .flatten())
+    }
+
+    fn add_throttle_failure(
+        &self,
+        key: &str,
+        now: NaiveDateTime,
+        reset_before: NaiveDateTime,
+    ) -> Result<i32, ApplicationError> {
+        Ok(diesel::sql_query(
+            "insert into pauth.login_throttle as t (identifier, failures, last_failure) \
+             values ($1, 1, $2) \
+             on conflict (identifier) do update set \
+             failures = case when t.last_failure < $3 then 1 else t.failures + 1 end, \
+             last_failure = $2 \
+             returning failures",
+        )
+        .bind::<Text, _>(key)
+        .bind::<Timestamp, _>(now)
+        .bind::<Timestamp, _>(reset_before)
+        .get_result::<FailureCount>(&db::connection()?)?
+        .failures)
+    }
+
+    fn block_throttle_key(&self, key: &str, until: NaiveDateTime) -> Result<(), ApplicationError> {
+        diesel::update(login_throttle::table.find(key))
+            .set(login_throttle::blocked_until.eq(until))
+            .execute(&db::connection()?)?;
+        Ok(())
+    }
+
+    fn reset_throttle(&self, keys: &[String]) -> Result<(), ApplicationError> {
+        diesel::delete(login_throttle::table.filter(login_throttle::identifier.eq_any(keys)))
+            .execute(&db::connection()?)?;
+        Ok(())
+    }
+
+    fn insert_ip_rule(
+        &self,
+        scope: &RuleScope,
+        network: IpNetwork,
+        allow: bool,
+        note: Option<&str>,
+    ) -> Result<i32, ApplicationError> {
+        let (uid, domain) = scope.columns();
+        Ok(diesel::insert_into(ip_rule::table)
+            .values((
+                ip_rule::user_id.eq(uid),
+                ip_rule::email_domain.eq(domain),
+                ip_rule::network.eq(network),
+                ip_rule::allow.eq(allow),
+                ip_rule::note.eq(note),
+            ))
+            .returning(ip_rule::id)
+            .get_result(&db::connection()?)?)
+    }
+
+    fn delete_ip_rule(&self, rule_id: i32) -> Result<bool, ApplicationError> {
+        Ok(diesel::delete(ip_rule::table.find(rule_id)).execute(&db::connection()?)? > 0)
+    }
+
+    fn ip_rules(&self, scope: &RuleScope) -> Result<Vec<IpRule>, ApplicationError> {
+        let query = ip_rule::table
+            .select((
+                ip_rule::id,
+                ip_rule::user_id,
+                ip_rule::email_domain,
+                ip_rule::network,
+                ip_rule::allow,
+                ip_rule::note,
+            ))
+            .order(ip_rule::id)
+            .into_boxed();
+        let query = match scope {
+            RuleScope::Global => query
+                .filter(ip_rule::user_id.is_null())
+                .filter(ip_rule::email_domain.is_null()),
+            RuleScope::EmailDomain(domain) => query.filter(ip_rule::email_domain.eq(domain)),
+            RuleScope::User(uid) => query.filter(ip_rule::user_id.eq(*uid)),
+        };
+        Ok(query
+            .load::<IpRuleRow>(&db::connection()?)?
+            .into_iter()
+            .map(IpRule::from)
+            .collect())
+    }
+
+    fn get_config(&self, key: &str, uid: Option<i32>) -> Result<Option<String>, ApplicationError> {
+        let conn = db::connection()?;
+        if let Some(uid) = uid {
+            let user_value = user_config::table
+                .inner_join(config::table)
+                .select(config::config_value)
+                .filter(user_config::user_id.eq(uid))
+                .filter(config::config_key.eq(key))
+                .first::<Option<String>>(&conn)
+                .optional()?;
+            if let Some(value) = user_value {
+                return Ok(value);
+            }
+        }
+        Ok(default_config::table
+            .inner_join(config::table)
+            .select(config::config_value)
+            .filter(config::config_key.eq(key))
+            .first::<Option<String>>(&conn)
+            .optional()?
+            .flatten())
+    }
+
+    fn set_user_config(&self, uid: i32, key: &str, value: &str) -> Result<(), ApplicationError> {
+        let conn = db::connection()?;
+        conn.transaction::<_, ApplicationError, _>(|| {
+            let existing = user_config::table
+                .inner_join(config::table)
+                .select(config::id)
+                .filter(user_config::user_id.eq(uid))
+                .filter(config::config_key.eq(key))
+                .first::<i32>(&conn)
+                .optional()?;
+            match existing {
+                Some(config_id) => {
+                    diesel::update(config::table.find(config_id))
+                        .set(config::config_value.eq(value))
+                        .execute(&conn)?;
+                }
+                None => {
+                    let config_id = diesel::insert_into(config::table)
+                        .values((config::config_key.eq(key), config::config_value.eq(value)))
+                        .returning(config::id)
+                        .get_result::<i32>(&conn)?;
+                    diesel::insert_into(user_config::table)
+                        .values((
+                            user_config::user_id.eq(uid),
+                            user_config::config_id.eq(config_id),
+                        ))
+                        .execute(&conn)?;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn remove_user_config(&self, uid: i32, key: &str) -> Result<(), ApplicationError> {
+        let conn = db::connection()?;
+        conn.transaction::<_, ApplicationError, _>(|| {
+            let config_ids = user_config::table
+                .inner_join(config::table)
+                .select(config::id)
+                .filter(user_config::user_id.eq(uid))
+                .filter(config::config_key.eq(key))
+                .load::<i32>(&conn)?;
+            diesel::delete(
+                user_config::table
+                    .filter(user_config::user_id.eq(uid))
+                    .filter(user_config::config_id.eq_any(&config_ids)),
+            )
+            .execute(&conn)?;
+            diesel::delete(config::table.filter(config::id.eq_any(&config_ids))).execute(&conn)?;
+            Ok(())
+        })
+    }
+
+    fn user_config(&self, uid: i32) -> Result<Vec<(String, String)>, ApplicationError> {
+        let conn = db::connection()?;
+        Ok(user_config::table
+            .inner_join(config::table)
+            .select((config::config_key, config::config_value))
+            .filter(user_config::user_id.eq(uid))
+            .order(config::config_key)
+            .load::<(Option<String>, Option<String>)>(&conn)?
+            .into_iter()
+            .filter_map(|(key, value)| Some((key?, value?)))
+            .collect())
+    }
+
+    fn is_postgres(&self) -> bool {
+        true
+    }
+}
@@ -0,0 +1,1220 @@
+use super::{
+    bcrypt_hash, bcrypt_verify, prefix_patterns, CollisionRow, LoginRecord, Session, Store,
+};
+use crate::admin::{AdminAction, Impersonation, PageRequest, UserFilter};
+use crate::identifier::{Canonicaliser, UserKeys};
+use crate::ip_rules::{IpRule, RuleScope};
+use crate::models::{IdentifierCollision, User, UserActionFailure, UserUpdate};
+use crate::pauth_error::ApplicationError;
+use chrono::{NaiveDateTime, Utc};
+use diesel::dsl::exists;
+use diesel::prelude::*;
+use diesel::select;
+use diesel::sqlite::SqliteConnection;
+use ipnetwork::IpNetwork;
+use std::sync::{Mutex, MutexGuard};
+
+mod schema {
+    table! {
+        users (id) {
+            id -> Integer,
+            chosen_name -> Text,
+            email -> Text,
+            pass_hash -> Text,
+            last_login -> Timestamp,
+            failed_logins -> Integer,
+            locked_at -> Nullable<Timestamp>,
+            password_changed_at -> Timestamp,
+            must_change_password -> Bool,
+            temporary_password_expires -> Nullable<Timestamp>,
+            deleted_at -> Nullable<Timestamp>,
+            is_admin -> Bool,
+            disabled_at -> Nullable<Timestamp>,
+            created -> Timestamp,
+        }
+    }
+
+    table! {
+        user_key (user_id) {
+            user_id -> Integer,
+            name_key -> Text,
+            email_key -> Text,
+            name_skeleton -> Nullable<Text>,
+            name_collision -> Bool,
+            email_collision -> Bool,
+        }
+    }
+
+    table! {
+        user_key_form (form) {
+            form -> Text,
+        }
+    }
+
+    joinable!(user_key -> users (user_id));
+    allow_tables_to_appear_in_same_query!(users, user_key);
+
+    table! {
+        user_login_tokens (id) {
+            id -> Integer,
+            user_id -> Integer,
+            token -> Text,
+            created -> Timestamp,
+            last_used -> Timestamp,
+            last_strong_auth -> Nullable<Timestamp>,
+        }
+    }
+
+    table! {
+        pw_reset (id) {
+            id -> Integer,
+            user_id -> Integer,
+            user_token_hash -> Text,
+            expires -> Nullable<Timestamp>,
+        }
+    }
+
+    table! {
+        password_change_token (id) {
+            id -> Integer,
+            user_id -> Integer,
+            token_hash -> Text,
+            expires -> Timestamp,
+        }
+    }
+
+    table! {
+        login_history (id) {
+            id -> Integer,
+            user_id -> Nullable<Integer>,
+            login_time -> Timestamp,
+            route -> Nullable<Text>,
+        }
+    }
+
+    table! {
+        admin_audit (id) {
+            id -> Integer,
+            admin_id -> Integer,
+            user_id -> Nullable<Integer>,
+            action -> Text,
+            action_time -> Timestamp,
+        }
+    }
+
+    table! {
+        impersonation (id) {
+            id -> Integer,
+            admin_id -> Integer,
+            user_id -> Integer,
+            token_hash -> Text,
+            reason -> Text,
+            started -> Timestamp,
+            expires -> Timestamp,
+            ended -> Nullable<Timestamp>,
+        }
+    }
+
+    table! {
+        role (id) {
+            id -> Integer,
+            name -> Text,
+        }
+    }
+
+    table! {
+        permission (id) {
+            id -> Integer,
+            name -> Text,
+        }
+    }
+
+    table! {
+        role_permission (role_id, permission_id) {
+            role_id -> Integer,
+            permission_id -> Integer,
+        }
+    }
+
+    table! {
+        user_role (user_id, role_id) {
+            user_id -> Integer,
+            role_id -> Integer,
+        }
+    }
+
+    joinable!(role_permission -> permission (permission_id));
+    joinable!(user_role -> role (role_id));
+    allow_tables_to_appear_in_same_query!(permission, role, role_permission, user_role);
+
+    table! {
+        default_config (config_key) {
+            config_key -> Text,
+            config_value -> Nullable<Text>,
+        }
+    }
+
+    table! {
+        user_config (user_id, config_key) {
+            user_id -> Integer,
+            config_key -> Text,
+            config_value -> Nullable<Text>,
+        }
+    }
+
+    table! {
+        login_throttle (identifier) {
+            identifier -> Text,
+            failures -> Integer,
+            last_failure -> Timestamp,
+            blocked_until -> Nullable<Timestamp>,
+        }
+    }
+
+    table! {
+        ip_rule (id) {
+            id -> Integer,
+            user_id -> Nullable<Integer>,
+            email_domain -> Nullable<Text>,
+            network -> Text,
+            allow -> Bool,
+            note -> Nullable<Text>,
+        }
+    }
+}
+
+use schema::{
+    admin_audit, default_config, impersonation, ip_rule, login_history, login_throttle,
+    password_change_token, permission, pw_reset, role, role_permission, user_config, user_key,
+    user_key_form, user_login_tokens, user_role, users,
+};
+
+embed_migrations!("migrations_sqlite");
+
+no_arg_sql_function!(
+    last_insert_rowid,
+    diesel::sql_types::Integer,
+    "The id of the last row inserted on this connection"
+);
+
+/// A store using a SQLite database file, for command line tools and desktop apps.
+pub struct SqliteStore {
+    conn: Mutex<SqliteConnection>,
+}
+
+impl SqliteStore {
+    /// Open (creating if needed) the database at path, and bring its schema up to date.
+    /// ":memory:" gives a private in memory database.
+    pub fn open(path: &str) -> Result<SqliteStore, ApplicationError> {
+        let conn = SqliteConnection::establish(path)
+            .map_err(|e| ApplicationError::ApplicationDataLogic(e.to_string()))?;
+        conn.execute("pragma foreign_keys = on")?;
+        embedded_migrations::run(&conn)
+            .map_err(|e| ApplicationError::ApplicationDataLogic(e.to_string()))?;
+        let store = SqliteStore {
+            conn: Mutex::new(conn),
+        };
+        store.fill_keys()?;
+        Ok(store)
+    }
+
+    /// Fill in identifier keys for users added before there were keys, and make every user's
+    /// keys again if they were made in another form. Returns how many users were keyed.
+    fn fill_keys(&self) -> Result<usize, ApplicationError> {
+        let canonicaliser = Canonicaliser::configured(self)?;
+        let form = canonicaliser.form();
+        let conn = self.conn();
+        conn.transaction::<_, ApplicationError, _>(|| {
+            let current = user_key_form::table
+                .select(user_key_form::form)
+                .first::<String>(&*conn)
+                .optional()?;
+            let query = users::table
+                .left_join(user_key::table)
+                .select((users::id, users::chosen_name, users::email))
+                .order(users::id)
+                .into_boxed();
+            let unkeyed = if current.as_ref() == Some(&form) {
+                query
+                    .filter(user_key::name_skeleton.is_null())
+                    .load::<(i32, String, String)>(&*conn)?
+            } else {
+                diesel::delete(user_key::table).execute(&*conn)?;
+                diesel::delete(user_key_form::table).execute(&*conn)?;
+                diesel::insert_into(user_key_form::table)
+                    .values(user_key_form::form.eq(&form))
+                    .execute(&*conn)?;
+                query.load::<(i32, String, String)>(&*conn)?
+            };
+            for (uid, name, email) in &unkeyed {
+                let keys = canonicaliser.user_keys(name, email);
+                //users whose key is already taken keep it, as a collision
+                let name_collision = select(exists(
+                    user_key::table
+                        .filter(user_key::name_key.eq(&keys.name_key))
+                        .filter(user_key::name_collision.eq(false)),
+                ))
+                .get_result::<bool>(&*conn)?;
+                let email_collision = select(exists(
+                    user_key::table
+                        .filter(user_key::email_key.eq(&keys.email_key))
+                        .filter(user_key::email_collision.eq(false)),
+                ))
+                .get_result::<bool>(&*conn)?;
+                write_keys(&conn, *uid, &keys, name_collision, email_collision)?;
+            }
+            Ok(unkeyed.len())
+        })
+    }
+
+    fn conn(&self) -> MutexGuard<'_, SqliteConnection> {
+        self.conn.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn tokens_match(&self, hashes: Vec<String>, token: &str) -> Result<bool, ApplicationError> {
+        for hash in hashes {
+            if bcrypt_verify(token, &hash)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+}
+
+/// Store the user's keys. A collision (see fill_keys) is kept until the key changes.
+fn save_keys(conn: &SqliteConnection, uid: i32, keys: &UserKeys) -> QueryResult<usize> {
+    let (name_collision, email_collision) = user_key::table
+        .find(uid)
+        .select((
+            user_key::name_key,
+            user_key::email_key,
+            user_key::name_collision,
+            user_key::email_collision,
+        ))
+        .first::<(String, String, bool, bool)>(conn)
+        .optional()?
+        .map_or((false, false), |(name_key, email_key, name, email)| {
+            (
+                name && name_key == keys.name_key,
+                email && email_key == keys.email_key,
+            )
+        });
+    write_keys(conn, uid, keys, name_collision, email_collision)
+}
+
+fn write_keys(
+    conn: &SqliteConnection,
+    uid: i32,
+    keys: &UserKeys,
+    name_collision: bool,
+    email_collision: bool,
+) -> QueryResult<usize> {
+    diesel::replace_into(user_key::table)
+        .values((
+            user_key::user_id.eq(uid),
+            user_key::name_key.eq(&keys.name_key),
+            user_key::email_key.eq(&keys.email_key),
+            user_key::name_skeleton.eq(&keys.name_skeleton),
+            user_key::name_collision.eq(name_collision),
+            user_key::email_collision.eq(email_collision),
+        ))
+        .execute(conn)
+}
+
+impl Store for SqliteStore {
+    fn hash(&self, secret: &str) -> Result<String, ApplicationError> {
+        bcrypt_hash(secret)
+    }
+
+    fn verify(&self, secret: &str, hash: &str) -> Result<bool, ApplicationError> {
+        bcrypt_verify(secret, hash)
+    }
+
+    fn find_users(&self, name_or_email: &str) -> Result<Vec<User>, ApplicationError> {
+        let key = Canonicaliser::new(self)?.key(name_or_email);
+        Ok(users::table
+            .inner_join(user_key::table)
+            .select(users::all_columns)
+            .filter(user_key::email_key.eq(&key).or(user_key::name_key.eq(&key)))
+            .order(users::id)
+            .load::<User>(&*self.conn())?)
+    }
+
+    fn user_by_id(&self, uid: i32) -> Result<Option<User>, ApplicationError> {
+        Ok(users::table
+            .find(uid)
+            .first::<User>(&*self.conn())
+            .optional()?)
+    }
+
+    fn user_by_name(&self, name: &str) -> Result<Option<User>, ApplicationError> {
+        let key = Canonicaliser::new(self)?.key(name);
+        Ok(users::table
+            .inner_join(user_key::table)
+            .select(users::all_columns)
+            .filter(user_key::name_key.eq(key))
+            .first::<User>(&*self.conn())
+            .optional()?)
+    }
+
+    fn user_by_email(&self, email: &str) -> Result<Option<User>, ApplicationError> {
+        let key = Canonicaliser::new(self)?.key(email);
+        Ok(users::table
+            .inner_join(user_key::table)
+            .select(users::all_columns)
+            .filter(user_key::email_key.eq(key))
+            .first::<User>(&*self.conn())
+            .optional()?)
+    }
+
+    fn conflicts(
+        &self,
+        name: Option<&str>,
+        email: Option<&str>,
+        except: Option<i32>,
+    ) -> Result<Vec<UserActionFailure>, ApplicationError> {
+        let canonicaliser = Canonicaliser::new(self)?;
+        let conn = self.conn();
+        let others = || {
+            let query = user_key::table.select(user_key::user_id).into_boxed();
+            match except {
+                Some(uid) => query.filter(user_key::user_id.ne(uid)),
+                None => query,
+            }
+        };
+        let mut conflicts = Vec::new();
+        if let Some(name) = name {
+            let key = canonicaliser.key(name);
+            let taken = select(exists(
+                others().filter(user_key::name_key.eq(&key).or(user_key::email_key.eq(&key))),
+            ))
+            .get_result::<bool>(&*conn)?;
+            let looks_taken = canonicaliser.check_confusables
+                && select(exists(
+                    others().filter(user_key::name_skeleton.eq(canonicaliser.skeleton(name))),
+                ))
+                .get_result::<bool>(&*conn)?;
+            if taken || looks_taken {
+                conflicts.push(UserActionFailure::UsernameExists);
+            }
+        }
+        if let Some(email) = email {
+            let key = canonicaliser.key(email);
+            if select(exists(others().filter(
+                user_key::email_key.eq(&key).or(user_key::name_key.eq(&key)),
+            )))
+            .get_result::<bool>(&*conn)?
+            {
+                conflicts.push(UserActionFailure::EmailExists);
+            }
+        }
+        Ok(conflicts)
+    }
+
+    fn identifier_collisions(&self) -> Result<Vec<IdentifierCollision>, ApplicationError> {
+        //rekeying first if the config has changed
+        Canonicaliser::new(self)?;
+        let conn = self.conn();
+        Ok(diesel::sql_query(
+            "select user_id, other_user_id, kind from identifier_collision \
+                order by user_id, other_user_id, kind",
+        )
+        .load::<CollisionRow>(&*conn)?
+        .into_iter()
+        .map(CollisionRow::into_collision)
+        .collect())
+    }
+
+    fn rekey_users(&self, form: &str) -> Result<usize, ApplicationError> {
+        let current = user_key_form::table
+            .select(user_key_form::form)
+            .first::<String>(&*self.conn())
+            .optional()?;
+        if current.as_deref() == Some(form) {
+            Ok(0)
+        } else {
+            self.fill_keys()
+        }
+    }
+
+    fn insert_user(
+        &self,
+        name: &str,
+        email: &str,
+        pass_hash: &str,
+    ) -> Result<i32, ApplicationError> {
+        let keys = Canonicaliser::new(self)?.user_keys(name, email);
+        let conn = self.conn();
+        conn.transaction::<_, ApplicationError, _>(|| {
+            diesel::insert_into(users::table)
+                .values((
+                    users::chosen_name.eq(name),
+                    users::email.eq(email),
+                    users::pass_hash.eq(pass_hash),
+                    users::password_changed_at.eq(Utc::now().naive_utc()),
+                    users::created.eq(Utc::now().naive_utc()),
+                ))
+                .execute(&*conn)?;
+            let uid = diesel::select(last_insert_rowid).get_result::<i32>(&*conn)?;
+            save_keys(&conn, uid, &keys)?;
+            Ok(uid)
+        })
+    }
+
+    fn update_user(&self, uid: i32, changes: &UserUpdate) -> Result<bool, ApplicationError> {
+        let canonicaliser = Canonicaliser::new(self)?;
+        let mut user = match self.user_by_id(uid)? {
+            Some(user) => user,
+            None => return Ok(false),
+        };
+        changes.apply(&mut user);
+        let keys = canonicaliser.user_keys(&user.chosen_name, &user.email);
+        let conn = self.conn();
+        conn.transaction::<_, ApplicationError, _>(|| {
+            let updated = diesel::update(users::table.find(uid))
+                .set((
+                    users::chosen_name.eq(&user.chosen_name),
+                    users::email.eq(&user.email),
+                    users::pass_hash.eq(&user.pass_hash),
+                    users::password_changed_at.eq(user.password_changed_at),
+                    users::must_change_password.eq(user.must_change_password),
+                    users::temporary_password_expires.eq(user.temporary_password_expires),
+                ))
+                .execute(&*conn)?;
+            save_keys(&conn, uid, &keys)?;
+            if changes.pass_hash().is_some() {
+                diesel::delete(pw_reset::table.filter(pw_reset::user_id.eq(uid)))
+                    .execute(&*conn)?;
+            }
+            Ok(updated > 0)
+        })
+    }
+
+    fn delete_user(&self, uid: i32) -> Result<bool, ApplicationError> {
+        Ok(diesel::delete(users::table.find(uid)).execute(&*self.conn())? > 0)
+    }
+
+    fn set_deleted(
+        &self,
+        uid: i32,
+        deleted_at: Option<NaiveDateTime>,
+    ) -> Result<bool, ApplicationError> {
+        let conn = self.conn();
+        conn.transaction::<_, ApplicationError, _>(|| {
+            if deleted_at.is_none() {
+                diesel::delete(pw_reset::table.filter(pw_reset::user_id.eq(uid)))
+                    .execute(&*conn)?;
+            }
+            Ok(diesel::update(users::table.find(uid))
+                .set(users::deleted_at.eq(deleted_at))
+                .execute(&*conn)?
+                > 0)
+        })
+    }
+
+    fn search_users(
+        &self,
+        filter: &UserFilter,
+        page: &PageRequest,
+        limit: i64,
+    ) -> Result<Vec<User>, ApplicationError> {
+        let prefixes = prefix_patterns(self, filter)?;
+        let query = users::table
+            .inner_join(user_key::table)
+            .select(users::all_columns)
+            .into_boxed();
+        let query = filter_users!(query, filter, prefixes, users, user_key);
+        Ok(page_users!(query, page, users, user_key)
+            .limit(limit)
+            .load::<User>(&*self.conn())?)
+    }
+
+    fn count_users(&self, filter: &UserFilter) -> Result<i64, ApplicationError> {
+        let prefixes = prefix_patterns(self, filter)?;
+        let query = users::table
+            .inner_join(user_key::table)
+            .select(diesel::dsl::count_star())
+            .into_boxed();
+        Ok(filter_users!(query, filter, prefixes, users, user_key)
+            .get_result::<i64>(&*self.conn())?)
+    }
+
+    fn set_admin(&self, uid: i32, is_admin: bool) -> Result<bool, ApplicationError> {
+        Ok(diesel::update(users::table.find(uid))
+            .set(users::is_admin.eq(is_admin))
+            .execute(&*self.conn())?
+            > 0)
+    }
+
+    fn set_disabled(
+        &self,
+        uid: i32,
+        disabled_at: Option<NaiveDateTime>,
+    ) -> Result<bool, ApplicationError> {
+        Ok(diesel::update(users::table.find(uid))
+            .set(users::disabled_at.eq(disabled_at))
+            .execute(&*self.conn())?
+            > 0)
+    }
+
+    fn deleted_users(&self) -> Result<Vec<User>, ApplicationError> {
+        Ok(users::table
+            .filter(users::deleted_at.is_not_null())
+            .order((users::deleted_at, users::id))
+            .load::<User>(&*self.conn())?)
+    }
+
+    fn set_last_login(&self, uid: i32, time: NaiveDateTime) -> Result<(), ApplicationError> {
+        diesel::update(users::table.find(uid))
+            .set(users::last_login.eq(time))
+            .execute(&*self.conn())?;
+        Ok(())
+    }
+
+    fn add_failed_login(&self, uid: i32) -> Result<i32, ApplicationError> {
+        let conn = self.conn();
+        conn.transaction::<_, ApplicationError, _>(|| {
+            diesel::update(users::table.find(uid))
+                .set(users::failed_logins.eq(users::failed_logins + 1))
+                .execute(&*conn)?;
+            Ok(users::table
+                .find(uid)
+                .select(users::failed_logins)
+                .first::<i32>(&*conn)?)
+        })
+    }
+
+    fn lock_user(&self, uid: i32, time: NaiveDateTime) -> Result<bool, ApplicationError> {
+        Ok(
+            diesel::update(users::table.find(uid).filter(users::locked_at.is_null()))
+                .set(users::locked_at.eq(time))
+                .execute(&*self.conn())?
+                > 0,
+        )
+    }
+
+    fn unlock_user(&self, uid: i32) -> Result<bool, ApplicationError> {
+        let conn = self.conn();
+        conn.transaction::<_, ApplicationError, _>(|| {
+            diesel::delete(pw_reset::table.filter(pw_reset::user_id.eq(uid))).execute(&*conn)?;
+            Ok(diesel::update(users::table.find(uid))
+                .set((
+                    users::locked_at.eq(None::<NaiveDateTime>),
+                    users::failed_logins.eq(0),
+                ))
+                .execute(&*conn)?
+                > 0)
+        })
+    }
+
+    fn insert_token(
+        &self,
+        uid: i32,
+        token_hash: &str,
+        strong_auth: Option<NaiveDateTime>,
+    ) -> Result<(), ApplicationError> {
+        diesel::insert_into(user_login_tokens::table)
+            .values((
+                user_login_tokens::user_id.eq(uid),
+                user_login_tokens::token.eq(token_hash),
+                user_login_tokens::last_strong_auth.eq(strong_auth),
+            ))
+            .execute(&*self.conn())?;
+        Ok(())
+    }
+
+    fn token_valid(&self, uid: i32, token: &str) -> Result<bool, ApplicationError> {
+        let hashes = user_login_tokens::table
+            .select(user_login_tokens::token)
+            .filter(user_login_tokens::user_id.eq(uid))
+            .load::<String>(&*self.conn())?;
+        self.tokens_match(hashes, token)
+    }
+
+    fn strong_auth_time(
+        &self,
+        uid: i32,
+        token: &str,
+    ) -> Result<Option<NaiveDateTime>, ApplicationError> {
+        let tokens = user_login_tokens::table
+            .select((
+                user_login_tokens::token,
+                user_login_tokens::last_strong_auth,
+            ))
+            .filter(user_login_tokens::user_id.eq(uid))
+            .load::<(String, Option<NaiveDateTime>)>(&*self.conn())?;
+        for (hash, strong_auth) in tokens {
+            if bcrypt_verify(token, &hash)? {
+                return Ok(strong_auth);
+            }
+        }
+        Ok(None)
+    }
+
+    fn set_strong_auth_time(
+        &self,
+        uid: i32,
+        token: &str,
+        time: NaiveDateTime,
+    ) -> Result<bool, ApplicationError> {
+        let conn = self.conn();
+        let tokens = user_login_tokens::table
+            .select((user_login_tokens::id, user_login_tokens::token))
+            .filter(user_login_tokens::user_id.eq(uid))
+            .load::<(i32, String)>(&*conn)?;
+        for (id, hash) in tokens {
+            if bcrypt_verify(token, &hash)? {
+                diesel::update(user_login_tokens::table.find(id))
+                    .set(user_login_tokens::last_strong_auth.eq(time))
+                    .execute(&*conn)?;
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn sessions(&self, uid: i32) -> Result<Vec<Session>, ApplicationError> {
+        Ok(user_login_tokens::table
+            .select((
+                user_login_tokens::created,
+                user_login_tokens::last_used,
+                user_login_tokens::last_strong_auth,
+            ))
+            .filter(user_login_tokens::user_id.eq(uid))
+            .order(user_login_tokens::id)
+            .load::<Session>(&*self.conn())?)
+    }
+
+    fn delete_tokens(&self, uid: i32, except: Option<&str>) -> Result<usize, ApplicationError> {
+        let conn = self.conn();
+        let tokens = user_login_tokens::table
+            .select((user_login_tokens::id, user_login_tokens::token))
+            .filter(user_login_tokens::user_id.eq(uid))
+            .load::<(i32, String)>(&*conn)?;
+        let mut ended = Vec::new();
+        for (id, hash) in tokens {
+            match except {
+                Some(token) if bcrypt_verify(token, &hash)? => {}
+                _ => ended.push(id),
+            }
+        }
+        Ok(
+            diesel::delete(user_login_tokens::table.filter(user_login_tokens::id.eq_any(ended)))
+                .execute(&*conn)?,
+        )
+    }
+
+    fn insert_reset(
+        &self,
+        uid: i32,
+        token_hash: &str,
+        expires: Option<NaiveDateTime>,
+    ) -> Result<(), ApplicationError> {
+        diesel::insert_into(pw_reset::table)
+            .values((
+                pw_reset::user_id.eq(uid),
+                pw_reset::user_token_hash.eq(token_hash),
+                pw_reset::expires.eq(expires),
+            ))
+            .execute(&*self.conn())?;
+        Ok(())
+    }
+
+    fn take_reset(
+        &self,
+        uid: i32,
+        token: &str,
+        now: NaiveDateTime,
+    ) -> Result<bool, ApplicationError> {
+        let conn = self.conn();
+        conn.transaction::<_, ApplicationError, _>(|| {
+            let resets = pw_reset::table
+                .select((pw_reset::id, pw_reset::user_token_hash))
+                .filter(pw_reset::user_id.eq(uid))
+                .filter(pw_reset::expires.is_null().or(pw_reset::expires.gt(now)))
+                .load::<(i32, String)>(&*conn)?;
+            for (id, hash) in resets {
+                if bcrypt_verify(token, &hash)? {
+                    return Ok(diesel::delete(pw_reset::table.find(id)).execute(&*conn)? > 0);
+                }
+            }
+            Ok(false)
+        })
+    }
+
+    fn insert_password_change_token(
+        &self,
+        uid: i32,
+        token_hash: &str,
+        expires: NaiveDateTime,
+    ) -> Result<(), ApplicationError> {
+        diesel::insert_into(password_change_token::table)
+            .values((
+                password_change_token::user_id.eq(uid),
+                password_change_token::token_hash.eq(token_hash),
+                password_change_token::expires.eq(expires),
+            ))
+            .execute(&*self.conn())?;
+        Ok(())
+    }
+
+    fn password_change_token_valid(
+        &self,
+        uid: i32,
+        token: &str,
+        now: NaiveDateTime,
+    ) -> Result<bool, ApplicationError> {
+        let hashes = password_change_token::table
+            .select(password_change_token::token_hash)
+            .filter(password_change_token::user_id.eq(uid))
+            .filter(password_change_token::expires.gt(now))
+            .load::<String>(&*self.conn())?;
+        self.tokens_match(hashes, token)
+    }
+
+    fn delete_password_change_tokens(&self, uid: i32) -> Result<(), ApplicationError> {
+        diesel::delete(password_change_token::table.filter(password_change_token::user_id.eq(uid)))
+            .execute(&*self.conn())?;
+        Ok(())
+    }
+
+    fn record_login(
+        &self,
+        uid: i32,
+        _source: Option<i32>,
+        route: Option<&str>,
+    ) -> Result<(), ApplicationError> {
+        diesel::insert_into(login_history::table)
+            .values((
+                login_history::user_id.eq(uid),
+                login_history::route.eq(route),
+            ))
+            .execute(&*self.conn())?;
+        Ok(())
+    }
+
+    fn login_history(&self, uid: i32) -> Result<Vec<LoginRecord>, ApplicationError> {
+        Ok(login_history::table
+            .select((login_history::login_time, login_history::route))
+            .filter(login_history::user_id.eq(uid))
+            .order(login_history::id)
+            .load::<(NaiveDateTime, Option<String>)>(&*self.conn())?
+            .into_iter()
+            .map(|(time, route)| LoginRecord { time, route })
+            .collect())
+    }
+
+    fn login_count(&self) -> Result<i64, ApplicationError> {
+        Ok(login_history::table.count().get_result(&*self.conn())?)
+    }
+
+    fn record_admin_action(
+        &self,
+        admin_id: i32,
+        uid: Option<i32>,
+        action: &str,
+    ) -> Result<(), ApplicationError> {
+        diesel::insert_into(admin_audit::table)
+            .values((
+                admin_audit::admin_id.eq(admin_id),
+                admin_audit::user_id.eq(uid),
+                admin_audit::action.eq(action),
+            ))
+            .execute(&*self.conn())?;
+        Ok(())
+    }
+
+    fn admin_actions(&self, uid: i32) -> Result<Vec<AdminAction>, ApplicationError> {
+        Ok(admin_audit::table
+            .select((
+                admin_audit::admin_id,
+                admin_audit::user_id,
+                admin_audit::action,
+                admin_audit::action_time,
+            ))
+            .filter(admin_audit::user_id.eq(uid))
+            .order(admin_audit::id)
+            .load::<AdminAction>(&*self.conn())?)
+    }
+
+    fn insert_impersonation(
+        &self,
+        admin_id: i32,
+        uid: i32,
+        token_hash: &str,
+        reason: &str,
+        expires: NaiveDateTime,
+    ) -> Result<(), ApplicationError> {
+        diesel::insert_into(impersonation::table)
+            .values((
+                impersonation::admin_id.eq(admin_id),
+                impersonation::user_id.eq(uid),
+                impersonation::token_hash.eq(token_hash),
+                impersonation::reason.eq(reason),
+                impersonation::started.eq(Utc::now().naive_utc()),
+                impersonation::expires.eq(expires),
+            ))
+            .execute(&*self.conn())?;
+        Ok(())
+    }
+
+    fn impersonation(
+        &self,
+        uid: i32,
+        token: &str,
+    ) -> Result<Option<Impersonation>, ApplicationError> {
+        let hashes = impersonation::table
+            .select((impersonation::id, impersonation::token_hash))
+            .filter(impersonation::user_id.eq(uid))
+            .filter(impersonation::ended.is_null())
+            .load::<(i32, String)>(&*self.conn())?;
+        for (id, hash) in hashes {
+            if bcrypt_verify(token, &hash)? {
+                return Ok(impersonation::table
+                    .find(id)
+                    .select((
+                        impersonation::id,
+                        impersonation::admin_id,
+                        impersonation::user_id,
+                        impersonation::reason,
+                        impersonation::started,
+                        impersonation::expires,
+                        impersonation::ended,
+                    ))
+                    .first::<Impersonation>(&*self.conn())
+                    .optional()?);
+            }
+        }
+        Ok(None)
+    }
+
+    fn expired_impersonations(
+        &self,
+        now: NaiveDateTime,
+    ) -> Result<Vec<Impersonation>, ApplicationError> {
+        Ok(impersonation::table
+            .select((
+                impersonation::id,
+                impersonation::admin_id,
+                impersonation::user_id,
+                impersonation::reason,
+                impersonation::started,
+                impersonation::expires,
+                impersonation::ended,
+            ))
+            .filter(impersonation::ended.is_null())
+            .filter(impersonation::expires.le(now))
+            .order(impersonation::id)
+            .load::<Impersonation>(&*self.conn())?)
+    }
+
+    fn end_impersonation(&self, id: i32, time: NaiveDateTime) -> Result<bool, ApplicationError> {
+        Ok(diesel::update(
+            impersonation::table
+                .find(id)
+                .filter(impersonation::ended.is_null()),
+        )
+        .set(impersonation::ended.eq(time))
+        .execute(&*self.conn())?
+            > 0)
+    }
+
+    fn create_role(&self, name: &str) -> Result<bool, ApplicationError> {
+        Ok(diesel::insert_or_ignore_into(role::table)
+            .values(role::name.eq(name))
+            .execute(&*self.conn())?
+            > 0)
+    }
+
+    fn delete_role(&self, name: &str) -> Result<bool, ApplicationError> {
+        Ok(diesel::delete(role::table.filter(role::name.eq(name))).execute(&*self.conn())? > 0)
+    }
+
+    fn roles(&self) -> Result<Vec<String>, ApplicationError> {
+        Ok(role::table
+            .select(role::name)
+            .order(role::name)
+            .load::<String>(&*self.conn())?)
+    }
+
+    fn grant_permission(&self, role: &str, permission: &str) -> Result<bool, ApplicationError> {
+        let conn = self.conn();
+        conn.transaction::<_, ApplicationError, _>(|| {
+            let role_id = match role::table
+                .select(role::id)
+                .filter(role::name.eq(role))
+                .first::<i32>(&*conn)
+                .optional()?
+            {
+                Some(id) => id,
+                None => return Ok(false),
+            };
+            diesel::insert_or_ignore_into(permission::table)
+                .values(permission::name.eq(permission))
+                .execute(&*conn)?;
+            let permission_id = permission::table
+                .select(permission::id)
+                .filter(permission::name.eq(permission))
+                .first::<i32>(&*conn)?;
+            diesel::insert_or_ignore_into(role_permission::table)
+                .values((
+                    role_permission::role_id.eq(role_id),
+                    role_permission::permission_id.eq(permission_id),
+                ))
+                .execute(&*conn)?;
+            Ok(true)
+        })
+    }
+
+    fn revoke_permission(&self, role: &str, permission: &str) -> Result<bool, ApplicationError> {
+        let role_ids = role::table.select(role::id).filter(role::name.eq(role));
+        let permission_ids = permission::table
+            .select(permission::id)
+            .filter(permission::name.eq(permission));
+        Ok(diesel::delete(
+            role_permission::table
+                .filter(role_permission::role_id.eq_any(role_ids))
+                .filter(role_permission::permission_id.eq_any(permission_ids)),
+        )
+        .execute(&*self.conn())?
+            > 0)
+    }
+
+    fn role_permissions(&self, role: &str) -> Result<Vec<String>, ApplicationError> {
+        let role_ids = role::table.select(role::id).filter(role::name.eq(role));
+        Ok(role_permission::table
+            .inner_join(permission::table)
+            .select(permission::name)
+            .filter(role_permission::role_id.eq_any(role_ids))
+            .order(permission::name)
+            .load::<String>(&*self.conn())?)
+    }
+
+    fn grant_role(&self, uid: i32, role: &str) -> Result<bool, ApplicationError> {
+        let conn = self.conn();
+        let role_id = match role::table
+            .select(role::id)
+            .filter(role::name.eq(role))
+            .first::<i32>(&*conn)
+            .optional()?
+        {
+            Some(id) => id,
+            None => return Ok(false),
+        };
+        if !select(exists(users::table.find(uid))).get_result::<bool>(&*conn)? {
+            return Ok(false);
+        }
+        diesel::insert_or_ignore_into(user_role::table)
+            .values((user_role::user_id.eq(uid), user_role::role_id.eq(role_id)))
+            .execute(&*conn)?;
+        Ok(true)
+    }
+
+    fn revoke_role(&self, uid: i32, role: &str) -> Result<bool, ApplicationError> {
+        let role_ids = role::table.select(role::id).filter(role::name.eq(role));
+        Ok(diesel::delete(
+            user_role::table
+                .filter(user_role::user_id.eq(uid))
+                .filter(user_role::role_id.eq_any(role_ids)),
+        )
+        .execute(&*self.conn())?
+            > 0)
+    }
+
+    fn user_roles(&self, uid: i32) -> Result<Vec<String>, ApplicationError> {
+        Ok(user_role::table
+            .inner_join(role::table)
+            .select(role::name)
+            .filter(user_role::user_id.eq(uid))
+            .order(role::name)
+            .load::<String>(&*self.conn())?)
+    }
+
+    fn user_permissions(&self, uid: i32) -> Result<Vec<String>, ApplicationError> {
+        let role_ids = user_role::table
+            .select(user_role::role_id)
+            .filter(user_role::user_id.eq(uid));
+        Ok(role_permission::table
+            .inner_join(permission::table)
+            .select(permission::name)
+            .filter(role_permission::role_id.eq_any(role_ids))
+            .distinct()
+            .order(permission::name)
+            .load::<String>(&*self.conn())?)
+    }
+
+    fn throttle_blocked_until(&self, key: &str) -> Result<Option<NaiveDateTime>, ApplicationError> {
+        Ok(login_throttle::table
+            .select(login_throttle::blocked_until)
+            .filter(login_throttle::identifier.eq(key))
+            .first::<Option<NaiveDateTime>>(&*self.conn())
+            .optional()?
+            .flatten())
+    }
+
+    fn add_throttle_failure(
+        &self,
+        key: &str,
+        now: NaiveDateTime,
+        reset_before: NaiveDateTime,
+    ) -> Result<i32, ApplicationError> {
+        let conn = self.conn();
+        conn.transaction::<_, ApplicationError, _>(|| {
+            let last = login_throttle::table
+                .select((
+                    login_throttle::failures,
+                    login_throttle::last_failure,
+                    login_throttle::blocked_until,
+                ))
+                .filter(login_throttle::identifier.eq(key))
+                .first::<(i32, NaiveDateTime, Option<NaiveDateTime>)>(&*conn)
+                .optional()?;
+            let (failures, blocked_until) = match last {
+                Some((failures, last_failure, blocked_until)) if last_failure >= reset_before => {
+                    (failures + 1, blocked_until)
+                }
+                Some((_, _, blocked_until)) => (1, blocked_until),
+                None => (1, None),
+            };
+            diesel::replace_into(login_throttle::table)
+                .values((
+                    login_throttle::identifier.eq(key),
+                    login_throttle::failures.eq(failures),
+                    login_throttle::last_failure.eq(now),
+                    login_throttle::blocked_until.eq(blocked_until),
+                ))
+                .execute(&*conn)?;
+            Ok(failures)
+        })
+    }
+
+    fn block_throttle_key(&self, key: &str, until: NaiveDateTime) -> Result<(), ApplicationError> {
+        diesel::update(login_throttle::table.find(key))
+            .set(login_throttle::blocked_until.eq(until))
+            .execute(&*self.conn())?;
+        Ok(())
+    }
+
+    fn reset_throttle(&self, keys: &[String]) -> Result<(), ApplicationError> {
+        diesel::delete(login_throttle::table.filter(login_throttle::identifier.eq_any(keys)))
+            .execute(&*self.conn())?;
+        Ok(())
+    }
+
+    fn insert_ip_rule(
+        &self,
+        scope: &RuleScope,
+        network: IpNetwork,
+        allow: bool,
+        note: Option<&str>,
+    ) -> Result<i32, ApplicationError> {
+        let (uid, domain) = scope.columns();
+        let conn = self.conn();
+        conn.transaction::<_, ApplicationError, _>(|| {
+            diesel::insert_into(ip_rule::table)
+                .values((
+                    ip_rule::user_id.eq(uid),
+                    ip_rule::email_domain.eq(domain),
+                    ip_rule::network.eq(network.to_string()),
+                    ip_rule::allow.eq(allow),
+                    ip_rule::note.eq(note),
+                ))
+                .execute(&*conn)?;
+            Ok(diesel::select(last_insert_rowid).get_result::<i32>(&*conn)?)
+        })
+    }
+
+    fn delete_ip_rule(&self, rule_id: i32) -> Result<bool, ApplicationError> {
+        Ok(diesel::delete(ip_rule::table.find(rule_id)).execute(&*self.conn())? > 0)
+    }
+
+    fn ip_rules(&self, scope: &RuleScope) -> Result<Vec<IpRule>, ApplicationError> {
+        let query = ip_rule::table
+            .select((
+                ip_rule::id,
+                ip_rule::user_id,
+                ip_rule::email_domain,
+                ip_rule::network,
+                ip_rule::allow,
+                ip_rule::note,
+            ))
+            .order(ip_rule::id)
+            .into_boxed();
+        let query = match scope {
+            RuleScope::Global => query
+                .filter(ip_rule::user_id.is_null())
+                .filter(ip_rule::email_domain.is_null()),
+            RuleScope::EmailDomain(domain) => query.filter(ip_rule::email_domain.eq(domain)),
+            RuleScope::User(uid) => query.filter(ip_rule::user_id.eq(*uid)),
+        };
+        query
+            .load::<(
+                i32,
+                Option<i32>,
+                Option<String>,
+                String,
+                bool,
+                Option<String>,
+            )>(&*self.conn())?
+            .into_iter()
+            .map(|(id, uid, domain, network, allow, note)| {
+                Ok(IpRule {
+                    id,
+                    scope: RuleScope::from_columns(uid, domain),
+                    network: network
+                        .parse()
+                        .map_err(|e| ApplicationError::ApplicationDataLogic(format!("{}", e)))?,
+                    allow,
+                    note,
+                })
+            })
+            .collect()
+    }
+
+    fn get_config(&self, key: &str, uid: Option<i32>) -> Result<Option<String>, ApplicationError> {
+        let conn = self.conn();
+        if let Some(uid) = uid {
+            let user_value = user_config::table
+                .find((uid, key))
+                .select(user_config::config_value)
+                .first::<Option<String>>(&*conn)
+                .optional()?;
+            if let Some(value) = user_value {
+                return Ok(value);
+            }
+        }
+        Ok(default_config::table
+            .find(key)
+            .select(default_config::config_value)
+            .first::<Option<String>>(&*conn)
+            .optional()?
+            .flatten())
+    }
+
+    fn set_user_config(&self, uid: i32, key: &str, value: &str) -> Result<(), ApplicationError> {
+        diesel::replace_into(user_config::table)
+            .values((
+                user_config::user_id.eq(uid),
+                user_config::config_key.eq(key),
+                user_config::config_value.eq(value),
+            ))
+            .execute(&*self.conn())?;
+        Ok(())
+    }
+
+    fn remove_user_config(&self, uid: i32, key: &str) -> Result<(), ApplicationError> {
+        diesel::delete(user_config::table.find((uid, key))).execute(&*self.conn())?;
+        Ok(())
+    }
+
+    fn user_config(&self, uid: i32) -> Result<Vec<(String, String)>, ApplicationError> {
+        Ok(user_config::table
+            .select((user_config::config_key, user_config::config_value))
+            .filter(user_config::user_id.eq(uid))
+            .order(user_config::config_key)
+            .load::<(String, Option<String>)>(&*self.conn())?
+            .into_iter()
+            .filter_map(|(key, value)| value.map(|value| (key, value)))
+            .collect())
+    }
+}
@@ -0,0 +1,169 @@
+//! A throwaway database for each test, so tests can run in parallel (and fail part way
+//! through) without seeing each other's users or leaving anything behind.
+//!
+//! TestDb::new creates a database named pauth_test_<uuid> on the server at
+//! PAUTH_TEST_DATABASE_URL (or DATABASE_URL), runs the migrations, and makes db::connection
+//! use it on the current thread. The database is dropped with the TestDb. The url's user must
+//! be able to create databases, and its parameters (such as sslmode) are kept.
+//!
+//! The initial migration creates the pauth_admin role, which is shared by every database on the
+//! server, so would fail for every test database but the first. TestDb runs it without that,
+//! creating the role once if the server doesn't have it, and then the other migrations.
+use crate::admin;
+use crate::db::{self, Pool};
+use crate::models::{add_user, AddUserResult, AuthenticatedID};
+use diesel::connection::SimpleConnection;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::r2d2::ConnectionManager;
+use std::env;
+use std::sync::Once;
+use uuid::Uuid;
+
+const DEFAULT_URL: &str = "postgres://postgres@localhost:5432/postgres";
+
+const INITIAL_SETUP: &str =
+    include_str!("../migrations/00000000000000_diesel_initial_setup/up.sql");
+
+const CREATE_ROLE: &str = "create user pauth_admin with password 'pauth_admin_password';";
+
+static ROLE: Once = Once::new();
+
+pub struct TestDb {
+    url: String,
+    name: String,
+    pool: Option<Pool>,
+}
+
+impl TestDb {
+    pub fn new() -> TestDb {
+        let url = env::var("PAUTH_TEST_DATABASE_URL")
+            .or_else(|_| env::var("DATABASE_URL"))
+            .unwrap_or_else(|_| DEFAULT_URL.to_owned());
+        let name = format!("pauth_test_{}", Uuid::new_v4().to_simple());
+        let admin = PgConnection::establish(&with_database(&url, "postgres"))
+            .expect("Failed to connect to the test database server");
+        admin
+            .execute(&format!("create database {}", name))
+            .expect("Failed to create the test database");
+
+        let manager = ConnectionManager::<PgConnection>::new(with_database(&url, &name));
+        let pool = Pool::builder()
+            .min_idle(Some(1))
+            .build(manager)
+            .expect("Failed to create the test db pool");
+        let conn = pool.get().expect("Failed to get test db connection");
+        initial_setup(&conn);
+        db::run_migrations(&conn).expect("Failed to migrate the test database");
+        drop(conn);
+        let test_db = TestDb {
+            url,
+            name,
+            pool: Some(pool),
+        };
+        test_db.attach();
+        test_db
+    }
+
+    /// Use this database on the current thread too, for tests which spawn threads.
+    pub fn attach(&self) {
+        db::set_thread_pool(self.pool.clone());
+    }
+}
+
+impl Drop for TestDb {
+    fn drop(&mut self) {
+        db::set_thread_pool(None);
+        self.pool.take();
+        if let Ok(admin) = PgConnection::establish(&with_database(&self.url, "postgres")) {
+            let _ = admin.execute(&format!(
+                "drop database if exists {} with (force)",
+                self.name
+            ));
+        }
+    }
+}
+
+/// The password of users from added and admin.
+pub const PASSWORD: &str = "forgotten";
+
+/// Add a user with an email made from the name, and PASSWORD, returning their AuthenticatedID.
+pub fn added(name: &str) -> AuthenticatedID {
+    let email = format!("{}@pr0.co.uk", name.to_lowercase());
+    match add_user(name, &email, PASSWORD).unwrap() {
+        AddUserResult::Added(auth_id) => auth_id,
+        r => panic!("Test failure: user not added, got {:?}", r),
+    }
+}
+
+/// Add a user as added does, making them an admin.
+pub fn admin(name: &str) -> AuthenticatedID {
+    let auth_id = added(name);
+    assert!(admin::set_admin(auth_id.user_id, true).unwrap());
+    auth_id
+}
+
+/// The url of the named database on the server of url, with url's parameters.
+fn with_database(url: &str, name: &str) -> String {
+    let (base, parameters) = url.split_at(url.find('?').unwrap_or(url.len()));
+    let server = match base.rfind('/') {
+        Some(i) if base[..i].ends_with('/') => base,
+        Some(i) => &base[..i],
+        None => base,
+    };
+    format!("{}/{}{}", server, name, parameters)
+}
+
+/// Run the initial migration, without creating the pauth_admin role unless the server needs it,
+/// and record it as run.
+fn initial_setup(conn: &PgConnection) {
+    assert!(
+        INITIAL_SETUP.contains(CREATE_ROLE),
+        "The initial migration no longer creates pauth_admin as expected"
+    );
+    ROLE.call_once(|| {
+        conn.batch_execute(&format!(
+            "do $$ begin
+                 if not exists (select from pg_roles where rolname = 'pauth_admin') then
+                     {}
+                 end if;
+             end $$;",
+            CREATE_ROLE
+        ))
+        .expect("Failed to create the pauth_admin role");
+    });
+    conn.batch_execute(&INITIAL_SETUP.replacen(CREATE_ROLE, "", 1))
+        .expect("Failed to run the initial migration");
+    conn.batch_execute(
+        "create table __diesel_schema_migrations (
+             version varchar(50) primary key not null,
+             run_on timestamp not null default current_timestamp
+         );
+         insert into __diesel_schema_migrations (version) values ('00000000000000');",
+    )
+    .expect("Failed to record the initial migration");
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_db::with_database;
+
+    #[test]
+    fn urls_keep_parameters() {
+        let cases = vec![
+            ("postgres://u@h:5432/pauth", "postgres://u@h:5432/t"),
+            (
+                "postgres://u@h/pauth?sslmode=require",
+                "postgres://u@h/t?sslmode=require",
+            ),
+            (
+                "postgres://u@h?sslmode=require",
+                "postgres://u@h/t?sslmode=require",
+            ),
+            ("postgres://u@h", "postgres://u@h/t"),
+        ];
+        for (url, expected) in cases {
+            assert_eq!(expected, with_database(url, "t"));
+        }
+    }
+}
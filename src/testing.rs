@@ -0,0 +1,268 @@
+//! An in process fake of pauth, for unit testing services which use it without a database.
+//! Enable with the "testing" cargo feature.
+//!
+//! FakePauth keeps everything in memory (like store::MemoryStore). While it is installed, the
+//! normal pauth functions (login, add_user and so on) use it, so the code under test doesn't
+//! change. Install it for the current thread with run, or for every thread with install.
+//!
+//! As well as behaving like pauth, the fake can be pre-seeded with users, tokens and resets,
+//! made to fail calls, lock accounts or expire tokens, and asked which calls were made.
+use crate::models::{AuthenticatedID, User};
+use crate::pauth_error::ApplicationError;
+use crate::store::{self, MemoryStore, Store};
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::result::{DatabaseErrorKind, Error};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use uuid::Uuid;
+
+/// A call to the pauth API, with the identifiers it was passed. Passwords and tokens are
+/// not recorded.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Call {
+    pub name: String,
+    pub args: Vec<String>,
+}
+
+#[derive(Default)]
+struct State {
+    calls: Vec<Call>,
+    failures: HashMap<String, usize>,
+}
+
+/// The fake is a MemoryStore whose API call hook records the calls and fails those asked to.
+pub struct FakePauth {
+    store: Arc<MemoryStore>,
+    state: Arc<Mutex<State>>,
+}
+
+impl FakePauth {
+    pub fn new() -> Arc<FakePauth> {
+        let state = Arc::new(Mutex::new(State::default()));
+        let hook_state = state.clone();
+        let store = MemoryStore::with_api_hook(Box::new(move |name, args| {
+            record_call(&hook_state, name, args)
+        }));
+        Arc::new(FakePauth {
+            store: Arc::new(store),
+            state,
+        })
+    }
+
+    /// Run f with pauth calls on this thread going to the fake.
+    pub fn run<T, F: FnOnce() -> T>(&self, f: F) -> T {
+        store::with_store(self.store.clone(), f)
+    }
+
+    /// Send pauth calls on every thread to the fake, as with store::set_store.
+    pub fn install(&self) {
+        store::set_store(self.store.clone())
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Add a user, returning their id.
+    pub fn seed_user(&self, name: &str, email: &str, password: &str) -> i32 {
+        let hash = self.store.hash(password).expect("Failed to hash password");
+        self.store
+            .insert_user(name, email, &hash)
+            .expect("Failed to add user")
+    }
+
+    /// A valid AuthenticatedID for the user, as if they had logged in.
+    pub fn seed_token(&self, uid: i32) -> AuthenticatedID {
+        let token = Uuid::new_v4().to_hyphenated().to_string();
+        let hash = self.store.hash(&token).expect("Failed to hash token");
+        self.store
+            .insert_token(uid, &hash, Some(Utc::now().naive_utc()))
+            .expect("Failed to add token");
+        AuthenticatedID {
+            user_id: uid,
+            token,
+        }
+    }
+
+    /// A password reset token for the user, as if generate_pw_reset had been called.
+    pub fn seed_reset(&self, uid: i32, expires: Option<NaiveDateTime>) -> String {
+        let token = Uuid::new_v4().to_simple().to_string();
+        let hash = self.store.hash(&token).expect("Failed to hash token");
+        self.store
+            .insert_reset(uid, &hash, expires)
+            .expect("Failed to add reset");
+        token
+    }
+
+    /// A password reset token for the user which has already expired.
+    pub fn seed_expired_reset(&self, uid: i32) -> String {
+        self.seed_reset(uid, Some(Utc::now().naive_utc() - Duration::minutes(1)))
+    }
+
+    /// Make the next times calls to the named function (such as "login") fail with
+    /// ApplicationError::Database.
+    pub fn fail_next(&self, name: &str, times: usize) {
+        *self.state().failures.entry(name.to_owned()).or_insert(0) += times;
+    }
+
+    /// Lock the user's account, as if they had failed to log in too many times.
+    pub fn lock_user(&self, uid: i32) {
+        self.store
+            .lock_user(uid, Utc::now().naive_utc())
+            .expect("Failed to lock user");
+    }
+
+    /// Make all of the user's AuthenticatedIDs invalid, as if they had expired. Ones issued
+    /// afterwards, such as by logging in again, are valid.
+    pub fn expire_tokens(&self, uid: i32) {
+        self.store
+            .delete_tokens(uid, None)
+            .expect("Failed to expire tokens");
+    }
+
+    /// The user, with any changes made through pauth.
+    pub fn user(&self, uid: i32) -> Option<User> {
+        self.store.user_by_id(uid).expect("Failed to get user")
+    }
+
+    /// Every call made so far, oldest first.
+    pub fn calls(&self) -> Vec<Call> {
+        self.state().calls.clone()
+    }
+
+    /// The calls made to the named function.
+    pub fn calls_to(&self, name: &str) -> Vec<Call> {
+        self.state()
+            .calls
+            .iter()
+            .filter(|c| c.name == name)
+            .cloned()
+            .collect()
+    }
+
+    pub fn clear_calls(&self) {
+        self.state().calls.clear();
+    }
+
+    pub fn assert_called(&self, name: &str) {
+        assert!(
+            !self.calls_to(name).is_empty(),
+            "Expected a call to {}, calls were {:?}",
+            name,
+            self.calls()
+        );
+    }
+
+    pub fn assert_not_called(&self, name: &str) {
+        assert!(
+            self.calls_to(name).is_empty(),
+            "Expected no call to {}, calls were {:?}",
+            name,
+            self.calls()
+        );
+    }
+}
+
+/// Record the call, failing it if fail_next asked for that.
+fn record_call(state: &Mutex<State>, name: &str, args: &[&str]) -> Result<(), ApplicationError> {
+    let mut state = state.lock().unwrap_or_else(|e| e.into_inner());
+    state.calls.push(Call {
+        name: name.to_owned(),
+        args: args.iter().map(|a| a.to_string()).collect(),
+    });
+    match state.failures.get_mut(name) {
+        Some(remaining) if *remaining > 0 => {
+            *remaining -= 1;
+            Err(ApplicationError::Database(Error::DatabaseError(
+                DatabaseErrorKind::__Unknown,
+                Box::new(format!("forced failure of {}", name)),
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::models::*;
+    use crate::testing::*;
+
+    #[test]
+    fn fake_behaves_like_pauth() {
+        let fake = FakePauth::new();
+        let uid = fake.seed_user("fake34", "fake34@pr0.co.uk", "pass34");
+        let token = fake.seed_token(uid);
+        fake.run(|| {
+            assert!(check_id(&token).unwrap());
+            assert!(matches!(
+                login("fake34", "pass34").unwrap(),
+                LoginResult::LoggedIn(_)
+            ));
+            assert!(matches!(
+                add_user("fake34", "other34@pr0.co.uk", "pass34").unwrap(),
+                AddUserResult::NotAdded(_)
+            ));
+            change_details(&token, UserUpdate::new().with_email("new34@pr0.co.uk")).unwrap();
+        });
+        assert_eq!("new34@pr0.co.uk", fake.user(uid).unwrap().email);
+        assert_eq!(
+            vec!["check_id", "login", "add_user", "change_details"],
+            fake.calls()
+                .iter()
+                .map(|c| c.name.as_str())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(vec!["fake34".to_owned()], fake.calls_to("login")[0].args);
+        fake.assert_not_called("delete_user");
+    }
+
+    #[test]
+    fn forced_failures() {
+        let fake = FakePauth::new();
+        let uid = fake.seed_user("fail34", "fail34@pr0.co.uk", "pass34");
+        let token = fake.seed_token(uid);
+        let reset = fake.seed_expired_reset(uid);
+        fake.fail_next("login", 1);
+        fake.run(|| {
+            assert!(matches!(
+                login("fail34", "pass34"),
+                Err(ApplicationError::Database(_))
+            ));
+            assert!(matches!(
+                login("fail34", "pass34").unwrap(),
+                LoginResult::LoggedIn(_)
+            ));
+
+            assert_eq!(
+                LoginResult::AuthenticationFailure,
+                validate_pw_reset("fail34", reset).unwrap()
+            );
+
+            fake.lock_user(uid);
+            assert_eq!(
+                LoginResult::AccountLocked,
+                login("fail34", "pass34").unwrap()
+            );
+
+            fake.expire_tokens(uid);
+            assert!(!check_id(&token).unwrap());
+        });
+        fake.assert_called("validate_pw_reset");
+    }
+
+    #[test]
+    fn expired_tokens_stay_expired() {
+        let fake = FakePauth::new();
+        let uid = fake.seed_user("expire34", "expire34@pr0.co.uk", "pass34");
+        let token = fake.seed_token(uid);
+        fake.expire_tokens(uid);
+        fake.run(|| {
+            let again = match login("expire34", "pass34").unwrap() {
+                LoginResult::LoggedIn(id) => id,
+                r => panic!("Test failure: login failed, got {:?}", r),
+            };
+            assert!(check_id(&again).unwrap());
+            assert!(!check_id(&token).unwrap());
+        });
+    }
+}
@@ -0,0 +1,105 @@
+//! Per account throttling of failed logins. After a number of consecutive failures for an
+//! identifier (username or email), further attempts are rejected until a back-off window has
+//! passed. The window doubles with each further failure. Counters are kept in the store, so
+//! with Postgres they apply across every process using pauth.
+use crate::config;
+use crate::identifier::Canonicaliser;
+use crate::pauth_error::ApplicationError;
+use crate::store::Store;
+use chrono::{Duration, Utc};
+
+/// Identifiers are compared as usernames and emails are (see the identifier module), ignoring
+/// surrounding whitespace, so that varying them does not get around the throttle.
+fn throttle_key(canonicaliser: &Canonicaliser, identifier: &str) -> String {
+    canonicaliser.key(identifier.trim())
+}
+
+/// The back-off window after the given number of consecutive failures, if any.
+pub(crate) fn backoff(
+    failures: i64,
+    allowed: i64,
+    base_seconds: i64,
+    max_seconds: i64,
+) -> Option<Duration> {
+    if failures < allowed {
+        return None;
+    }
+    let doublings = (failures - allowed).min(32) as u32;
+    let seconds = base_seconds.saturating_mul(2i64.saturating_pow(doublings));
+    Some(Duration::seconds(seconds.min(max_seconds)))
+}
+
+/// If attempts for this identifier are currently blocked, how long until they are allowed again.
+pub(crate) fn retry_after(
+    store: &dyn Store,
+    identifier: &str,
+) -> Result<Option<Duration>, ApplicationError> {
+    let key = throttle_key(&Canonicaliser::configured(store)?, identifier);
+    let blocked_until = store.throttle_blocked_until(&key)?;
+    let now = Utc::now().naive_utc();
+    Ok(blocked_until
+        .filter(|until| *until > now)
+        .map(|until| until - now))
+}
+
+/// Record a failed login for the identifier, returning the number of consecutive failures and
+/// the back-off window which now applies.
+pub(crate) fn record_failure(
+    store: &dyn Store,
+    identifier: &str,
+) -> Result<(i32, Option<Duration>), ApplicationError> {
+    let reset_minutes = config::get_config_i64("failed login count reset minutes", None, 1440)?;
+    let key = throttle_key(&Canonicaliser::configured(store)?, identifier);
+    let now = Utc::now().naive_utc();
+    let failures = store.add_throttle_failure(&key, now, now - Duration::minutes(reset_minutes))?;
+    let window = backoff(
+        failures as i64,
+        config::get_config_i64("failed logins before delay", None, 3)?,
+        config::get_config_i64("login delay seconds", None, 1)?,
+        config::get_config_i64("max login delay seconds", None, 900)?,
+    );
+    if let Some(window) = window {
+        store.block_throttle_key(&key, now + window)?;
+    }
+    Ok((failures, window))
+}
+
+/// Clear the failure counts for the identifiers, after a successful login.
+pub(crate) fn reset(store: &dyn Store, identifiers: &[&str]) -> Result<(), ApplicationError> {
+    let canonicaliser = Canonicaliser::configured(store)?;
+    let keys: Vec<String> = identifiers
+        .iter()
+        .map(|i| throttle_key(&canonicaliser, i))
+        .collect();
+    store.reset_throttle(&keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::store::{self, MemoryStore};
+    use crate::throttle::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn identifiers_are_canonicalised() {
+        store::with_store(Arc::new(MemoryStore::new()), || {
+            let store = store::current();
+            for identifier in &["Straße", " STRASSE", "strasse "] {
+                record_failure(&*store, identifier).unwrap();
+            }
+            assert!(retry_after(&*store, "straße").unwrap().is_some());
+            reset(&*store, &["STRAẞE"]).unwrap();
+            assert_eq!(None, retry_after(&*store, "strasse").unwrap());
+        })
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        assert_eq!(None, backoff(2, 3, 1, 900));
+        assert_eq!(Some(Duration::seconds(1)), backoff(3, 3, 1, 900));
+        assert_eq!(Some(Duration::seconds(2)), backoff(4, 3, 1, 900));
+        assert_eq!(Some(Duration::seconds(8)), backoff(6, 3, 1, 900));
+        assert_eq!(Some(Duration::seconds(900)), backoff(13, 3, 1, 900));
+        assert_eq!(Some(Duration::seconds(900)), backoff(1000, 3, 1, 900));
+    }
+}
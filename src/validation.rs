@@ -0,0 +1,387 @@
+//! Validation of usernames and emails when users are added (add_user) or changed
+//! (change_details). Every rule which fails is reported, as UsernameInvalid or EmailInvalid with
+//! a reason which can be shown to the user.
+//!
+//! The ValidationPolicy is read from the config:
+//! - 'username min length' and 'username max length', in characters
+//! - 'username allowed characters': 'any', or a space separated list of 'letters', 'digits'
+//!   and any other characters allowed, such as 'letters digits ._-'. Control characters are
+//!   never allowed.
+//! - 'reserved usernames': comma separated names no one can have, such as 'admin'
+//! - 'username blocklist file': a file of names no one can have, one per line
+//! - 'email max length'
+//! - 'disposable email domains file': a file of domains, one per line, which emails can't use.
+//!   Subdomains of the domains listed are also rejected.
+//!
+//! Reserved and blocked names are compared in canonical form (ignoring case, by default). In
+//! the files, blank lines and lines starting with # are ignored. The files are read each time
+//! a user is validated, so can be updated without a restart.
+//!
+//! Rules of your own can be added with add_rule.
+use crate::identifier::Canonicaliser;
+use crate::models::{UserActionFailure, UserActionFailureReason};
+use crate::pauth_error::ApplicationError;
+use crate::store;
+use lazy_static::lazy_static;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+/// An extra check on usernames and emails. Return a reason to reject the identifier.
+pub trait ValidationRule: Send + Sync {
+    fn check_username(&self, _name: &str) -> Option<UserActionFailureReason> {
+        None
+    }
+    fn check_email(&self, _email: &str) -> Option<UserActionFailureReason> {
+        None
+    }
+}
+
+lazy_static! {
+    static ref RULES: RwLock<Vec<Box<dyn ValidationRule>>> = RwLock::new(Vec::new());
+}
+
+/// Add a rule, which applies as well as the ValidationPolicy.
+pub fn add_rule(rule: Box<dyn ValidationRule>) {
+    RULES.write().unwrap().push(rule);
+}
+
+/// Remove all rules added with add_rule.
+pub fn clear_rules() {
+    RULES.write().unwrap().clear();
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ValidationPolicy {
+    pub username_min_length: usize,
+    pub username_max_length: usize,
+    pub username_allowed_characters: String,
+    pub reserved_usernames: Vec<String>,
+    pub username_blocklist_file: Option<PathBuf>,
+    pub email_max_length: usize,
+    pub disposable_email_domains_file: Option<PathBuf>,
+}
+
+impl Default for ValidationPolicy {
+    fn default() -> Self {
+        ValidationPolicy {
+            username_min_length: 1,
+            username_max_length: 64,
+            username_allowed_characters: "any".to_owned(),
+            reserved_usernames: Vec::new(),
+            username_blocklist_file: None,
+            email_max_length: 254,
+            disposable_email_domains_file: None,
+        }
+    }
+}
+
+impl ValidationPolicy {
+    /// The policy set by the config, with the defaults for any keys which are missing.
+    pub fn from_config() -> Result<ValidationPolicy, ApplicationError> {
+        let store = store::current();
+        let get = |key: &str| -> Result<Option<String>, ApplicationError> {
+            Ok(store
+                .get_config(key, None)?
+                .map(|v| v.trim().to_owned())
+                .filter(|v| !v.is_empty()))
+        };
+        let number = |key: &str, default: usize| -> Result<usize, ApplicationError> {
+            Ok(get(key)?
+                .and_then(|v| v.parse::<usize>().ok())
+                .unwrap_or(default))
+        };
+        let default = ValidationPolicy::default();
+        Ok(ValidationPolicy {
+            username_min_length: number("username min length", default.username_min_length)?,
+            username_max_length: number("username max length", default.username_max_length)?,
+            username_allowed_characters: get("username allowed characters")?
+                .unwrap_or(default.username_allowed_characters),
+            reserved_usernames: get("reserved usernames")?
+                .map(|v| {
+                    v.split(',')
+                        .map(|name| name.trim().to_owned())
+                        .filter(|name| !name.is_empty())
+                        .collect()
+                })
+                .unwrap_or_default(),
+            username_blocklist_file: get("username blocklist file")?.map(PathBuf::from),
+            email_max_length: number("email max length", default.email_max_length)?,
+            disposable_email_domains_file: get("disposable email domains file")?.map(PathBuf::from),
+        })
+    }
+
+    /// The reasons the username is not allowed, if any.
+    pub fn check_username(&self, name: &str) -> Result<Vec<String>, ApplicationError> {
+        let mut reasons = Vec::new();
+        let length = name.chars().count();
+        if length < self.username_min_length {
+            reasons.push(format!(
+                "Usernames must be at least {} characters long",
+                self.username_min_length
+            ));
+        }
+        if length > self.username_max_length {
+            reasons.push(format!(
+                "Usernames must be at most {} characters long",
+                self.username_max_length
+            ));
+        }
+        if !name.chars().all(|c| self.username_char_allowed(c)) {
+            reasons.push(match self.username_allowed_characters.as_str() {
+                "any" => "Usernames can't contain control characters".to_owned(),
+                allowed => format!("Usernames can only contain {}", allowed),
+            });
+        }
+
+        let canonicaliser = Canonicaliser::new(&*store::current())?;
+        let key = canonicaliser.key(name);
+        if self
+            .reserved_usernames
+            .iter()
+            .any(|reserved| canonicaliser.key(reserved) == key)
+        {
+            reasons.push("That username is reserved".to_owned());
+        } else if let Some(path) = &self.username_blocklist_file {
+            if read_list(path)?
+                .iter()
+                .any(|blocked| canonicaliser.key(blocked) == key)
+            {
+                reasons.push("That username is not allowed".to_owned());
+            }
+        }
+        Ok(reasons)
+    }
+
+    /// The reasons the email is not allowed, if any.
+    pub fn check_email(&self, email: &str) -> Result<Vec<String>, ApplicationError> {
+        let mut reasons = Vec::new();
+        if email.chars().count() > self.email_max_length {
+            reasons.push(format!(
+                "Emails must be at most {} characters long",
+                self.email_max_length
+            ));
+        }
+        let domain = match email.rsplit_once('@') {
+            Some((local, domain))
+                if !local.is_empty()
+                    && domain.contains('.')
+                    && !domain.starts_with('.')
+                    && !domain.ends_with('.')
+                    && !email.chars().any(|c| c.is_whitespace() || c.is_control()) =>
+            {
+                domain.to_lowercase()
+            }
+            _ => {
+                reasons.push("Emails must be an address like name@example.com".to_owned());
+                return Ok(reasons);
+            }
+        };
+        if let Some(path) = &self.disposable_email_domains_file {
+            let disposable = read_list(path)?.into_iter().any(|d| {
+                let d = d.to_lowercase();
+                domain == d || domain.ends_with(&format!(".{}", d))
+            });
+            if disposable {
+                reasons.push("Emails from disposable email services are not allowed".to_owned());
+            }
+        }
+        Ok(reasons)
+    }
+
+    fn username_char_allowed(&self, c: char) -> bool {
+        if c.is_control() {
+            return false;
+        }
+        let allowed = self.username_allowed_characters.as_str();
+        allowed == "any"
+            || allowed.split_whitespace().any(|class| match class {
+                "letters" => c.is_alphabetic(),
+                "digits" => c.is_numeric(),
+                chars => chars.contains(c),
+            })
+    }
+}
+
+/// The lines of a list file, without blanks and comments.
+fn read_list(path: &Path) -> Result<Vec<String>, ApplicationError> {
+    let contents = fs::read_to_string(path).map_err(|e| {
+        ApplicationError::ApplicationDataLogic(format!("Failed to read {}: {}", path.display(), e))
+    })?;
+    Ok(contents
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.to_owned())
+        .collect())
+}
+
+/// Check a new username and/or email against the policy and any added rules.
+pub(crate) fn validate(
+    name: Option<&str>,
+    email: Option<&str>,
+) -> Result<Vec<UserActionFailure>, ApplicationError> {
+    let policy = ValidationPolicy::from_config()?;
+    let rules = RULES.read().unwrap();
+    let mut failures = Vec::new();
+    if let Some(name) = name {
+        let mut reasons = policy.check_username(name)?;
+        reasons.extend(rules.iter().filter_map(|r| r.check_username(name)));
+        failures.extend(reasons.into_iter().map(UserActionFailure::UsernameInvalid));
+    }
+    if let Some(email) = email {
+        let mut reasons = policy.check_email(email)?;
+        reasons.extend(rules.iter().filter_map(|r| r.check_email(email)));
+        failures.extend(reasons.into_iter().map(UserActionFailure::EmailInvalid));
+    }
+    Ok(failures)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db;
+    use crate::models::*;
+    use crate::store::{self, MemoryStore};
+    use crate::test_db::TestDb;
+    use crate::validation::*;
+    use diesel::prelude::*;
+    use std::env;
+    use std::sync::Arc;
+    use uuid::Uuid;
+
+    fn list_file(contents: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("pauth-{}.txt", Uuid::new_v4()));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn policy_rules() {
+        let blocklist = list_file("# names we don't want\n\nBadName\n");
+        let disposable = list_file("mailinator.com\n");
+        let policy = ValidationPolicy {
+            username_min_length: 3,
+            username_max_length: 8,
+            username_allowed_characters: "letters digits _".to_owned(),
+            reserved_usernames: vec!["admin".to_owned()],
+            username_blocklist_file: Some(blocklist.clone()),
+            email_max_length: 20,
+            disposable_email_domains_file: Some(disposable.clone()),
+        };
+        store::with_store(Arc::new(MemoryStore::new()), || {
+            assert!(policy.check_username("paul_39").unwrap().is_empty());
+            assert!(policy.check_username("Pâul").unwrap().is_empty());
+            assert_eq!(2, policy.check_username("p!").unwrap().len());
+            assert_eq!(
+                vec!["Usernames must be at most 8 characters long".to_owned()],
+                policy.check_username("paul_3939").unwrap()
+            );
+            assert_eq!(
+                vec!["That username is reserved".to_owned()],
+                policy.check_username("ADMIN").unwrap()
+            );
+            assert_eq!(
+                vec!["That username is not allowed".to_owned()],
+                policy.check_username("badname").unwrap()
+            );
+            assert_eq!(
+                1,
+                ValidationPolicy::default()
+                    .check_username("pa\u{7}ul")
+                    .unwrap()
+                    .len()
+            );
+
+            assert!(policy.check_email("paul@pr0.co.uk").unwrap().is_empty());
+            for email in &[
+                "paul",
+                "@pr0.co.uk",
+                "paul@localhost",
+                "paul@pr0.",
+                "pa ul@pr0.co.uk",
+            ] {
+                assert_eq!(
+                    vec!["Emails must be an address like name@example.com".to_owned()],
+                    policy.check_email(email).unwrap(),
+                    "{} should be invalid",
+                    email
+                );
+            }
+            assert_eq!(
+                2,
+                policy.check_email("paul@x.mailinator.com").unwrap().len()
+            );
+            assert_eq!(1, policy.check_email("p@Mailinator.com").unwrap().len());
+            assert!(policy
+                .check_email("p@notmailinator.com")
+                .unwrap()
+                .is_empty());
+        });
+        fs::remove_file(blocklist).unwrap();
+        fs::remove_file(disposable).unwrap();
+    }
+
+    struct NoNumbers;
+
+    impl ValidationRule for NoNumbers {
+        fn check_username(&self, name: &str) -> Option<UserActionFailureReason> {
+            if name
+                .strip_prefix("rule39")
+                .is_some_and(|rest| rest.chars().any(char::is_numeric))
+            {
+                Some("No numbers after rule39".to_owned())
+            } else {
+                None
+            }
+        }
+    }
+
+    #[test]
+    fn add_and_change_report_every_failure() {
+        let _db = TestDb::new();
+        db::connection()
+            .unwrap()
+            .execute(
+                "update pauth.config set config_value = '3' where config_key = 'username min length'",
+            )
+            .unwrap();
+        add_rule(Box::new(NoNumbers));
+        assert_eq!(
+            AddUserResult::NotAdded(vec![
+                UserActionFailure::UsernameInvalid(
+                    "Usernames must be at least 3 characters long".to_owned()
+                ),
+                UserActionFailure::EmailInvalid(
+                    "Emails must be an address like name@example.com".to_owned()
+                ),
+            ]),
+            add_user("pa", "paul", "test").unwrap()
+        );
+        assert_eq!(
+            AddUserResult::NotAdded(vec![UserActionFailure::UsernameInvalid(
+                "That username is reserved".to_owned()
+            )]),
+            add_user("Root", "root@pr0.co.uk", "test").unwrap()
+        );
+        assert_eq!(
+            AddUserResult::NotAdded(vec![UserActionFailure::UsernameInvalid(
+                "No numbers after rule39".to_owned()
+            )]),
+            add_user("rule391", "rule39@pr0.co.uk", "test").unwrap()
+        );
+        let auth_id = match add_user("rule39", "rule39@pr0.co.uk", "test").unwrap() {
+            AddUserResult::Added(auth_id) => auth_id,
+            r => panic!("Test failure: user not added, got {:?}", r),
+        };
+        assert_eq!(
+            ChangeDetailsResult::NotChanged(vec![UserActionFailure::EmailInvalid(
+                "Emails must be an address like name@example.com".to_owned()
+            )]),
+            change_details(&auth_id, UserUpdate::new().with_email("rule39")).unwrap()
+        );
+        assert_eq!(
+            ChangeDetailsResult::Changed,
+            change_details(&auth_id, UserUpdate::new().with_chosen_name("rule")).unwrap()
+        );
+    }
+}